@@ -1,5 +1,8 @@
+use std::cell::RefCell;
 use std::error::Error;
 use std::io;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 use async_channel::Sender;
@@ -12,10 +15,17 @@ use glib::object::IsA;
 use gtk::prelude::*;
 use gtk::{DestDefaults, Label, TargetEntry, TargetFlags};
 
-use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use libp2p::{Multiaddr, PeerId};
 
-use crate::p2p::{FileToSend, OperatingSystem, Payload, Peer, PeerEvent};
-use crate::user_data::UserConfig;
+use crate::dnd::dialogs::PairingDialog;
+use crate::p2p::{
+    manual_address, Bandwidth, DiscoveryCommand, FileToSend, Payload, Peer, PeerEvent, PeerQueue,
+    QueueCommand, QueuedFile, Trust,
+};
+use crate::user_data::{RateLimit, UserConfig};
+
+/// Highest rate limit that can be set in the menu, in KiB/s
+const MAX_RATE_LIMIT: f64 = 1024.0 * 1024.0;
 
 pub const STYLE: &str = "
 #notification {
@@ -58,11 +68,19 @@ pub struct MainLayout {
     pub layout: gtk::Box,
     pub item_layout: gtk::ListBox,
     recent_layout: gtk::Grid,
+    queue_layout: gtk::ListBox,
+    queue_sender: Sender<QueueCommand>,
+    /// Pairing with the peers on the list
+    pub discovery_sender: Sender<DiscoveryCommand>,
     pub bar: gtk::HeaderBar,
 }
 
 impl MainLayout {
-    pub fn new() -> Result<MainLayout, Box<dyn Error>> {
+    pub fn new(
+        bandwidth: Bandwidth,
+        queue_sender: Sender<QueueCommand>,
+        discovery_sender: Sender<DiscoveryCommand>,
+    ) -> Result<MainLayout, Box<dyn Error>> {
         let layout = gtk::Box::new(gtk::Orientation::Vertical, 10);
         let inner_layout = gtk::Box::new(gtk::Orientation::Vertical, 0);
         let recent_layout = gtk::Grid::new();
@@ -75,6 +93,13 @@ impl MainLayout {
         recent_scroll.set_hexpand(false);
         recent_scroll.add(&recent_layout);
 
+        let queue_layout = gtk::ListBox::new();
+        queue_layout.set_selection_mode(gtk::SelectionMode::None);
+        let queue_scroll: gtk::ScrolledWindow =
+            gtk::ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+        queue_scroll.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+        queue_scroll.add(&queue_layout);
+
         let bar = gtk::HeaderBar::new();
         bar.set_show_close_button(true);
 
@@ -82,6 +107,7 @@ impl MainLayout {
         stack.set_transition_type(gtk::StackTransitionType::SlideLeftRight);
         stack.add_titled(&inner_layout, "devices", "Devices");
         stack.add_titled(&recent_scroll, "recent-files", "Recent Files");
+        stack.add_titled(&queue_scroll, "queue", "Queue");
 
         let switcher = gtk::StackSwitcher::new();
         switcher.set_stack(Some(&stack));
@@ -104,7 +130,7 @@ impl MainLayout {
 
         inner_layout.pack_start(&scroll, true, true, 10);
 
-        let menu_button = Self::setup_menu_button()?;
+        let menu_button = Self::setup_menu_button(bandwidth, discovery_sender.clone())?;
 
         bar.pack_start(&menu_button);
         bar.pack_start(&switcher);
@@ -115,6 +141,9 @@ impl MainLayout {
             layout,
             item_layout,
             recent_layout,
+            queue_layout,
+            queue_sender,
+            discovery_sender,
             bar,
         })
     }
@@ -144,6 +173,19 @@ impl MainLayout {
                 label.set_selectable(true);
                 recent_item.pack_start(&label, false, false, 0);
             }
+            Payload::Batch(items) => {
+                for item in items {
+                    let item_name = match &item {
+                        Payload::Dir(path) | Payload::File(path) => Path::new(path)
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_else(|| path.to_string()),
+                        _ => file_name.to_string(),
+                    };
+                    self.add_recent_file(&item_name, item);
+                }
+                return;
+            }
         }
 
         self.recent_layout.attach_next_to(
@@ -155,19 +197,102 @@ impl MainLayout {
         );
     }
 
-    fn setup_menu_button() -> Result<gtk::MenuButton, Box<dyn Error>> {
+    /// Replaces the rows of the files that go to the peer of `queue`.
+    pub fn show_queue(&self, queue: &PeerQueue) {
+        let peer = queue.peer.to_string();
+        for child in self
+            .queue_layout
+            .children()
+            .iter()
+            .filter(|c| get_item_name(*c) == peer)
+        {
+            self.queue_layout.remove(child);
+        }
+
+        for file in queue.active.iter() {
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+            row.set_widget_name(&peer);
+            let label = gtk::Label::new(Some(&format!("Sending {}", file.name)));
+            row.pack_start(&label, false, false, 5);
+            self.queue_layout.add(&row);
+            row.show_all();
+        }
+        for (position, file) in queue.waiting.iter().enumerate() {
+            let row = self.queued_row(file, position);
+            row.set_widget_name(&peer);
+            self.queue_layout.add(&row);
+            row.show_all();
+        }
+    }
+
+    fn queued_row(&self, file: &QueuedFile, position: usize) -> gtk::Box {
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+        let label = gtk::Label::new(Some(&format!("{}. {}", position + 1, file.name)));
+        row.pack_start(&label, false, false, 5);
+
+        let commands = [
+            (
+                "go-up-symbolic",
+                QueueCommand::MoveTo(file.id, position.saturating_sub(1)),
+            ),
+            (
+                "go-down-symbolic",
+                QueueCommand::MoveTo(file.id, position + 1),
+            ),
+            ("list-remove-symbolic", QueueCommand::Remove(file.id)),
+        ];
+        for (icon, command) in commands {
+            let button = gtk::Button::from_icon_name(Some(icon), gtk::IconSize::Button);
+            let sender = self.queue_sender.clone();
+            button.connect_clicked(move |_| {
+                if let Err(e) = sender.try_send(command) {
+                    error!("Could not change the queue: {:?}", e);
+                }
+            });
+            row.pack_end(&button, false, false, 0);
+        }
+        row
+    }
+
+    fn setup_menu_button(
+        bandwidth: Bandwidth,
+        discovery_sender: Sender<DiscoveryCommand>,
+    ) -> Result<gtk::MenuButton, Box<dyn Error>> {
         let menu_image =
             gtk::Image::from_icon_name(Some("open-menu-symbolic"), gtk::IconSize::Menu);
         let menu_button = gtk::MenuButton::new();
         let vbox = gtk::Box::new(gtk::Orientation::Vertical, 10);
         let popover = gtk::Popover::new(None::<&gtk::Widget>);
+
+        let name_label = gtk::Label::new(Some("Name shown to other devices"));
+        let display_name = Self::setup_display_name(discovery_sender.clone())?;
+        display_name.set_margin_start(10);
+        display_name.set_margin_end(10);
+
         let label = gtk::Label::new(Some("Downloads directory"));
         let file_chooser = Self::setup_file_chooser()?;
         file_chooser.set_margin_start(10);
         file_chooser.set_margin_end(10);
 
+        let limits_label = gtk::Label::new(Some("Speed limits in KiB/s, 0 for none"));
+        let limits = Self::setup_rate_limits(bandwidth);
+        limits.set_margin_start(10);
+        limits.set_margin_end(10);
+
+        vbox.pack_start(&name_label, true, true, 10);
+        vbox.pack_start(&display_name, true, true, 10);
         vbox.pack_start(&label, true, true, 10);
         vbox.pack_start(&file_chooser, true, true, 10);
+        vbox.pack_start(&limits_label, true, true, 10);
+        vbox.pack_start(&limits, true, true, 10);
+
+        let peer_label = gtk::Label::new(Some("Add device by address, like 192.168.1.5:36571"));
+        let add_peer = Self::setup_add_peer(discovery_sender);
+        add_peer.set_margin_start(10);
+        add_peer.set_margin_end(10);
+
+        vbox.pack_start(&peer_label, true, true, 10);
+        vbox.pack_start(&add_peer, true, true, 10);
         vbox.show_all();
 
         popover.add(&vbox);
@@ -195,6 +320,127 @@ impl MainLayout {
         item_layout
     }
 
+    /// The limits apply to the running transfers right away.
+    fn setup_rate_limits(bandwidth: Bandwidth) -> gtk::Grid {
+        let grid = gtk::Grid::new();
+        grid.set_row_spacing(5);
+        grid.set_column_spacing(10);
+
+        let limit = bandwidth.global_limit();
+        let upload = gtk::SpinButton::with_range(0.0, MAX_RATE_LIMIT, 100.0);
+        let download = gtk::SpinButton::with_range(0.0, MAX_RATE_LIMIT, 100.0);
+        upload.set_value(limit.upload as f64);
+        download.set_value(limit.download as f64);
+
+        grid.attach(&gtk::Label::new(Some("Upload")), 0, 0, 1, 1);
+        grid.attach(&upload, 1, 0, 1, 1);
+        grid.attach(&gtk::Label::new(Some("Download")), 0, 1, 1, 1);
+        grid.attach(&download, 1, 1, 1, 1);
+
+        for spin in [&upload, &download] {
+            let (upload, download) = (upload.clone(), download.clone());
+            let bandwidth = bandwidth.clone();
+            spin.connect_value_changed(move |_| {
+                let limit = RateLimit {
+                    upload: upload.value_as_int() as u64,
+                    download: download.value_as_int() as u64,
+                };
+                bandwidth.set_global_limit(limit);
+
+                // Loaded again, so that other settings changed meanwhile are kept
+                match UserConfig::new() {
+                    Ok(mut config) => {
+                        if let Err(e) = config.set_rate_limit(limit) {
+                            error!("Failed to save rate limits: {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to load config: {:?}", e),
+                }
+            });
+        }
+        grid
+    }
+
+    /// Saved and sent to the other devices when the entry loses focus or Enter is pressed.
+    fn setup_display_name(
+        discovery_sender: Sender<DiscoveryCommand>,
+    ) -> Result<gtk::Entry, Box<dyn Error>> {
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text(Some("Hostname"));
+        let config = UserConfig::new()?;
+        let saved = Rc::new(RefCell::new(config.get_display_name().to_string()));
+        entry.set_text(&saved.borrow());
+
+        let save = move |entry: &gtk::Entry| {
+            let name = entry.text().trim().to_string();
+            if *saved.borrow() == name {
+                return;
+            }
+            // Loaded again, so that other settings changed meanwhile are kept
+            let result = UserConfig::new().and_then(|mut config| config.set_display_name(&name));
+            if let Err(e) = result {
+                error!("Failed to save display name: {:?}", e);
+            }
+            saved.replace(name.clone());
+            if let Err(e) = discovery_sender.try_send(DiscoveryCommand::SetDisplayName(name)) {
+                error!("Failed to send display name: {:?}", e);
+            }
+        };
+        let save = Rc::new(save);
+        let save_clone = Rc::clone(&save);
+        entry.connect_activate(move |entry| save_clone(entry));
+        entry.connect_focus_out_event(move |entry, _| {
+            save(entry);
+            Inhibit(false)
+        });
+        Ok(entry)
+    }
+
+    /// For networks where mDNS doesn't find the other device.
+    /// The address is saved, so that it's dialed on every start.
+    fn setup_add_peer(discovery_sender: Sender<DiscoveryCommand>) -> gtk::Box {
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text(Some("host:port"));
+        let button = gtk::Button::with_label("Add");
+
+        let add = {
+            let entry = entry.clone();
+            move || {
+                let text = entry.text().to_string();
+                let addr = match manual_address(&text) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        warn!("Invalid address: {}", e);
+                        entry.style_context().add_class("error");
+                        return;
+                    }
+                };
+                entry.style_context().remove_class("error");
+                entry.set_text("");
+
+                match UserConfig::new() {
+                    Ok(mut config) => {
+                        if let Err(e) = config.add_manual_peer(text.trim()) {
+                            error!("Failed to save the peer: {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to load config: {:?}", e),
+                }
+                if let Err(e) = discovery_sender.try_send(DiscoveryCommand::Dial(addr)) {
+                    error!("Failed to dial the peer: {:?}", e);
+                }
+            }
+        };
+        let add_clone = add.clone();
+        button.connect_clicked(move |_| add_clone());
+        entry.connect_activate(move |_| add());
+
+        hbox.pack_start(&entry, true, true, 0);
+        hbox.pack_start(&button, false, false, 0);
+        hbox
+    }
+
     fn setup_file_chooser() -> Result<gtk::FileChooserButton, Box<dyn Error>> {
         let file_chooser =
             gtk::FileChooserButton::new("Choose file", gtk::FileChooserAction::SelectFolder);
@@ -207,7 +453,10 @@ impl MainLayout {
             match chooser.filename() {
                 Some(path) => {
                     info!("Setting downloads directory: {:?}", path);
-                    if let Err(e) = config.set_downloads_dir(path.as_path()) {
+                    // Loaded again, so that the rate limits changed meanwhile are kept
+                    let result = UserConfig::new()
+                        .and_then(|mut config| config.set_downloads_dir(path.as_path()));
+                    if let Err(e) = result {
                         error!("Failed to set downloads directory: {:?}", e);
                     };
                 }
@@ -224,19 +473,41 @@ impl MainLayout {
 pub struct PeerItem {
     pub container: gtk::ListBoxRow,
     pub label: Label,
+    inner_container: gtk::Box,
 }
 
 impl PeerItem {
     // TODO: is this safe to use &str here?
-    pub fn new(name: &str, address: &Multiaddr, hostname: &str, os: &OperatingSystem) -> PeerItem {
-        let ip = PeerItem::extract_ip(&address);
+    /// `display_name` tells the peer apart from the others on the list.
+    pub fn new(peer: &Peer, display_name: &str) -> PeerItem {
+        let ip = PeerItem::extract_ip(&peer.address);
+        let version = match &peer.capabilities {
+            Some(capabilities) => capabilities.version.as_str(),
+            None => "Unknown",
+        };
+        let trust = match peer.trust {
+            Trust::Paired => "<b>Paired</b>\n",
+            Trust::Unpaired => "",
+            Trust::KeyChanged => concat!(
+                "<span foreground=\"red\"><b>Warning</b>: ",
+                "not the paired device with this name</span>\n"
+            ),
+        };
         let display_name = format!(
             concat!(
                 "<big><b>Device Name</b>: {}</big>\n",
+                "<b>Hostname</b>: {}\n",
                 "<big><b>IP Address</b>: {}</big>\n",
                 "<big><b>System</b>: {:?}</big>\n",
+                "<b>Dragit</b>: {}\n",
+                "{}",
             ),
-            hostname, ip, os
+            glib::markup_escape_text(display_name),
+            glib::markup_escape_text(&peer.hostname),
+            ip,
+            peer.os,
+            glib::markup_escape_text(version),
+            trust
         );
 
         let label = Label::new(None);
@@ -248,7 +519,7 @@ impl PeerItem {
         let image = gtk::Image::from_icon_name(Some("insert-object"), gtk::IconSize::Dialog);
 
         let container = gtk::ListBoxRow::new();
-        container.set_widget_name(name);
+        container.set_widget_name(&peer.name);
         container.set_vexpand(true);
 
         let inner_container = gtk::Box::new(gtk::Orientation::Vertical, 0);
@@ -258,13 +529,19 @@ impl PeerItem {
         inner_container.pack_start(&label, true, true, 0);
         container.add(&inner_container);
 
-        PeerItem { container, label }
+        PeerItem {
+            container,
+            label,
+            inner_container,
+        }
     }
 
     fn extract_ip(address: &Multiaddr) -> String {
-        let components = address.iter().collect::<Vec<Protocol>>();
-        let ip = &components[0];
-        ip.to_string().replace("/ip4/", "").replace("/ip6/", "")
+        // Known peers that never shared an address have none
+        match address.iter().next() {
+            Some(ip) => ip.to_string().replace("/ip4/", "").replace("/ip6/", ""),
+            None => "Unknown".to_string(),
+        }
     }
 
     pub fn bind_drag_and_drop(
@@ -289,9 +566,12 @@ impl PeerItem {
 
         self.container.connect_drag_data_received(
             move |_win, _drag_context, _, _, selection_data, _, _| {
-                let file_to_send = match selection_data.uris().pop() {
-                    Some(file) => Self::get_file_payload(&peer_id, file.to_string()),
-                    None => Self::get_text_payload(&selection_data, &peer_id),
+                let mut uris = selection_data.uris();
+                let file_to_send = match uris.len() {
+                    0 => Self::get_text_payload(&selection_data, &peer_id),
+                    1 => Self::get_file_payload(&peer_id, uris.remove(0).to_string()),
+                    // Dropping several files at once sends them all after a single prompt
+                    _ => Self::get_batch_payload(&peer_id, uris),
                 };
 
                 match file_to_send {
@@ -312,28 +592,66 @@ impl PeerItem {
         self
     }
 
+    /// Online peers that aren't paired get a button that shows the pairing code.
+    /// Confirmed peers are saved in the config.
+    pub fn bind_pairing(self, peer: &Peer, discovery_sender: Sender<DiscoveryCommand>) -> Self {
+        if !peer.online || peer.trust == Trust::Paired {
+            return self;
+        }
+        let peer_id = peer.peer_id;
+        let name = peer.shown_name().to_string();
+        let code = peer.pairing_code.clone();
+
+        let button = gtk::Button::with_label("Pair");
+        button.set_halign(gtk::Align::Center);
+        button.connect_clicked(move |button| {
+            let window = button
+                .toplevel()
+                .and_then(|window| window.downcast::<gtk::Window>().ok());
+            let dialog = PairingDialog::new(window.as_ref(), &name, &code);
+            if dialog.run() != gtk::ResponseType::Yes {
+                return;
+            }
+            // Loaded again, so that other settings changed meanwhile are kept
+            let result = UserConfig::new().and_then(|mut config| config.trust_peer(&peer_id));
+            if let Err(e) = result {
+                error!("Failed to save the paired peer: {:?}", e);
+                return;
+            }
+            if let Err(e) = discovery_sender.try_send(DiscoveryCommand::Trust(peer_id)) {
+                error!("Failed to pair with the peer: {:?}", e);
+            }
+        });
+        self.inner_container.pack_start(&button, false, false, 10);
+        self
+    }
+
     fn get_file_payload(peer_id: &PeerId, file: String) -> Result<FileToSend, Box<dyn Error>> {
+        let payload = Self::get_path_payload(file)?;
+        Ok(FileToSend::new(peer_id, payload)?)
+    }
+
+    fn get_batch_payload(
+        peer_id: &PeerId,
+        uris: Vec<glib::GString>,
+    ) -> Result<FileToSend, Box<dyn Error>> {
+        let items = uris
+            .into_iter()
+            .map(|uri| Self::get_path_payload(uri.to_string()))
+            .collect::<Result<Vec<Payload>, _>>()?;
+        Ok(FileToSend::new(peer_id, Payload::Batch(items))?)
+    }
+
+    fn get_path_payload(file: String) -> Result<Payload, Box<dyn Error>> {
         let file = gio::File::for_uri(&file);
-        if file.is_native() {
-            match file.path() {
-                Some(p) => {
-                    let path = clean_file_proto(&p.display().to_string());
-                    let payload = Payload::new_for_path(path)?;
-                    Ok(FileToSend::new(peer_id, payload)?)
-                }
-                None => {
-                    let uri: String = file.uri().into();
-                    let path = clean_file_proto(&uri);
-                    let payload = Payload::new_for_path(path)?;
-                    Ok(FileToSend::new(peer_id, payload)?)
-                }
+        let path = match file.path() {
+            Some(p) if file.is_native() => clean_file_proto(&p.display().to_string()),
+            _ => {
+                let uri: String = file.uri().into();
+                clean_file_proto(&uri)
             }
-        } else {
-            let uri: String = file.uri().into();
-            let path = clean_file_proto(&uri);
-            let payload = Payload::new_for_path(path)?;
-            Ok(FileToSend::new(peer_id, payload)?)
-        }
+        };
+        Ok(Payload::new_for_path(path)?)
     }
 
     fn get_text_payload(
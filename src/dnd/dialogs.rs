@@ -2,33 +2,134 @@ use gtk::prelude::*;
 
 use bytesize::ByteSize;
 
-use crate::p2p::TransferType;
+use crate::p2p::transfer::file::Manifest;
+use crate::p2p::{IncomingFile, TransferType, Trust};
 use crate::user_data::UserConfig;
 
 pub struct AcceptFileDialog(gtk::MessageDialog);
 
+/// Response of the accept dialog that replaces the file with the taken name
+pub const REPLACE_RESPONSE: u16 = 1;
+
 impl AcceptFileDialog {
-    pub fn new(
-        window: &gtk::ApplicationWindow,
-        name: String,
-        size: usize,
-        transfer_type: TransferType,
-    ) -> AcceptFileDialog {
-        let readable_size = ByteSize(size as u64);
-        let message = match transfer_type {
+    /// When the name is taken the dialog offers to keep both files, replace the existing
+    /// one or skip. The manifest of a directory is listed in an expander under the question.
+    /// Payloads from devices that take the name of a paired one come with a warning.
+    pub fn new(window: &gtk::ApplicationWindow, incoming: &IncomingFile) -> AcceptFileDialog {
+        let name = &incoming.name;
+        let mime_type = &incoming.mime_type;
+        let readable_size = ByteSize(incoming.size as u64);
+        let message = match incoming.transfer_type {
+            TransferType::File if !mime_type.is_empty() => {
+                format!("Incoming file {} ({}, {}).", name, readable_size, mime_type)
+            }
             TransferType::File => format!("Incoming file {} ({}).", name, readable_size),
             TransferType::Text => format!("Incoming text {}.", name),
-            TransferType::Dir => format!("Incoming directory {}.", name),
+            TransferType::Dir => match &incoming.manifest {
+                Some(manifest) => format!(
+                    "Incoming directory {} ({}, {} files).",
+                    name,
+                    ByteSize(manifest.total_size),
+                    manifest.file_count
+                ),
+                None => format!("Incoming directory {}.", name),
+            },
+            TransferType::Batch => format!("Incoming {} ({}).", name, readable_size),
+        };
+        let sender = match incoming.trust {
+            Trust::Paired => "It comes from a paired device.",
+            Trust::Unpaired => "It comes from a device that isn't paired.",
+            Trust::KeyChanged => concat!(
+                "It comes from a device with the name of a paired one, but a different key. ",
+                "It may be someone else."
+            ),
+        };
+        let message = format!("{}\n{}", message, sender);
+        let message_type = match incoming.trust {
+            Trust::KeyChanged => gtk::MessageType::Warning,
+            _ => gtk::MessageType::Question,
         };
-        let message = format!("{}\n\nWould you like to accept?", message);
+        let dialog = if incoming.name_taken {
+            let message = format!(
+                "{}\n\nThe name is taken already. Would you like to keep both or replace it?",
+                message
+            );
+            let dialog = gtk::MessageDialog::new(
+                Some(window),
+                gtk::DialogFlags::MODAL,
+                message_type,
+                gtk::ButtonsType::None,
+                &message,
+            );
+            dialog.add_button("Skip", gtk::ResponseType::No);
+            dialog.add_button("Replace", gtk::ResponseType::Other(REPLACE_RESPONSE));
+            dialog.add_button("Keep both", gtk::ResponseType::Yes);
+            dialog
+        } else {
+            let message = format!("{}\n\nWould you like to accept?", message);
+            gtk::MessageDialog::new(
+                Some(window),
+                gtk::DialogFlags::MODAL,
+                message_type,
+                gtk::ButtonsType::YesNo,
+                &message,
+            )
+        };
+        if let Some(manifest) = &incoming.manifest {
+            Self::add_manifest(&dialog, manifest);
+        }
+        AcceptFileDialog(dialog)
+    }
+
+    fn add_manifest(dialog: &gtk::MessageDialog, manifest: &Manifest) {
+        let mut contents = manifest.entries.join("\n");
+        if manifest.more_entries > 0 {
+            contents.push_str(&format!("\n... and {} more", manifest.more_entries));
+        }
+        let label = gtk::Label::new(Some(&contents));
+        label.set_halign(gtk::Align::Start);
+        label.set_selectable(true);
+
+        let expander = gtk::Expander::new(Some("Contents"));
+        expander.add(&label);
+        if let Ok(area) = dialog.message_area().downcast::<gtk::Box>() {
+            area.pack_start(&expander, false, false, 0);
+            expander.show_all();
+        }
+    }
+
+    pub fn run(&self) -> gtk::ResponseType {
+        let resp = self.0.run();
+        self.0.close();
+        resp
+    }
+}
+
+/// Shows the code that has to match the one on the other device.
+pub struct PairingDialog(gtk::MessageDialog);
+
+impl PairingDialog {
+    pub fn new(window: Option<&gtk::Window>, name: &str, code: &str) -> PairingDialog {
+        let text = format!(
+            concat!(
+                "Pair with {}?\n",
+                "\n",
+                "Check that the other device shows the same code:\n",
+                "\n",
+                "{}\n",
+                "\n",
+                "Pair only when the codes match, otherwise someone else may pretend to be it."
+            ),
+            name, code
+        );
         let dialog = gtk::MessageDialog::new(
-            Some(window),
+            window,
             gtk::DialogFlags::MODAL,
             gtk::MessageType::Question,
             gtk::ButtonsType::YesNo,
-            &message,
+            &text,
         );
-        AcceptFileDialog(dialog)
+        PairingDialog(dialog)
     }
 
     pub fn run(&self) -> gtk::ResponseType {
@@ -10,7 +10,7 @@ use glib::{timeout_add_local, Continue};
 use gtk::ApplicationWindow;
 
 use crate::dnd::components::{get_item_name, EmptyListItem, PeerItem};
-use crate::p2p::{CurrentPeers, FileToSend, PeerEvent};
+use crate::p2p::{CurrentPeers, DiscoveryCommand, FileToSend, PeerEvent, Trust};
 
 pub fn pool_peers(
     window: &ApplicationWindow,
@@ -18,6 +18,7 @@ pub fn pool_peers(
     file_sender: Arc<Mutex<Sender<FileToSend>>>,
     peer_receiver: Arc<Mutex<Receiver<PeerEvent>>>,
     peer_event_sender: glib::Sender<PeerEvent>,
+    discovery_sender: Sender<DiscoveryCommand>,
 ) {
     // TODO: investigate why set_placeholder() doesn't work
     let empty_item = EmptyListItem::new();
@@ -54,15 +55,27 @@ pub fn pool_peers(
                 remove_items(&layout_in);
 
                 for peer in peers.iter() {
-                    let name = &peer.name;
-                    let addr = &peer.address;
-                    let hostname = &peer.hostname;
-                    let os = &peer.os;
-
-                    let item = PeerItem::new(name, addr, &hostname, &os);
+                    let item = PeerItem::new(peer, &peer.distinct_name(&peers));
                     let sender = file_sender.clone();
                     let event_sender = peer_event_sender.clone();
-                    let item = item.bind_drag_and_drop(peer, sender, event_sender);
+                    let item = item
+                        .bind_drag_and_drop(peer, sender, event_sender)
+                        .bind_pairing(peer, discovery_sender.clone());
+                    if !peer.online {
+                        // Greyed out, nothing can be dropped on it until it reconnects
+                        item.container.set_sensitive(false);
+                        item.container
+                            .set_tooltip_text(Some("Offline, trying to reconnect"));
+                    } else if peer.trust == Trust::KeyChanged {
+                        item.container.set_tooltip_text(Some(
+                            "Uses the name of a paired device, but it's another one",
+                        ));
+                    } else if peer.is_outdated() {
+                        // Still works, but without the newer features
+                        item.container.set_tooltip_text(Some(
+                            "Runs an older version of Dragit, some features may not work",
+                        ));
+                    }
 
                     layout_in.add(&item.container);
                 }
@@ -18,10 +18,13 @@ use async_channel::{bounded, Receiver, Sender};
 #[cfg(target_os = "linux")]
 use crate::firewall::Firewall;
 
-use crate::p2p::{peer::Direction, run_server, FileToSend, PeerEvent, TransferCommand};
-use crate::user_data::UserConfig;
+use crate::p2p::{
+    peer::Direction, run_server, Bandwidth, DiscoveryCommand, FileToSend, PeerEvent, QueueCommand,
+    TransferCommand,
+};
+use crate::user_data::{ConflictPolicy, UserConfig};
 use components::{MainLayout, STYLE};
-use dialogs::{AcceptFileDialog, FirewallDialog};
+use dialogs::{AcceptFileDialog, FirewallDialog, REPLACE_RESPONSE};
 use events::pool_peers;
 use notifications::{AppNotification, NotificationType, ProgressNotification};
 
@@ -30,6 +33,7 @@ pub fn build_window(
     file_sender: Arc<Mutex<Sender<FileToSend>>>,
     peer_receiver: Arc<Mutex<Receiver<PeerEvent>>>,
     command_sender: Arc<Mutex<Sender<TransferCommand>>>,
+    layout: MainLayout,
     f: fn(&gtk::ApplicationWindow),
 ) -> Result<(), Box<dyn Error>> {
     let title = format!("Dragit {}", env!("CARGO_PKG_VERSION"));
@@ -37,8 +41,6 @@ pub fn build_window(
     glib::set_program_name(Some(&title));
     let window = gtk::ApplicationWindow::new(application);
 
-    let layout = MainLayout::new()?;
-
     let overlay = gtk::Overlay::new();
     window.set_titlebar(Some(&layout.bar));
 
@@ -47,7 +49,7 @@ pub fn build_window(
 
     let alert_notif = AppNotification::new(&overlay, NotificationType::Alert);
     let error_notif = AppNotification::new(&overlay, NotificationType::Error);
-    let progress = ProgressNotification::new(&overlay);
+    let progress = ProgressNotification::new(&overlay, Arc::clone(&command_sender));
 
     overlay.add_overlay(&layout.layout);
 
@@ -60,12 +62,14 @@ pub fn build_window(
         file_sender,
         peer_receiver,
         gtk_sender,
+        layout.discovery_sender.clone(),
     );
 
     let window_weak = window.downgrade();
     gtk_receiver.attach(None, move |values| match values {
-        PeerEvent::TransferProgress((v, t, direction, speed_bps)) => {
+        PeerEvent::TransferProgress((id, v, t, direction, speed_bps)) => {
             alert_notif.hide(&overlay);
+            progress.set_transfer(id);
             let size = v as f64;
             let total = t as f64;
             match direction {
@@ -74,6 +78,12 @@ pub fn build_window(
             }
             Continue(true)
         }
+        PeerEvent::DirectoryProgress(dir_progress) => {
+            alert_notif.hide(&overlay);
+            progress.set_transfer(dir_progress.id);
+            progress.show_entry(&overlay, &dir_progress);
+            Continue(true)
+        }
         PeerEvent::WaitingForAnswer => {
             alert_notif.show_text(&overlay, "Waiting for answer from the other device...");
             Continue(true)
@@ -86,7 +96,7 @@ pub fn build_window(
             progress.hide(&overlay);
             Continue(true)
         }
-        PeerEvent::FileCorrect(file_name, payload) => {
+        PeerEvent::FileCorrect(_id, file_name, payload) => {
             progress.progress_bar.set_fraction(0.0);
             progress.hide(&overlay);
 
@@ -95,28 +105,45 @@ pub fn build_window(
 
             Continue(true)
         }
-        PeerEvent::FileIncorrect => {
+        PeerEvent::FileIncorrect(files) => {
             progress.progress_bar.set_fraction(0.0);
             progress.hide(&overlay);
-            error_notif.show_text(&overlay, "File is incorrect");
+            let text = format!("File is incorrect: {}", files.join(", "));
+            error_notif.show_text(&overlay, &text);
             Continue(true)
         }
-        PeerEvent::FileIncoming(name, hash, size, transfer_type) => {
+        PeerEvent::FileIncoming(incoming) => {
             if let Some(win) = window_weak.upgrade() {
-                let accept_dialog = AcceptFileDialog::new(&win, name, size, transfer_type);
+                let accept_dialog = AcceptFileDialog::new(&win, &incoming);
                 let response = accept_dialog.run();
+                let (id, name_taken) = (incoming.id, incoming.name_taken);
 
                 let command = match response {
-                    gtk::ResponseType::Yes => TransferCommand::Accept(hash),
-                    gtk::ResponseType::No => TransferCommand::Deny(hash),
-                    _ => TransferCommand::Deny(hash),
+                    gtk::ResponseType::Yes if name_taken => {
+                        TransferCommand::AcceptWith(id, ConflictPolicy::Rename)
+                    }
+                    gtk::ResponseType::Yes => TransferCommand::Accept(id),
+                    gtk::ResponseType::Other(REPLACE_RESPONSE) => {
+                        TransferCommand::AcceptWith(id, ConflictPolicy::Overwrite)
+                    }
+                    gtk::ResponseType::No => TransferCommand::Deny(id),
+                    _ => TransferCommand::Deny(id),
                 };
 
                 let _ = command_sender.lock().unwrap().try_send(command);
             }
             Continue(true)
         }
-        PeerEvent::TransferFailed { file_name, reason } => {
+        PeerEvent::FileSkipped(file_name) => {
+            alert_notif.show_text(
+                &overlay,
+                &format!("Skipped '{}', the name is taken", file_name),
+            );
+            Continue(true)
+        }
+        PeerEvent::TransferFailed {
+            file_name, reason, ..
+        } => {
             error!("Transfer of '{}' failed: {}", file_name, reason);
             progress.progress_bar.set_fraction(0.0);
             progress.hide(&overlay);
@@ -126,6 +153,39 @@ pub fn build_window(
             );
             Continue(true)
         }
+        PeerEvent::PayloadRefused {
+            file_name, reason, ..
+        } => {
+            warn!("Refused '{}': {}", file_name, reason);
+            progress.progress_bar.set_fraction(0.0);
+            progress.hide(&overlay);
+            error_notif.show_text(
+                &overlay,
+                &format!("Refused '{}' from the other device: {}", file_name, reason),
+            );
+            Continue(true)
+        }
+        PeerEvent::TransferCancelled {
+            file_name,
+            reason,
+            by_remote,
+            ..
+        } => {
+            info!("Transfer of '{}' cancelled: {}", file_name, reason);
+            progress.progress_bar.set_fraction(0.0);
+            progress.hide(&overlay);
+            let text = if by_remote {
+                format!("Cancelled by the other device: {}", reason)
+            } else {
+                format!("Transfer of '{}' cancelled", file_name)
+            };
+            alert_notif.show_text(&overlay, &text);
+            Continue(true)
+        }
+        PeerEvent::QueueChanged(queue) => {
+            layout.show_queue(&queue);
+            Continue(true)
+        }
         PeerEvent::Error(error) => {
             error!("Got error: {}", error);
             progress.hide(&overlay);
@@ -152,7 +212,7 @@ fn handle_firewall(window: &gtk::ApplicationWindow) -> Result<(), Box<dyn Error>
     // Check firewalld configuration if applicable and offer permanently opening ports
     // in case they are closed in the runtime rules.
     // If user happens not to use firewalld in their distribution, this function will just return error
-    let config = UserConfig::new()?;
+    let mut config = UserConfig::new()?;
     let port = config.get_port();
 
     if !config.get_firewall_checked() {
@@ -196,11 +256,30 @@ pub fn start_window(name: String) {
     let (file_sender, file_receiver) = bounded::<FileToSend>(1024 * 24);
     let (peer_sender, peer_receiver) = bounded::<PeerEvent>(1024 * 24);
     let (command_sender, command_receiver) = bounded::<TransferCommand>(1024 * 24);
+    let (queue_sender, queue_receiver) = bounded::<QueueCommand>(1024);
+    let (discovery_sender, discovery_receiver) = bounded::<DiscoveryCommand>(1024);
+
+    // The limits are changed in the menu while transfers run
+    let bandwidth = match UserConfig::new() {
+        Ok(config) => Bandwidth::new(config.get_rate_limit(), config.get_peer_rate_limits()),
+        Err(e) => {
+            error!("Could not load rate limits: {:?}", e);
+            Bandwidth::default()
+        }
+    };
 
     // Start the p2p server in separate thread
     let sender_clone = peer_sender.clone();
-    thread::spawn(
-        move || match run_server(peer_sender, file_receiver, command_receiver) {
+    let server_bandwidth = bandwidth.clone();
+    thread::spawn(move || {
+        match run_server(
+            peer_sender,
+            file_receiver,
+            command_receiver,
+            queue_receiver,
+            discovery_receiver,
+            server_bandwidth,
+        ) {
             Ok(_) => {}
             Err(e) => {
                 error!("Server error: {:?}", e);
@@ -208,8 +287,8 @@ pub fn start_window(name: String) {
                     .try_send(PeerEvent::Error(e.to_string()))
                     .unwrap();
             }
-        },
-    );
+        }
+    });
 
     let peer_receiver_arc = Arc::new(Mutex::new(peer_receiver));
     let application = gtk::Application::new(Some(&name), gio::ApplicationFlags::empty());
@@ -229,11 +308,24 @@ pub fn start_window(name: String) {
         let peer_receiver_c = Arc::clone(&peer_receiver_arc);
         let command_sender_c = Arc::new(Mutex::new(command_sender.clone()));
 
+        let layout = match MainLayout::new(
+            bandwidth.clone(),
+            queue_sender.clone(),
+            discovery_sender.clone(),
+        ) {
+            Ok(layout) => layout,
+            Err(e) => {
+                error!("Layout error: {:?}", e);
+                return;
+            }
+        };
+
         match build_window(
             app,
             file_sender_c,
             peer_receiver_c,
             command_sender_c,
+            layout,
             |window| match handle_firewall(window) {
                 Ok(_) => {}
                 Err(e) => error!("Firewall handling error: {}", e),
@@ -1,23 +1,38 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use async_channel::Sender;
 use gtk::prelude::*;
 use gtk::Label;
 
 use crate::dnd::components::get_link;
-use crate::p2p::Payload;
+use crate::p2p::peer::Direction;
+use crate::p2p::{DirectoryProgress, Payload, TransferCommand, TransferId};
 
 pub struct ProgressNotification {
     revealer: gtk::Revealer,
     overlay: gtk::Overlay,
     pub progress_bar: gtk::ProgressBar,
+    /// Transfer that the cancel button stops
+    transfer: Rc<Cell<Option<TransferId>>>,
+    /// Current file of a directory, with the count of files
+    entry: RefCell<Option<String>>,
 }
 
 impl ProgressNotification {
-    pub fn new(main_overlay: &gtk::Overlay) -> Self {
+    pub fn new(
+        main_overlay: &gtk::Overlay,
+        command_sender: Arc<Mutex<Sender<TransferCommand>>>,
+    ) -> Self {
         let layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
         layout.set_widget_name("notification");
 
         let overlay = gtk::Overlay::new();
         let revealer = gtk::Revealer::new();
         let progress_bar = gtk::ProgressBar::new();
+        let cancel_button = gtk::Button::with_label("Cancel");
+        let transfer: Rc<Cell<Option<TransferId>>> = Rc::new(Cell::new(None));
 
         revealer.set_halign(gtk::Align::Center);
         revealer.set_valign(gtk::Align::Start);
@@ -32,7 +47,20 @@ impl ProgressNotification {
         progress_bar.set_size_request(500, 50);
         revealer.set_margin_bottom(30);
 
+        cancel_button.set_valign(gtk::Align::Center);
+        let transfer_c = Rc::clone(&transfer);
+        cancel_button.connect_clicked(move |_| {
+            if let Some(id) = transfer_c.take() {
+                info!("Cancelling transfer {}", id);
+                let _ = command_sender
+                    .lock()
+                    .unwrap()
+                    .try_send(TransferCommand::Cancel(id));
+            }
+        });
+
         layout.pack_start(&progress_bar, true, false, 0);
+        layout.pack_start(&cancel_button, false, false, 0);
         revealer.add(&layout);
 
         overlay.add_overlay(&revealer);
@@ -44,9 +72,16 @@ impl ProgressNotification {
             revealer,
             overlay,
             progress_bar,
+            transfer,
+            entry: RefCell::new(None),
         }
     }
 
+    /// Remember the transfer in progress, so that it can be cancelled.
+    pub fn set_transfer(&self, id: TransferId) {
+        self.transfer.set(Some(id));
+    }
+
     fn show(&self, main_overlay: &gtk::Overlay) {
         main_overlay.reorder_overlay(&self.overlay, 10);
         self.revealer.set_reveal_child(true)
@@ -58,6 +93,37 @@ impl ProgressNotification {
         self.progress_bar.set_text(Some(text));
     }
 
+    /// Name the file of a directory that is being transferred. The name
+    /// stays in the text until the directory is complete.
+    pub fn show_entry(&self, main_overlay: &gtk::Overlay, progress: &DirectoryProgress) {
+        let entry = match progress.files_total {
+            0 => format!("{} ({} files done)", progress.path, progress.files_done),
+            total => format!(
+                "{} ({} of {} files)",
+                progress.path, progress.files_done, total
+            ),
+        };
+        let verb = match progress.direction {
+            Direction::Incoming => "Receiving",
+            Direction::Outgoing => "Sending",
+        };
+        self.show(main_overlay);
+        self.progress_bar
+            .set_text(Some(&format!("{} {}", verb, entry)));
+
+        // Items of a batch that follow the directory are plain files again
+        let complete = progress.files_total > 0 && progress.files_done == progress.files_total;
+        self.entry
+            .replace(if complete { None } else { Some(entry) });
+    }
+
+    fn current_item(&self) -> String {
+        self.entry
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| "file".to_string())
+    }
+
     pub fn show_incoming(
         &self,
         main_overlay: &gtk::Overlay,
@@ -65,7 +131,11 @@ impl ProgressNotification {
         total: f64,
         speed_bps: Option<f64>,
     ) {
-        let text = format!("Receiving file: {}", format_speed(speed_bps));
+        let text = format!(
+            "Receiving {}: {}",
+            self.current_item(),
+            format_speed(speed_bps)
+        );
         self.show_progress(main_overlay, size, total, &text);
     }
 
@@ -76,12 +146,18 @@ impl ProgressNotification {
         total: f64,
         speed_bps: Option<f64>,
     ) {
-        let text = format!("Sending file: {}", format_speed(speed_bps));
+        let text = format!(
+            "Sending {}: {}",
+            self.current_item(),
+            format_speed(speed_bps)
+        );
         self.show_progress(main_overlay, size, total, &text);
     }
 
     pub fn hide(&self, main_overlay: &gtk::Overlay) {
         main_overlay.reorder_overlay(&self.overlay, 0);
+        self.transfer.set(None);
+        self.entry.replace(None);
 
         self.revealer.set_reveal_child(false)
     }
@@ -223,6 +299,11 @@ impl AppNotification {
                 self.remove_link();
                 self.label.set_text("Received text");
             }
+            Payload::Batch(items) => {
+                self.remove_link();
+                self.label
+                    .set_text(&format!("Received {} items", items.len()));
+            }
         };
 
         self.reveal(overlay);
@@ -1,5 +1,208 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+
+use async_channel::Receiver;
+use libp2p::{Multiaddr, PeerId};
+use tokio::sync::Mutex;
+
+use crate::p2p::peer::TransferId;
+use crate::user_data::ConflictPolicy;
+
 #[derive(Debug)]
 pub enum TransferCommand {
-    Accept(String),
-    Deny(String),
+    Accept(TransferId),
+    /// Accept the transfer, resolving a name collision with the given policy
+    AcceptWith(TransferId, ConflictPolicy),
+    Deny(TransferId),
+    /// Stop the transfer after it was accepted, on either side
+    Cancel(TransferId),
+}
+
+/// Changes to the outgoing files that didn't start yet.
+#[derive(Clone, Copy, Debug)]
+pub enum QueueCommand {
+    Remove(TransferId),
+    /// Move the file to the position among the files waiting for the same peer
+    MoveTo(TransferId, usize),
+}
+
+/// Requests from the menu that concern the other devices rather than transfers.
+#[derive(Clone, Debug)]
+pub enum DiscoveryCommand {
+    /// Connect to an address entered by the user
+    Dial(Multiaddr),
+    /// Name shown to the other devices, empty for the hostname
+    SetDisplayName(String),
+    /// Users compared the pairing codes, already saved in the config
+    Trust(PeerId),
+}
+
+impl TransferCommand {
+    pub fn transfer_id(&self) -> TransferId {
+        match self {
+            TransferCommand::Accept(id) => *id,
+            TransferCommand::AcceptWith(id, _) => *id,
+            TransferCommand::Deny(id) => *id,
+            TransferCommand::Cancel(id) => *id,
+        }
+    }
+
+    /// Policy chosen by the user instead of the configured one.
+    pub fn conflict_policy(&self) -> Option<ConflictPolicy> {
+        match self {
+            TransferCommand::AcceptWith(_, policy) => Some(*policy),
+            _ => None,
+        }
+    }
+}
+
+/// Delivers commands from the single UI channel to the transfers they belong to.
+///
+/// Transfers waiting for a command take turns in holding the channel. A command
+/// addressed to another transfer is parked in `unclaimed` and the channel is
+/// released, so that its owner can pick it up. Transfers `forget` their
+/// commands once they end.
+#[derive(Clone, Debug)]
+pub struct CommandRouter {
+    receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+    unclaimed: Arc<StdMutex<HashMap<TransferId, TransferCommand>>>,
+}
+
+impl CommandRouter {
+    pub fn new(receiver: Arc<Mutex<Receiver<TransferCommand>>>) -> Self {
+        CommandRouter {
+            receiver,
+            unclaimed: Arc::new(StdMutex::new(HashMap::new())),
+        }
+    }
+
+    /// Waits until a command for transfer `id` arrives.
+    pub async fn wait_for(&self, id: TransferId) -> TransferCommand {
+        self.wait_for_matching(id, |_| true).await
+    }
+
+    /// Waits until the user cancels transfer `id`. Other commands for it
+    /// stay parked, as a running transfer has no use for them.
+    pub async fn cancelled(&self, id: TransferId) {
+        self.wait_for_matching(id, |command| matches!(command, TransferCommand::Cancel(_)))
+            .await;
+    }
+
+    /// Drops the command parked for transfer `id`, which ended.
+    pub fn forget(&self, id: TransferId) {
+        if let Some(command) = self.unclaimed.lock().unwrap().remove(&id) {
+            debug!("Dropping command for an ended transfer: {:?}", command);
+        }
+    }
+
+    async fn wait_for_matching(
+        &self,
+        id: TransferId,
+        wanted: impl Fn(&TransferCommand) -> bool,
+    ) -> TransferCommand {
+        loop {
+            if let Some(command) = self.take_unclaimed(id, &wanted) {
+                return command;
+            }
+            let receiver = self.receiver.lock().await;
+
+            // The command could have been parked while we were waiting for the lock
+            if let Some(command) = self.take_unclaimed(id, &wanted) {
+                return command;
+            }
+            match receiver.recv().await {
+                Ok(command) if command.transfer_id() == id && wanted(&command) => {
+                    info!("Got the command: {:?}", command);
+                    return command;
+                }
+                Ok(command) if command.transfer_id() == id => {
+                    debug!("Parking command for a running transfer: {:?}", command);
+                    self.unclaimed.lock().unwrap().insert(id, command);
+                }
+                Ok(command) => {
+                    debug!("Parking command for another transfer: {:?}", command);
+                    self.unclaimed
+                        .lock()
+                        .unwrap()
+                        .insert(command.transfer_id(), command);
+                }
+                Err(_) => {
+                    info!("Receiver closed, retrying...");
+                    drop(receiver);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+                }
+            }
+        }
+    }
+
+    fn take_unclaimed(
+        &self,
+        id: TransferId,
+        wanted: impl Fn(&TransferCommand) -> bool,
+    ) -> Option<TransferCommand> {
+        let mut unclaimed = self.unclaimed.lock().unwrap();
+        match unclaimed.get(&id) {
+            Some(command) if wanted(command) => unclaimed.remove(&id),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_channel::bounded;
+    use tokio::sync::Mutex;
+
+    use crate::p2p::commands::{CommandRouter, TransferCommand};
+
+    #[tokio::test]
+    async fn test_commands_reach_their_transfers() {
+        let (sender, receiver) = bounded::<TransferCommand>(10);
+        let router = CommandRouter::new(Arc::new(Mutex::new(receiver)));
+
+        let first = tokio::spawn({
+            let router = router.clone();
+            async move { router.wait_for(1).await }
+        });
+        let second = tokio::spawn({
+            let router = router.clone();
+            async move { router.wait_for(2).await }
+        });
+
+        sender.send(TransferCommand::Deny(2)).await.unwrap();
+        sender.send(TransferCommand::Accept(1)).await.unwrap();
+
+        assert!(matches!(first.await.unwrap(), TransferCommand::Accept(1)));
+        assert!(matches!(second.await.unwrap(), TransferCommand::Deny(2)));
+    }
+
+    #[tokio::test]
+    async fn test_running_transfer_leaves_other_commands_parked() {
+        let (sender, receiver) = bounded::<TransferCommand>(10);
+        let router = CommandRouter::new(Arc::new(Mutex::new(receiver)));
+
+        sender.send(TransferCommand::Accept(1)).await.unwrap();
+        sender.send(TransferCommand::Cancel(1)).await.unwrap();
+        router.cancelled(1).await;
+
+        assert!(matches!(
+            router.wait_for(1).await,
+            TransferCommand::Accept(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commands_of_ended_transfers_are_forgotten() {
+        let (sender, receiver) = bounded::<TransferCommand>(10);
+        let router = CommandRouter::new(Arc::new(Mutex::new(receiver)));
+
+        sender.send(TransferCommand::Deny(2)).await.unwrap();
+        sender.send(TransferCommand::Accept(1)).await.unwrap();
+        router.wait_for(1).await;
+        router.forget(2);
+
+        assert!(router.unclaimed.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,61 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+
+/// Address of a peer entered by the user, for networks where mDNS doesn't work.
+/// Accepts "ip:port", "hostname:port" or a multiaddr, like "/ip4/10.0.0.2/tcp/36571".
+/// Hostnames are resolved when the peer is dialed.
+pub fn manual_address(address: &str) -> Result<Multiaddr, Error> {
+    let address = address.trim();
+    if address.starts_with('/') {
+        return address
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e));
+    }
+
+    let (host, port) = match address.parse::<SocketAddr>() {
+        Ok(socket) => (Protocol::from(socket.ip()), socket.port()),
+        Err(_) => {
+            let invalid = || {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Expected host:port, got '{}'", address),
+                )
+            };
+            let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            if host.is_empty() || host.contains(['/', ':', '[', ']']) {
+                return Err(invalid());
+            }
+            (Protocol::Dns(host.into()), port)
+        }
+    };
+    Ok(Multiaddr::empty().with(host).with(Protocol::Tcp(port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::discovery::address::manual_address;
+
+    #[test]
+    fn test_manual_address() {
+        let parse = |address| manual_address(address).map(|addr| addr.to_string());
+
+        assert_eq!(
+            parse("192.168.1.5:36571").unwrap(),
+            "/ip4/192.168.1.5/tcp/36571"
+        );
+        assert_eq!(parse("[fe80::1]:36571").unwrap(), "/ip6/fe80::1/tcp/36571");
+        assert_eq!(
+            parse(" laptop.local:4000 ").unwrap(),
+            "/dns/laptop.local/tcp/4000"
+        );
+        assert_eq!(parse("/ip4/10.0.0.2/tcp/5").unwrap(), "/ip4/10.0.0.2/tcp/5");
+
+        assert!(parse("laptop.local").is_err());
+        assert!(parse(":4000").is_err());
+        assert!(parse("laptop:port").is_err());
+        assert!(parse("fe80::1:4000").is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
+    future::Future,
+    pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
@@ -8,35 +10,86 @@ use std::{
 use async_channel::Sender;
 use hostname;
 use libp2p::core::Multiaddr;
+use libp2p::swarm::DialError;
 use libp2p::swarm::{
     dial_opts::{DialOpts, PeerCondition},
     ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
     THandlerOutEvent, ToSwarm,
 };
 use libp2p::PeerId;
+use tokio::time::{sleep, Sleep};
 
 use crate::p2p::discovery::handler::KeepAliveHandler;
-use crate::p2p::discovery::protocol::{Discovery, DiscoveryEvent};
-use crate::p2p::peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent};
+use crate::p2p::discovery::protocol::{Discovery, DiscoveryEvent, DisplayName};
+use crate::p2p::pairing::pairing_code;
+use crate::p2p::peer::{
+    Capabilities, CurrentPeers, OperatingSystem, Peer, PeerEvent, PeerTrust, Trust,
+};
+use crate::user_data::KnownPeers;
 
 type Handler = KeepAliveHandler<Discovery, Discovery, Discovery>;
 
+/// Wait before the first attempt to reconnect, doubled after every failed one
+const REDIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_REDIAL_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Reconnection to a known peer that went offline.
+struct Redial {
+    attempt: u32,
+    /// None while the dial is in progress
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+fn redial_backoff(attempt: u32) -> Duration {
+    REDIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_REDIAL_BACKOFF)
+}
+
 pub struct DiscoveryBehaviour {
     events: VecDeque<ToSwarm<DiscoveryEvent, THandlerInEvent<Self>>>,
     peers: HashMap<PeerId, Peer>,
+    local_peer_id: PeerId,
     hostname: String,
     os: OperatingSystem,
+    display_name: DisplayName,
+    /// Advertised to the peers, limits can be set after new()
+    pub capabilities: Capabilities,
     sender: Sender<PeerEvent>,
+    /// Dials of addresses entered by the user. Their peers are not known
+    /// until the hosts exchange their details.
+    manual: HashMap<ConnectionId, Multiaddr>,
+    /// Peers seen before, kept on the list while they are offline
+    known: KnownPeers,
+    /// Addresses the peers listen on. Inbound connections come from other ports.
+    dialable: HashMap<PeerId, Multiaddr>,
+    /// Peers with at least one open connection
+    connected: HashSet<PeerId>,
+    redials: HashMap<PeerId, Redial>,
+    /// Peers the user paired with
+    trusted: HashSet<PeerId>,
+    /// Trust of the listed peers, updated with every change of the list
+    pub peer_trust: PeerTrust,
 }
 
 impl DiscoveryBehaviour {
-    pub fn new(sender: Sender<PeerEvent>) -> Self {
+    pub fn new(sender: Sender<PeerEvent>, local_peer_id: PeerId) -> Self {
         DiscoveryBehaviour {
             events: VecDeque::new(),
             peers: HashMap::new(),
+            local_peer_id,
             hostname: Self::get_hostname(),
             os: Self::get_os(),
+            display_name: DisplayName::default(),
+            capabilities: Capabilities::local(None),
             sender,
+            manual: HashMap::new(),
+            known: KnownPeers::default(),
+            dialable: HashMap::new(),
+            connected: HashSet::new(),
+            redials: HashMap::new(),
+            trusted: HashSet::new(),
+            peer_trust: PeerTrust::default(),
         }
     }
 
@@ -60,12 +113,74 @@ impl DiscoveryBehaviour {
         }
     }
 
+    /// Details of this host sent to the other peers
+    fn discovery(&self) -> Discovery {
+        Discovery {
+            hostname: self.hostname.clone(),
+            os: self.os,
+            display_name: self.display_name.clone(),
+            capabilities: Some(self.capabilities.clone()),
+        }
+    }
+
+    /// Empty name shows the hostname. Connected peers learn the new name right away.
+    pub fn set_display_name(&mut self, name: String) {
+        info!("Setting display name: {:?}", name);
+        self.display_name.set(name);
+        for peer_id in self.connected.clone() {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id,
+                handler: libp2p::swarm::NotifyHandler::Any,
+                event: self.discovery(),
+            });
+        }
+    }
+
     fn peers_event(&mut self) -> CurrentPeers {
-        self.peers
-            .clone()
-            .into_iter()
-            .map(|(_, peer)| peer.to_owned())
-            .collect::<CurrentPeers>()
+        let peers = self
+            .peers
+            .values()
+            .map(|peer| Peer {
+                trust: self.trust(peer),
+                pairing_code: pairing_code(&self.local_peer_id, &peer.peer_id),
+                ..peer.to_owned()
+            })
+            .collect::<CurrentPeers>();
+        let trust = peers.iter().map(|peer| (peer.peer_id, peer.trust));
+        self.peer_trust.set(trust.collect());
+        peers
+    }
+
+    /// Peers paired with before, as saved in the config.
+    pub fn set_trusted(&mut self, trusted: HashSet<PeerId>) {
+        self.trusted = trusted;
+    }
+
+    /// The user saw the same pairing code on both devices.
+    pub fn trust_peer(&mut self, peer_id: PeerId) {
+        info!("Pairing with {:?}", peer_id);
+        self.trusted.insert(peer_id);
+        if let Err(e) = self.notify_frontend() {
+            error!("Failed to notify the frontend: {:?}", e);
+        }
+    }
+
+    /// Paired devices are told apart by their keys, the names can be taken by anyone.
+    fn trust(&self, peer: &Peer) -> Trust {
+        if self.trusted.contains(&peer.peer_id) {
+            return Trust::Paired;
+        }
+        let name = peer.shown_name();
+        let impersonates = self
+            .trusted
+            .iter()
+            .filter_map(|peer_id| self.known.get(peer_id))
+            .any(|known| known.shown_name() == name);
+        if impersonates {
+            Trust::KeyChanged
+        } else {
+            Trust::Unpaired
+        }
     }
 
     pub fn notify_frontend(&mut self) -> Result<(), Box<dyn Error>> {
@@ -84,22 +199,112 @@ impl DiscoveryBehaviour {
         if insert_peer {
             let peer = Peer {
                 name: peer_id.to_base58(),
-                peer_id: peer_id.clone(),
+                peer_id,
                 address: addr,
                 hostname: "Not known yet".to_string(),
                 os: OperatingSystem::Unknown,
+                display_name: String::new(),
+                online: true,
+                capabilities: None,
+                trust: Trust::Unpaired,
+                pairing_code: String::new(),
             };
             self.peers.insert(peer_id, peer);
         }
     }
 
+    /// Shows the peers seen before as offline and tries to reconnect to them.
+    pub fn restore_known_peers(&mut self, known: KnownPeers) {
+        for (peer_id, known_peer) in known.iter() {
+            let address = match known_peer.addresses.first() {
+                Some(address) => address.clone(),
+                None => Multiaddr::empty(),
+            };
+            let peer = Peer {
+                name: peer_id.to_base58(),
+                peer_id: *peer_id,
+                address,
+                hostname: known_peer.hostname.clone(),
+                os: known_peer.os,
+                display_name: known_peer.display_name.clone(),
+                online: false,
+                capabilities: None,
+                trust: Trust::Unpaired,
+                pairing_code: String::new(),
+            };
+            self.peers.entry(*peer_id).or_insert(peer);
+        }
+        let peer_ids: Vec<PeerId> = known.iter().map(|(peer_id, _)| *peer_id).collect();
+        self.known = known;
+        for peer_id in peer_ids {
+            self.redial(peer_id);
+        }
+
+        if let Err(e) = self.notify_frontend() {
+            error!("Failed to notify the frontend: {:?}", e);
+        }
+    }
+
+    fn redial(&mut self, peer_id: PeerId) {
+        let addresses = match self.known.get(&peer_id) {
+            Some(known_peer) if !known_peer.addresses.is_empty() => known_peer.addresses.clone(),
+            _ => return,
+        };
+        info!("Redialing known peer {:?}", peer_id);
+        self.events.push_back(ToSwarm::Dial {
+            opts: DialOpts::peer_id(peer_id)
+                .addresses(addresses)
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build(),
+        });
+        self.redials
+            .entry(peer_id)
+            .or_insert(Redial {
+                attempt: 0,
+                delay: None,
+            })
+            .delay = None;
+    }
+
+    /// Redials the known peer later, waiting longer after every failed attempt.
+    fn schedule_redial(&mut self, peer_id: PeerId) {
+        if self.known.get(&peer_id).is_none() || self.connected.contains(&peer_id) {
+            return;
+        }
+        let attempt = match self.redials.get(&peer_id) {
+            Some(redial) => redial.attempt + 1,
+            None => 0,
+        };
+        let backoff = redial_backoff(attempt);
+        info!("Redialing {:?} in {:?}", peer_id, backoff);
+        let redial = Redial {
+            attempt,
+            delay: Some(Box::pin(sleep(backoff))),
+        };
+        self.redials.insert(peer_id, redial);
+    }
+
+    /// Dials an address entered by the user. The peer shows up once
+    /// the discovery exchange over the new connection succeeds.
+    pub fn dial_address(&mut self, addr: Multiaddr) {
+        info!("Dialing manually added address {}", addr);
+        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+        self.manual.insert(opts.connection_id(), addr);
+        self.events.push_back(ToSwarm::Dial { opts });
+    }
+
     pub fn add_peer(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        self.dialable.insert(peer_id, addr.clone());
         match self.peers.get(&peer_id) {
             // Keep dialing if server didn't get host details yet
             Some(peer) if peer.os == OperatingSystem::Unknown => {
                 info!("OS unknown, dialing... {:?}", peer_id);
                 self.dial_peer(peer_id, addr, false);
             }
+            Some(peer) if !peer.online => {
+                info!("Known peer is back, dialing... {:?}", peer_id);
+                self.dial_peer(peer_id, addr, false);
+            }
             Some(_) => (),
             None => {
                 info!("Peer not found, dialing... {:?}", peer_id);
@@ -108,8 +313,17 @@ impl DiscoveryBehaviour {
         }
     }
 
+    /// Peers that are still connected stay, known ones are shown as offline.
     pub fn remove_peer(&mut self, peer_id: &PeerId) -> Result<(), Box<dyn Error>> {
-        self.peers.remove(peer_id);
+        if self.connected.contains(peer_id) {
+            return Ok(());
+        }
+        match self.peers.get_mut(peer_id) {
+            Some(peer) if self.known.get(peer_id).is_some() => peer.online = false,
+            _ => {
+                self.peers.remove(peer_id);
+            }
+        }
 
         if let Err(e) = self.notify_frontend() {
             error!("Failed to notify the frontend: {:?}", e);
@@ -117,23 +331,57 @@ impl DiscoveryBehaviour {
         Ok(())
     }
 
-    pub fn update_peer(&mut self, peer_id: PeerId, hostname: String, os: OperatingSystem) {
+    pub fn update_peer(&mut self, event: DiscoveryEvent) {
+        let peer_id = event.peer;
+        // Already warned about under its previous name
+        let warned =
+            self.peers.get(&peer_id).map(|peer| self.trust(peer)) == Some(Trust::KeyChanged);
         match self.peers.get_mut(&peer_id) {
             Some(peer) => {
                 info!("Updating peer. {:?}", peer_id);
-                peer.hostname = hostname;
-                peer.os = os;
+                peer.hostname = event.hostname;
+                peer.os = event.os;
+                peer.display_name = event.display_name;
+                peer.capabilities = event.capabilities;
+                peer.online = true;
+
+                let address = self.dialable.get(&peer_id);
+                if let Err(e) = self.known.seen(peer, address) {
+                    error!("Failed to save known peers: {:?}", e);
+                }
+                self.redials.remove(&peer_id);
             }
             None => {
                 error!("Peer not found! {:?}", peer_id);
             }
         }
 
+        self.warn_about_key(peer_id, warned);
         if let Err(e) = self.notify_frontend() {
             error!("Failed to notify the frontend: {:?}", e);
         }
     }
 
+    /// Tells the user once when a peer takes the name of a paired device.
+    fn warn_about_key(&mut self, peer_id: PeerId, warned: bool) {
+        let peer = match self.peers.get(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if warned || self.trust(peer) != Trust::KeyChanged {
+            return;
+        }
+        let name = peer.shown_name();
+        warn!("{:?} uses the name of a paired device: {}", peer_id, name);
+        let message = format!(
+            "{} has a different key than the paired device with that name, it may be someone else",
+            name
+        );
+        if let Err(e) = self.sender.try_send(PeerEvent::Error(message)) {
+            error!("Failed to send the warning: {:?}", e);
+        }
+    }
+
     /// Queue a NotifyHandler event that triggers the discovery substream exchange
     /// on the given connection. Both inbound and outbound connections call this so
     /// that discovery succeeds on whichever connection survives simultaneous-dial
@@ -146,10 +394,7 @@ impl DiscoveryBehaviour {
         let event = ToSwarm::NotifyHandler {
             peer_id,
             handler: libp2p::swarm::NotifyHandler::One(connection_id),
-            event: Discovery {
-                hostname: self.hostname.clone(),
-                os: self.os,
-            },
+            event: self.discovery(),
         };
         self.events.push_back(event);
     }
@@ -184,6 +429,11 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                     address: remote_addr.clone(),
                     hostname: "Not known yet".to_string(),
                     os: OperatingSystem::Unknown,
+                    display_name: String::new(),
+                    online: true,
+                    capabilities: None,
+                    trust: Trust::Unpaired,
+                    pairing_code: String::new(),
                 };
                 self.peers.insert(peer_id, peer);
             }
@@ -193,13 +443,7 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         // passively for the dialer to open one, which will be handled by
         // upgrade_inbound (reads the dialer's data, then writes back ours).
 
-        let substream_proto = libp2p::swarm::SubstreamProtocol::new(
-            Discovery {
-                hostname: self.hostname.clone(),
-                os: self.os,
-            },
-            (),
-        );
+        let substream_proto = libp2p::swarm::SubstreamProtocol::new(self.discovery(), ());
         let substream_proto = substream_proto.with_timeout(Duration::from_secs(2));
         Ok(Handler::new(substream_proto))
     }
@@ -213,22 +457,20 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         _port_use: libp2p::swarm::derive_prelude::PortUse,
     ) -> Result<THandler<Self>, ConnectionDenied> {
         info!("Outbound connection established: peer={:?}", peer_id);
+        self.dialable.insert(peer_id, addr.clone());
 
         if let Some(peer) = self.peers.get_mut(&peer_id) {
             info!("Dialer, updating the address");
             peer.address = addr.clone();
+        } else if let Some(manual) = self.manual.get_mut(&connection_id) {
+            // Resolved address, rather than the hostname
+            *manual = addr.clone();
         }
 
         // Trigger discovery on the outbound connection.
         self.queue_discovery_notify(peer_id, connection_id);
 
-        let substream_proto = libp2p::swarm::SubstreamProtocol::new(
-            Discovery {
-                hostname: self.hostname.clone(),
-                os: self.os,
-            },
-            (),
-        );
+        let substream_proto = libp2p::swarm::SubstreamProtocol::new(self.discovery(), ());
         let substream_proto = substream_proto.with_timeout(Duration::from_secs(2));
         Ok(Handler::new(substream_proto))
     }
@@ -237,7 +479,20 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         match event {
             FromSwarm::ConnectionClosed(info) => {
                 info!("Peer disconnected: {:?}", info.peer_id);
-                self.peers.remove(&info.peer_id);
+                self.manual.remove(&info.connection_id);
+                if info.remaining_established > 0 {
+                    return;
+                }
+                self.connected.remove(&info.peer_id);
+                match self.peers.get_mut(&info.peer_id) {
+                    Some(peer) if self.known.get(&info.peer_id).is_some() => {
+                        peer.online = false;
+                        self.schedule_redial(info.peer_id);
+                    }
+                    _ => {
+                        self.peers.remove(&info.peer_id);
+                    }
+                }
 
                 if let Err(e) = self.notify_frontend() {
                     error!("Failed to notify the frontend: {:?}", e);
@@ -248,7 +503,22 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                     "Connection established event: peer={:?}, endpoint={:?}",
                     info.peer_id, info.endpoint
                 );
-                let _ = info;
+                self.connected.insert(info.peer_id);
+            }
+            FromSwarm::DialFailure(info) => {
+                if let Some(addr) = self.manual.remove(&info.connection_id) {
+                    error!("Dialing {} failed: {}", addr, info.error);
+                    let message = format!("Could not connect to {}", addr);
+                    if let Err(e) = self.sender.try_send(PeerEvent::Error(message)) {
+                        error!("Failed to notify the frontend: {:?}", e);
+                    }
+                }
+                match (info.peer_id, info.error) {
+                    // Skipped because it's connected or being dialed already
+                    (_, DialError::DialPeerConditionFalse(_)) => (),
+                    (Some(peer_id), _) => self.schedule_redial(peer_id),
+                    (None, _) => (),
+                }
             }
             _ => {}
         }
@@ -257,18 +527,51 @@ impl NetworkBehaviour for DiscoveryBehaviour {
     fn on_connection_handler_event(
         &mut self,
         peer: PeerId,
-        _connection: ConnectionId,
+        connection: ConnectionId,
         event: THandlerOutEvent<Self>,
     ) {
+        if let Some(address) = self.manual.remove(&connection) {
+            // Details are filled in by update_peer
+            self.peers.entry(peer).or_insert_with(|| Peer {
+                name: peer.to_base58(),
+                peer_id: peer,
+                address,
+                hostname: "Not known yet".to_string(),
+                os: OperatingSystem::Unknown,
+                display_name: String::new(),
+                online: true,
+                capabilities: None,
+                trust: Trust::Unpaired,
+                pairing_code: String::new(),
+            });
+        }
         let message = DiscoveryEvent {
             peer,
+            connection,
             hostname: event.hostname,
             os: event.os,
+            display_name: event.display_name.get(),
+            capabilities: event.capabilities,
         };
         self.events.push_back(ToSwarm::GenerateEvent(message));
     }
 
-    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        let due: Vec<PeerId> = self
+            .redials
+            .iter_mut()
+            .filter_map(|(peer_id, redial)| {
+                let delay = redial.delay.as_mut()?;
+                delay.as_mut().poll(cx).is_ready().then_some(*peer_id)
+            })
+            .collect();
+        for peer_id in due {
+            self.redial(peer_id);
+        }
+
         if let Some(event) = self.events.pop_front() {
             Poll::Ready(event)
         } else {
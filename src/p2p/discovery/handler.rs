@@ -1,11 +1,22 @@
 use libp2p::swarm::handler::{
-    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, FullyNegotiatedInbound,
-    FullyNegotiatedOutbound, InboundUpgradeSend, OutboundUpgradeSend, SubstreamProtocol,
+    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, DialUpgradeError,
+    FullyNegotiatedInbound, FullyNegotiatedOutbound, InboundUpgradeSend, OutboundUpgradeSend,
+    StreamUpgradeError, SubstreamProtocol,
 };
 use std::fmt::Debug;
 
 use smallvec::SmallVec;
-use std::{error, task::Context, task::Poll, time::Duration};
+use std::{error, task::Context, task::Poll};
+
+/// Outbound substreams a connection opens at once, the rest wait in the queue.
+/// Transfers run within their upgrade, so they hold the slot until they end.
+pub const MAX_OUTBOUND_SUBSTREAMS: usize = 8;
+
+/// Outbound upgrades that tell their behaviour when their substream failed.
+pub trait OutboundFailure<TEvent>: OutboundUpgradeSend {
+    /// Event for the behaviour, None when logging the error is enough
+    fn failed(self, error: StreamUpgradeError<Self::Error>) -> Option<TEvent>;
+}
 
 /// Shamelessly copied OneShotHandler that keeps the connections open
 pub struct KeepAliveHandler<TInbound, TOutbound, TEvent>
@@ -34,7 +45,7 @@ where
             events_out: SmallVec::new(),
             dial_queue: SmallVec::new(),
             dial_negotiated: 0,
-            max_dial_negotiated: 8,
+            max_dial_negotiated: MAX_OUTBOUND_SUBSTREAMS as u32,
         }
     }
 
@@ -79,7 +90,7 @@ impl<TInbound, TOutbound, TEvent> ConnectionHandler
     for KeepAliveHandler<TInbound, TOutbound, TEvent>
 where
     TInbound: InboundUpgradeSend + Send + 'static,
-    TOutbound: OutboundUpgradeSend + Debug + Send + 'static,
+    TOutbound: OutboundFailure<TEvent> + Clone + Debug + Send + 'static,
     TInbound::Output: Into<TEvent>,
     TOutbound::Output: Into<TEvent>,
     TOutbound::Error: error::Error + Send + 'static,
@@ -90,7 +101,8 @@ where
     type ToBehaviour = TEvent;
     type InboundProtocol = TInbound;
     type OutboundProtocol = TOutbound;
-    type OutboundOpenInfo = ();
+    /// The upgrade itself, which is handed back when its substream fails
+    type OutboundOpenInfo = TOutbound;
     type InboundOpenInfo = ();
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
@@ -124,8 +136,12 @@ where
                 self.dial_negotiated -= 1;
                 self.events_out.push(out.into());
             }
-            ConnectionEvent::DialUpgradeError(e) => {
-                warn!("Dial upgrade error in KeepAliveHandler: {:?}", e.error);
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { info, error }) => {
+                warn!("Dial upgrade error in KeepAliveHandler: {:?}", error);
+                self.dial_negotiated -= 1;
+                if let Some(event) = info.failed(error) {
+                    self.events_out.push(event);
+                }
             }
             _ => {}
         }
@@ -154,8 +170,9 @@ where
                 self.dial_negotiated += 1;
                 let upgrade = self.dial_queue.remove(0);
                 return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                    protocol: SubstreamProtocol::new(upgrade, ())
-                        .with_timeout(Duration::from_secs(30 * 365 * 24 * 60 * 60)),
+                    // Outbound substreams get the same time as the inbound ones
+                    protocol: SubstreamProtocol::new(upgrade.clone(), upgrade)
+                        .with_timeout(*self.listen_protocol.timeout()),
                 });
             }
         } else {
@@ -1,3 +1,4 @@
+pub mod address;
 pub mod behaviour;
 pub mod handler;
 pub mod protocol;
@@ -6,5 +7,6 @@ pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/dragit.p2p.discovery.host.rs"));
 }
 
+pub use address::manual_address;
 pub use behaviour::DiscoveryBehaviour;
 pub use protocol::DiscoveryEvent;
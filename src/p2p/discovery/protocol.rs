@@ -1,35 +1,69 @@
-use std::{fmt, io, iter, pin::Pin};
+use std::{
+    fmt, io, iter,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
 
 use futures::io::AsyncWriteExt;
 use futures::prelude::*;
 use libp2p::core::{InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+use libp2p::swarm::{ConnectionId, StreamUpgradeError};
 use prost::Message;
 
-use super::proto::Host;
+use super::proto::{self, Host};
 
-use crate::p2p::peer::OperatingSystem;
+use crate::p2p::discovery::handler::OutboundFailure;
+use crate::p2p::peer::{Capabilities, OperatingSystem};
+use crate::p2p::transfer::{Compression, HashAlgorithm, ProtocolVersion};
 
 #[derive(Debug)]
 pub struct DiscoveryEvent {
     pub peer: PeerId,
+    /// Connection the hosts exchanged their details over
+    pub connection: ConnectionId,
     pub hostname: String,
     pub os: OperatingSystem,
+    /// Empty when the user didn't choose one
+    pub display_name: String,
+    /// None when the peer is too old to advertise them
+    pub capabilities: Option<Capabilities>,
 }
 
 impl fmt::Display for DiscoveryEvent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "DiscoveryEvent: peer: {}, hostname: {}, os: {:?}",
-            self.peer, self.hostname, self.os
+            "DiscoveryEvent: peer: {}, name: {}, hostname: {}, os: {:?}",
+            self.peer, self.display_name, self.hostname, self.os
         )
     }
 }
 
+/// Name the user chose for this device. Handlers of all connections share it,
+/// so that they answer with the current one after it's changed.
+#[derive(Clone, Debug, Default)]
+pub struct DisplayName(Arc<Mutex<String>>);
+
+impl DisplayName {
+    pub fn new(name: String) -> Self {
+        DisplayName(Arc::new(Mutex::new(name)))
+    }
+
+    pub fn get(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, name: String) {
+        *self.0.lock().unwrap() = name;
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Discovery {
     pub hostname: String,
     pub os: OperatingSystem,
+    pub display_name: DisplayName,
+    pub capabilities: Option<Capabilities>,
 }
 
 impl Default for Discovery {
@@ -37,10 +71,68 @@ impl Default for Discovery {
         Discovery {
             hostname: "".to_string(),
             os: OperatingSystem::Linux,
+            display_name: DisplayName::default(),
+            capabilities: None,
         }
     }
 }
 
+impl From<&Capabilities> for proto::Capabilities {
+    fn from(capabilities: &Capabilities) -> Self {
+        proto::Capabilities {
+            version: capabilities.version.clone(),
+            protocols: capabilities
+                .protocols
+                .iter()
+                .map(|version| version.protocol_name().to_string())
+                .collect(),
+            hash_algorithms: capabilities
+                .hash_algorithms
+                .iter()
+                .map(|algorithm| *algorithm as i32)
+                .collect(),
+            compressions: capabilities
+                .compressions
+                .iter()
+                .map(|compression| *compression as i32)
+                .collect(),
+            max_size: capabilities.max_size.unwrap_or(0),
+        }
+    }
+}
+
+impl From<proto::Capabilities> for Capabilities {
+    /// Values unknown to this build are left out.
+    fn from(proto: proto::Capabilities) -> Self {
+        Capabilities {
+            version: proto.version,
+            protocols: proto
+                .protocols
+                .iter()
+                .filter_map(|name| ProtocolVersion::from_name(name))
+                .collect(),
+            hash_algorithms: proto
+                .hash_algorithms
+                .into_iter()
+                .filter_map(|algorithm| HashAlgorithm::try_from(algorithm).ok())
+                .collect(),
+            compressions: proto
+                .compressions
+                .into_iter()
+                .filter_map(|compression| Compression::try_from(compression).ok())
+                .collect(),
+            max_size: Some(proto.max_size).filter(|max_size| *max_size > 0),
+        }
+    }
+}
+
+impl OutboundFailure<Discovery> for Discovery {
+    /// Discovery runs again on the next connection
+    fn failed(self, _error: StreamUpgradeError<io::Error>) -> Option<Discovery> {
+        None
+    }
+}
+
 impl UpgradeInfo for Discovery {
     type Info = &'static str;
     type InfoIter = iter::Once<Self::Info>;
@@ -51,10 +143,15 @@ impl UpgradeInfo for Discovery {
 }
 
 /// Encode a `Host` protobuf message as a u32 big-endian length-prefixed frame.
-fn encode_peer(hostname: String, os: OperatingSystem) -> Result<Vec<u8>, io::Error> {
+fn encode_peer(discovery: &Discovery) -> Result<Vec<u8>, io::Error> {
     let proto = Host {
-        hostname,
-        os: os as i32,
+        hostname: discovery.hostname.clone(),
+        os: discovery.os as i32,
+        display_name: discovery.display_name.get(),
+        capabilities: discovery
+            .capabilities
+            .as_ref()
+            .map(proto::Capabilities::from),
     };
     let payload_len = proto.encoded_len();
     let mut buf = Vec::with_capacity(4 + payload_len);
@@ -86,6 +183,8 @@ async fn read_peer(mut socket: impl AsyncRead + Unpin) -> Result<Discovery, io::
     Ok(Discovery {
         hostname: host.hostname,
         os,
+        display_name: DisplayName::new(host.display_name),
+        capabilities: host.capabilities.map(Capabilities::from),
     })
 }
 
@@ -94,10 +193,9 @@ async fn read_peer(mut socket: impl AsyncRead + Unpin) -> Result<Discovery, io::
 /// of which side opened the substream.
 async fn exchange_peer_info(
     socket: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    hostname: String,
-    os: OperatingSystem,
+    discovery: Discovery,
 ) -> Result<Discovery, io::Error> {
-    let outgoing = encode_peer(hostname, os)?;
+    let outgoing = encode_peer(&discovery)?;
     let (reader, mut writer) = futures::io::AsyncReadExt::split(socket);
 
     let write_fut = async move {
@@ -125,7 +223,7 @@ where
 
     fn upgrade_inbound(self, socket: TSocket, _info: Self::Info) -> Self::Future {
         // Fully symmetric: write and read concurrently, no ordering dependency.
-        Box::pin(exchange_peer_info(socket, self.hostname, self.os))
+        Box::pin(exchange_peer_info(socket, self))
     }
 }
 
@@ -139,6 +237,6 @@ where
 
     fn upgrade_outbound(self, socket: TSocket, _info: Self::Info) -> Self::Future {
         // Fully symmetric: write and read concurrently, no ordering dependency.
-        Box::pin(exchange_peer_info(socket, self.hostname, self.os))
+        Box::pin(exchange_peer_info(socket, self))
     }
 }
@@ -8,16 +8,23 @@ use libp2p::{mdns, noise, swarm::SwarmEvent, tcp, yamux, PeerId, Swarm, SwarmBui
 
 pub mod commands;
 pub mod discovery;
+pub mod pairing;
 pub mod peer;
 pub mod transfer;
 pub mod util;
 
 use crate::user_data::UserConfig;
-pub use commands::TransferCommand;
-pub use discovery::{DiscoveryBehaviour, DiscoveryEvent};
-pub use peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent, TransferType};
+pub use commands::{CommandRouter, DiscoveryCommand, QueueCommand, TransferCommand};
+pub use discovery::{manual_address, DiscoveryBehaviour, DiscoveryEvent};
+pub use peer::{
+    Capabilities, CurrentPeers, DirectoryProgress, IncomingFile, OperatingSystem, Peer, PeerEvent,
+    PeerQueue, PeerTrust, QueuedFile, TransferId, TransferType, Trust,
+};
 
-pub use transfer::{FileToSend, Payload, TransferBehaviour, TransferOut, TransferPayload};
+pub use transfer::limiter::Bandwidth;
+pub use transfer::{
+    FileToSend, HashAlgorithm, Payload, TransferBehaviour, TransferOut, TransferPayload,
+};
 
 #[derive(libp2p::swarm::NetworkBehaviour)]
 #[behaviour(to_swarm = "MyBehaviourEvent")]
@@ -63,6 +70,9 @@ async fn execute_swarm(
     sender: Sender<PeerEvent>,
     receiver: Receiver<FileToSend>,
     command_receiver: Receiver<TransferCommand>,
+    queue_receiver: Receiver<QueueCommand>,
+    discovery_receiver: Receiver<DiscoveryCommand>,
+    bandwidth: Bandwidth,
 ) -> Result<(), Box<dyn Error>> {
     let config = UserConfig::new()?;
     let local_keys = config.get_or_create_keypair()?;
@@ -71,6 +81,15 @@ async fn execute_swarm(
 
     let command_rec = Arc::new(Mutex::new(command_receiver));
     let command_receiver_c = Arc::clone(&command_rec);
+    let conflict_policy = config.get_conflict_policy();
+    let preserved = config.get_preserved_attributes();
+    let parallel_streams = config.get_parallel_streams();
+    let transfers_per_peer = config.get_transfers_per_peer();
+    let timeouts = config.get_timeouts();
+    let display_name = config.get_display_name().to_string();
+    let max_size = config.get_max_size();
+    let trusted_peers = config.get_trusted_peers();
+    let peer_trust = PeerTrust::default();
 
     let sender_clone = sender.clone();
 
@@ -81,14 +100,29 @@ async fn execute_swarm(
             noise::Config::new,
             yamux::Config::default,
         )?
+        // Manually added peers may be given by hostname
+        .with_dns()?
         .with_behaviour(move |key| {
             let mdns =
                 mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
                     .expect("Failed to create mdns behaviour");
 
-            let transfer_behaviour =
+            let mut transfer_behaviour =
                 TransferBehaviour::new(sender_clone.clone(), command_receiver_c.clone(), None);
-            let discovery = DiscoveryBehaviour::new(sender_clone.clone());
+            transfer_behaviour.conflict_policy = conflict_policy;
+            transfer_behaviour.preserved = preserved;
+            transfer_behaviour.bandwidth = bandwidth.clone();
+            transfer_behaviour.parallelism.streams = parallel_streams;
+            transfer_behaviour.transfers_per_peer = transfers_per_peer;
+            transfer_behaviour.timeouts = timeouts;
+            transfer_behaviour.max_size = max_size;
+            transfer_behaviour.peer_trust = peer_trust.clone();
+            let mut discovery =
+                DiscoveryBehaviour::new(sender_clone.clone(), key.public().to_peer_id());
+            discovery.set_trusted(trusted_peers.clone());
+            discovery.set_display_name(display_name);
+            discovery.capabilities = Capabilities::local(max_size);
+            discovery.peer_trust = peer_trust.clone();
 
             MyBehaviour {
                 mdns,
@@ -104,6 +138,18 @@ async fn execute_swarm(
     let address = format!("/ip4/0.0.0.0/tcp/{}", port);
     swarm.listen_on(address.parse()?)?;
 
+    match config.get_known_peers() {
+        Ok(known) => swarm.behaviour_mut().discovery.restore_known_peers(known),
+        Err(e) => error!("Could not load known peers: {:?}", e),
+    }
+
+    for address in config.get_manual_peers() {
+        match manual_address(address) {
+            Ok(addr) => swarm.behaviour_mut().discovery.dial_address(addr),
+            Err(e) => warn!("Ignoring manually added peer {}: {}", address, e),
+        }
+    }
+
     loop {
         select! {
             received = receiver.recv().fuse() => {
@@ -115,6 +161,21 @@ async fn execute_swarm(
                     Err(e) => error!("Receiver error: {:?}", e),
                 }
             },
+            command = queue_receiver.recv().fuse() => {
+                match command {
+                    Ok(command) => swarm.behaviour_mut().transfer_behaviour.queue_command(command),
+                    Err(e) => error!("Queue receiver error: {:?}", e),
+                }
+            },
+            command = discovery_receiver.recv().fuse() => {
+                let discovery = &mut swarm.behaviour_mut().discovery;
+                match command {
+                    Ok(DiscoveryCommand::Dial(addr)) => discovery.dial_address(addr),
+                    Ok(DiscoveryCommand::SetDisplayName(name)) => discovery.set_display_name(name),
+                    Ok(DiscoveryCommand::Trust(peer_id)) => discovery.trust_peer(peer_id),
+                    Err(e) => error!("Discovery receiver error: {:?}", e),
+                }
+            },
             swarm_event = swarm.select_next_some() => {
                 match swarm_event {
                     SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(event)) => {
@@ -122,11 +183,15 @@ async fn execute_swarm(
                     }
                     SwarmEvent::Behaviour(MyBehaviourEvent::Discovery(event)) => {
                         info!("Discovered: {}", event);
-                        swarm.behaviour_mut().discovery.update_peer(
-                            event.peer,
-                            event.hostname,
-                            event.os,
-                        );
+                        swarm
+                            .behaviour_mut()
+                            .transfer_behaviour
+                            .connection_discovered(event.peer, event.connection);
+                        swarm
+                            .behaviour_mut()
+                            .transfer_behaviour
+                            .peer_capabilities(event.peer, event.capabilities.as_ref());
+                        swarm.behaviour_mut().discovery.update_peer(event);
                     }
                     SwarmEvent::Behaviour(MyBehaviourEvent::Transfer(event)) => {
                         info!("Transfer event: {}", event);
@@ -136,7 +201,7 @@ async fn execute_swarm(
                         };
                         if let Err(e) = event
                             .sender_queue
-                            .try_send(PeerEvent::FileCorrect(event.name, event.payload))
+                            .try_send(PeerEvent::FileCorrect(event.id, event.name, event.payload))
                         {
                             error!("{:?}", e);
                         }
@@ -181,16 +246,21 @@ fn handle_mdns_event(swarm: &mut Swarm<MyBehaviour>, event: mdns::Event) {
     }
 }
 
+/// `discovery_receiver` gets the peers added by hand and the new display names.
+/// `bandwidth` is shared with the UI, which changes the limits.
 pub fn run_server(
     sender: Sender<PeerEvent>,
     file_receiver: Receiver<FileToSend>,
     command_receiver: Receiver<TransferCommand>,
+    queue_receiver: Receiver<QueueCommand>,
+    discovery_receiver: Receiver<DiscoveryCommand>,
+    bandwidth: Bandwidth,
 ) -> Result<(), Box<dyn Error>> {
     loop {
         match util::check_network_interfaces() {
             Ok(_) => break,
             Err(e) => {
-                let _ = sender.try_send(PeerEvent::Error(e.to_string()))?;
+                sender.try_send(PeerEvent::Error(e.to_string()))?;
                 sleep(Duration::from_secs(5));
                 continue;
             }
@@ -198,6 +268,13 @@ pub fn run_server(
     }
 
     let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(execute_swarm(sender, file_receiver, command_receiver))?;
+    rt.block_on(execute_swarm(
+        sender,
+        file_receiver,
+        command_receiver,
+        queue_receiver,
+        discovery_receiver,
+        bandwidth,
+    ))?;
     Ok(())
 }
@@ -0,0 +1,44 @@
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+/// Short code the users compare on both devices before pairing them.
+/// PeerIds embed the public keys that sign the noise handshake, so a device
+/// in the middle would cause a different code on each side.
+pub fn pairing_code(local: &PeerId, remote: &PeerId) -> String {
+    let (local, remote) = (local.to_bytes(), remote.to_bytes());
+    // The same order on both sides
+    let (first, second) = if local <= remote {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&first);
+    hasher.update(&second);
+    let digest = hasher.finalize();
+
+    let number = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", number / 1000, number % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use crate::p2p::pairing::pairing_code;
+
+    #[test]
+    fn test_pairing_code_is_the_same_on_both_sides() {
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let code = pairing_code(&first, &second);
+
+        assert_eq!(code, pairing_code(&second, &first));
+        assert_eq!(code.len(), 7);
+        assert!(code.chars().enumerate().all(|(i, c)| if i == 3 {
+            c == ' '
+        } else {
+            c.is_ascii_digit()
+        }));
+    }
+}
@@ -1,8 +1,14 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
 
 use libp2p::{Multiaddr, PeerId};
 use prost::Enumeration;
+use serde::{Deserialize, Serialize};
 
+use crate::p2p::transfer::file::Manifest;
+use crate::p2p::transfer::{Compression, HashAlgorithm, ProtocolVersion};
 use crate::p2p::Payload;
 
 #[derive(Debug, Clone)]
@@ -16,31 +22,188 @@ pub enum TransferType {
     File = 0,
     Text = 1,
     Dir = 2,
+    Batch = 4,
 }
 
+/// Identifies a single transfer, incoming or outgoing, within this process.
+pub type TransferId = u64;
+
 #[derive(Debug, Clone)]
 pub enum PeerEvent {
     PeersUpdated(CurrentPeers),
     WaitingForAnswer,
     TransferRejected,
-    TransferProgress((usize, usize, Direction, Option<f64>)),
+    TransferProgress((TransferId, usize, usize, Direction, Option<f64>)),
+    /// Which file of a directory is being transferred
+    DirectoryProgress(DirectoryProgress),
     TransferCompleted,
-    FileCorrect(String, Payload),
-    FileIncorrect,
-    FileIncoming(String, String, usize, TransferType),
-    TransferFailed { file_name: String, reason: String },
+    FileCorrect(TransferId, String, Payload),
+    /// Received data doesn't match the sender's hash. Names the affected files,
+    /// or just the payload when they are not known.
+    FileIncorrect(Vec<String>),
+    FileIncoming(IncomingFile),
+    /// Incoming payload was skipped, because its name is taken
+    FileSkipped(String),
+    TransferFailed {
+        id: TransferId,
+        file_name: String,
+        reason: String,
+    },
+    /// Receiver refused the payload, because it could write outside of the target directory
+    PayloadRefused {
+        id: TransferId,
+        file_name: String,
+        reason: String,
+    },
+    /// Transfer was stopped on request of the user, on this device or the other one
+    TransferCancelled {
+        id: TransferId,
+        file_name: String,
+        reason: String,
+        by_remote: bool,
+    },
+    /// Outgoing files of a peer changed, running or waiting
+    QueueChanged(PeerQueue),
     Error(String),
 }
 
+/// Payload offered by the other peer, waiting for the user to answer.
+#[derive(Debug, Clone)]
+pub struct IncomingFile {
+    pub id: TransferId,
+    /// Sender of the payload
+    pub peer: PeerId,
+    /// Whether the sender is a paired device
+    pub trust: Trust,
+    pub name: String,
+    pub size: usize,
+    pub transfer_type: TransferType,
+    /// The name is taken and the user is asked how to resolve it
+    pub name_taken: bool,
+    /// Empty when the sender doesn't know it
+    pub mime_type: String,
+    /// Overview of the contents of a directory
+    pub manifest: Option<Manifest>,
+}
+
+/// Progress of a directory transfer, counted in files rather than bytes of the archive.
+#[derive(Debug, Clone)]
+pub struct DirectoryProgress {
+    pub id: TransferId,
+    pub direction: Direction,
+    /// Path of the current entry, starting with the name of the directory
+    pub path: String,
+    pub files_done: u64,
+    /// Zero when the sender didn't tell it
+    pub files_total: u64,
+    /// Size of the files done
+    pub bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Outgoing file, as listed in the queue.
+#[derive(Debug, Clone)]
+pub struct QueuedFile {
+    pub id: TransferId,
+    pub name: String,
+    pub transfer_type: TransferType,
+}
+
+/// Outgoing files of a single peer.
+#[derive(Debug, Clone)]
+pub struct PeerQueue {
+    pub peer: PeerId,
+    pub active: Vec<QueuedFile>,
+    /// In the order they are going to be sent
+    pub waiting: Vec<QueuedFile>,
+}
+
 pub type CurrentPeers = Vec<Peer>;
 
-#[derive(Debug, Eq, Hash, Clone)]
+#[derive(Debug, Eq, Clone)]
 pub struct Peer {
     pub name: String,
     pub address: Multiaddr,
     pub peer_id: PeerId,
     pub hostname: String,
     pub os: OperatingSystem,
+    /// Chosen by its user, empty when not set
+    pub display_name: String,
+    /// Known peers stay on the list while they are disconnected
+    pub online: bool,
+    /// None until discovery, or when the peer is too old to advertise them
+    pub capabilities: Option<Capabilities>,
+    pub trust: Trust,
+    /// Shown on both devices while pairing, see `pairing_code`
+    pub pairing_code: String,
+}
+
+/// Whether the user paired with the peer, comparing the codes on both devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Trust {
+    #[default]
+    Unpaired,
+    Paired,
+    /// Goes by the name of a paired device, but with another key
+    KeyChanged,
+}
+
+/// Trust of the peers as the discovery last saw it. Shared with the transfers,
+/// so that incoming payloads tell whether they come from a paired device.
+#[derive(Clone, Debug, Default)]
+pub struct PeerTrust(Arc<Mutex<HashMap<PeerId, Trust>>>);
+
+impl PeerTrust {
+    /// Peers the discovery doesn't know are not paired.
+    pub fn get(&self, peer: &PeerId) -> Trust {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, trust: HashMap<PeerId, Trust>) {
+        *self.0.lock().unwrap() = trust;
+    }
+}
+
+/// What a host supports, advertised during discovery. Transfers negotiate
+/// the hash and compression on their own, so these tell in advance what's
+/// going to be possible.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    /// Dragit release, like "1.0.0"
+    pub version: String,
+    /// Versions known to this build, others are left out
+    pub protocols: Vec<ProtocolVersion>,
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    pub compressions: Vec<Compression>,
+    /// Largest payload accepted in bytes, None when there's no limit
+    pub max_size: Option<u64>,
+}
+
+impl Capabilities {
+    /// Capabilities of this build.
+    pub fn local(max_size: Option<u64>) -> Self {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocols: ProtocolVersion::SUPPORTED.to_vec(),
+            hash_algorithms: HashAlgorithm::OFFERED.to_vec(),
+            compressions: vec![Compression::Zstd],
+            max_size,
+        }
+    }
+
+    /// The peer doesn't speak the newest protocol, so some features are missing.
+    pub fn is_outdated(&self) -> bool {
+        !self.protocols.contains(&ProtocolVersion::LATEST)
+    }
+
+    pub fn accepts(&self, size: u64) -> bool {
+        self.max_size.is_none_or(|max_size| size <= max_size)
+    }
 }
 
 impl PartialEq for Peer {
@@ -49,7 +212,51 @@ impl PartialEq for Peer {
     }
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration)]
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.peer_id.hash(state);
+    }
+}
+
+/// Characters of the PeerId that tell apart peers with the same name
+const PEER_ID_SUFFIX: usize = 6;
+
+impl Peer {
+    /// Display name, or the hostname when the user didn't choose one.
+    pub fn shown_name(&self) -> &str {
+        if self.display_name.is_empty() {
+            &self.hostname
+        } else {
+            &self.display_name
+        }
+    }
+
+    /// Older builds don't advertise their capabilities at all.
+    pub fn is_outdated(&self) -> bool {
+        self.online
+            && self
+                .capabilities
+                .as_ref()
+                .is_none_or(|capabilities| capabilities.is_outdated())
+    }
+
+    /// Shown name with the end of the PeerId, if another of `peers` has the same one.
+    pub fn distinct_name(&self, peers: &[Peer]) -> String {
+        let name = self.shown_name();
+        let taken = peers
+            .iter()
+            .any(|other| other.peer_id != self.peer_id && other.shown_name() == name);
+        if !taken {
+            return name.to_string();
+        }
+        let peer_id = self.peer_id.to_base58();
+        let suffix = &peer_id[peer_id.len().saturating_sub(PEER_ID_SUFFIX)..];
+        format!("{} ({})", name, suffix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OperatingSystem {
     Linux = 0,
     Windows = 1,
@@ -64,6 +271,61 @@ impl fmt::Display for TransferType {
             Self::File => write!(f, "TransferType: File"),
             Self::Text => write!(f, "TransferType: Text"),
             Self::Dir => write!(f, "TransferType: Directory"),
+            Self::Batch => write!(f, "TransferType: Batch"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{Multiaddr, PeerId};
+
+    use crate::p2p::peer::{Capabilities, OperatingSystem, Peer, Trust};
+    use crate::p2p::transfer::ProtocolVersion;
+
+    fn peer(hostname: &str, display_name: &str) -> Peer {
+        let peer_id = PeerId::random();
+        Peer {
+            name: peer_id.to_base58(),
+            address: Multiaddr::empty(),
+            peer_id,
+            hostname: hostname.to_string(),
+            os: OperatingSystem::Linux,
+            display_name: display_name.to_string(),
+            online: true,
+            capabilities: Some(Capabilities::local(None)),
+            trust: Trust::Unpaired,
+            pairing_code: String::new(),
         }
     }
+
+    #[test]
+    fn test_peers_with_the_same_name_are_told_apart() {
+        let peers = vec![
+            peer("ubuntu", ""),
+            peer("ubuntu", ""),
+            peer("ubuntu", "Kitchen laptop"),
+        ];
+
+        let first = peers[0].distinct_name(&peers);
+        let second = peers[1].distinct_name(&peers);
+        assert!(first.starts_with("ubuntu ("), "{}", first);
+        assert_ne!(first, second);
+        assert_eq!(peers[2].distinct_name(&peers), "Kitchen laptop");
+    }
+
+    #[test]
+    fn test_peers_without_the_latest_protocol_are_outdated() {
+        let mut peer = peer("ubuntu", "");
+        assert!(!peer.is_outdated());
+
+        peer.capabilities = None;
+        assert!(peer.is_outdated());
+
+        let mut capabilities = Capabilities::local(Some(100));
+        capabilities.protocols = vec![ProtocolVersion::V1_2];
+        assert!(capabilities.is_outdated());
+        assert!(capabilities.accepts(100));
+        assert!(!capabilities.accepts(101));
+    }
 }
@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use async_channel::{Receiver, Sender};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
 use tokio::sync::Mutex;
 
 use libp2p::core::Multiaddr;
@@ -14,24 +17,59 @@ use libp2p::swarm::{
 };
 use libp2p::PeerId;
 
-use super::protocol::{ProtocolEvent, TransferOut, TransferPayload};
-use crate::p2p::commands::TransferCommand;
-use crate::p2p::peer::PeerEvent;
+use super::connections::PeerConnections;
+use super::limiter::Bandwidth;
+use super::partial::PartialFiles;
+use super::protocol::{OutboundError, ProtocolEvent, TransferOut, TransferPayload};
+use super::queue::OutgoingQueue;
+use super::ranges::{Parallelism, RangeRegistry};
+use super::version::ProtocolVersion;
+use crate::p2p::commands::{CommandRouter, QueueCommand, TransferCommand};
+use crate::p2p::peer::{Capabilities, PeerEvent, PeerTrust, TransferId};
 use crate::p2p::transfer::file::{FileToSend, Payload};
+use crate::user_data::{ConflictPolicy, PreservedAttributes, Timeouts};
 
 use crate::p2p::discovery::handler::KeepAliveHandler;
 
 type Handler = KeepAliveHandler<TransferPayload, TransferOut, ProtocolEvent>;
 
+/// Transfers run within the upgrade of their substream, so it never times out,
+/// whatever the size of the file and the rate limit. Peers that stop responding
+/// are caught by the stall detection and the answer timeout instead.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
 pub struct TransferBehaviour {
-    pub events: Vec<ToSwarm<TransferPayload, THandlerInEvent<Self>>>,
-    payloads: Vec<FileToSend>,
+    pub events: VecDeque<ToSwarm<TransferPayload, THandlerInEvent<Self>>>,
+    /// Outgoing files, running and waiting for their turn
+    queue: OutgoingQueue,
+    /// Outgoing transfers that run at once for every peer
+    pub transfers_per_peer: usize,
+    connections: PeerConnections,
     pub sender: Sender<PeerEvent>,
-    receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+    commands: CommandRouter,
     pub target_path: Option<String>,
-    /// Tracks peers that currently have an outbound transfer in flight,
-    /// so that a sudden ConnectionClosed can be reported to the UI.
-    active_transfers: HashMap<PeerId, FileToSend>,
+    pub conflict_policy: ConflictPolicy,
+    pub preserved: PreservedAttributes,
+    /// Shared by all transfers, so that the limits can be changed while they run
+    pub bandwidth: Bandwidth,
+    pub timeouts: Timeouts,
+    pub parallelism: Parallelism,
+    /// Larger incoming payloads are refused, None accepts any
+    pub max_size: Option<u64>,
+    /// Limits the peers advertised in discovery, for the outgoing transfers
+    receiver_max_sizes: HashMap<PeerId, u64>,
+    /// Incoming transfers that wait for the ranges of their files
+    ranges: RangeRegistry,
+    /// Partial files of the incoming transfers
+    partials: PartialFiles,
+    /// Tells the incoming transfers whether their peer is paired
+    pub peer_trust: PeerTrust,
+    /// Ranges of outgoing files, each of them needs a substream of its own
+    range_requests: UnboundedSender<TransferOut>,
+    range_receiver: UnboundedReceiver<TransferOut>,
+    /// Outgoing transfers report here when they end, successfully or not
+    finished: UnboundedSender<TransferId>,
+    finished_receiver: UnboundedReceiver<TransferId>,
 }
 
 impl TransferBehaviour {
@@ -40,18 +78,184 @@ impl TransferBehaviour {
         receiver: Arc<Mutex<Receiver<TransferCommand>>>,
         target_path: Option<String>,
     ) -> Self {
+        let (range_requests, range_receiver) = mpsc::unbounded();
+        let (finished, finished_receiver) = mpsc::unbounded();
         TransferBehaviour {
-            events: vec![],
-            payloads: vec![],
+            events: VecDeque::new(),
+            queue: OutgoingQueue::default(),
+            transfers_per_peer: 1,
+            connections: PeerConnections::default(),
             sender,
-            receiver,
+            commands: CommandRouter::new(receiver),
             target_path,
-            active_transfers: HashMap::new(),
+            conflict_policy: ConflictPolicy::default(),
+            preserved: PreservedAttributes::default(),
+            bandwidth: Bandwidth::default(),
+            timeouts: Timeouts::default(),
+            parallelism: Parallelism::default(),
+            max_size: None,
+            receiver_max_sizes: HashMap::new(),
+            ranges: RangeRegistry::default(),
+            partials: PartialFiles::default(),
+            peer_trust: PeerTrust::default(),
+            range_requests,
+            range_receiver,
+            finished,
+            finished_receiver,
         }
     }
 
+    /// Queue `file` after the other files for the same peer.
     pub fn push_file(&mut self, file: FileToSend) {
-        self.payloads.push(file)
+        let peer = file.peer;
+        self.queue.push(file);
+        self.notify_queue(peer);
+    }
+
+    pub fn queue_command(&mut self, command: QueueCommand) {
+        let peer = match command {
+            QueueCommand::Remove(id) => self.queue.remove(id).map(|file| file.peer),
+            QueueCommand::MoveTo(id, position) => self.queue.move_to(id, position),
+        };
+        match peer {
+            Some(peer) => self.notify_queue(peer),
+            None => warn!("No waiting transfer for {:?}", command),
+        }
+    }
+
+    fn notify_queue(&self, peer: PeerId) {
+        let event = PeerEvent::QueueChanged(self.queue.state(peer));
+        if let Err(e) = self.sender.try_send(event) {
+            error!("Could not notify about the queue: {:?}", e);
+        }
+    }
+
+    /// The hosts exchanged their details over the connection, so it's preferred for transfers.
+    pub fn connection_discovered(&mut self, peer: PeerId, connection: ConnectionId) {
+        self.connections.discovered(peer, connection);
+    }
+
+    /// Remembers what the peer accepts. Older peers don't tell.
+    pub fn peer_capabilities(&mut self, peer: PeerId, capabilities: Option<&Capabilities>) {
+        match capabilities.and_then(|capabilities| capabilities.max_size) {
+            Some(max_size) => self.receiver_max_sizes.insert(peer, max_size),
+            None => self.receiver_max_sizes.remove(&peer),
+        };
+    }
+
+    /// Hands the transfer to the connection chosen for it.
+    /// Fails it when the peer is not connected, the swarm would drop it.
+    fn send(&mut self, transfer: TransferOut) {
+        let peer_id = transfer.file.peer;
+        let Some(connection) = self.connections.choose(peer_id, transfer.file.id) else {
+            warn!(
+                "Cannot send {}, {} is not connected",
+                transfer.file.name, peer_id
+            );
+            self.fail(transfer, "Receiver is not connected".to_string());
+            return;
+        };
+        self.events.push_back(ToSwarm::NotifyHandler {
+            handler: NotifyHandler::One(connection),
+            peer_id,
+            event: transfer,
+        });
+    }
+
+    /// Reports the transfer as failed and lets the next file of its peer go.
+    /// A range fails the transfer of the whole file instead.
+    fn fail(&mut self, transfer: TransferOut, reason: String) {
+        let file = transfer.file;
+        match transfer.range {
+            Some(range) => {
+                let error = io::Error::new(io::ErrorKind::NotConnected, reason);
+                let _ = range.done.try_send(Err(error));
+            }
+            None => {
+                let _ = self.sender.try_send(PeerEvent::TransferFailed {
+                    id: file.id,
+                    file_name: file.name.to_string(),
+                    reason,
+                });
+                self.connections.finished(file.id);
+                if let Some(file) = self.queue.finish(file.id) {
+                    self.notify_queue(file.peer);
+                }
+            }
+        }
+    }
+
+    /// Tries a transfer that didn't start again on another connection,
+    /// fails it when none is left or when it failed on the way.
+    fn outbound_failed(
+        &mut self,
+        transfer: TransferOut,
+        error: OutboundError,
+        connection: ConnectionId,
+    ) {
+        let file = &transfer.file;
+        warn!(
+            "Transfer of {} to {} failed: {:?}",
+            file.name, file.peer, error
+        );
+        if error.unopened() {
+            self.connections.failed(file.id, connection);
+            if self.connections.choose(file.peer, file.id).is_some() {
+                warn!(
+                    "Retrying {} on another connection to {}",
+                    file.name, file.peer
+                );
+                self.send(transfer);
+                return;
+            }
+        }
+
+        // Ranges that started report their errors to the transfer of the file already
+        if transfer.range.is_none() || error.unopened() {
+            self.fail(transfer, error.reason());
+        }
+    }
+
+    /// Outgoing transfer of `file`, which listens to the commands of the user.
+    pub fn transfer_out(&self, file: FileToSend) -> TransferOut {
+        let receiver_max_size = self.receiver_max_sizes.get(&file.peer).copied();
+        TransferOut {
+            file,
+            sender_queue: self.sender.clone(),
+            commands: self.commands.clone(),
+            bandwidth: self.bandwidth.clone(),
+            timeouts: self.timeouts,
+            parallelism: self.parallelism,
+            range_requests: self.range_requests.clone(),
+            range: None,
+            finished: self.finished.clone(),
+            receiver_max_size,
+        }
+    }
+
+    /// Incoming transfer from `peer`, filled in once the sender opens a substream.
+    pub fn transfer_in(&self, peer: PeerId) -> TransferPayload {
+        TransferPayload {
+            id: 0,
+            peer,
+            name: "default".to_string(),
+            hash: "".to_string(),
+            payload: Payload::File(".".to_string()),
+            size_bytes: 0,
+            sender_queue: self.sender.clone(),
+            commands: self.commands.clone(),
+            target_path: self.target_path.clone(),
+            conflict_policy: self.conflict_policy,
+            preserved: self.preserved,
+            bandwidth: self.bandwidth.clone(),
+            timeouts: self.timeouts,
+            max_size: self.max_size,
+            ranges: self.ranges.clone(),
+            partials: self.partials.clone(),
+            peer_trust: self.peer_trust.clone(),
+            part_of: None,
+            version: ProtocolVersion::default(),
+        }
     }
 }
 
@@ -62,43 +266,25 @@ impl NetworkBehaviour for TransferBehaviour {
     fn handle_established_inbound_connection(
         &mut self,
         _connection_id: ConnectionId,
-        _peer_id: PeerId,
+        peer_id: PeerId,
         _local_addr: &Multiaddr,
         _remote_addr: &Multiaddr,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        let tp = TransferPayload {
-            name: "default".to_string(),
-            hash: "".to_string(),
-            payload: Payload::File(".".to_string()),
-            size_bytes: 0,
-            sender_queue: self.sender.clone(),
-            receiver: Arc::clone(&self.receiver),
-            target_path: self.target_path.clone(),
-        };
-        let proto = libp2p::swarm::SubstreamProtocol::new(tp, ())
-            .with_timeout(Duration::from_secs(30 * 365 * 24 * 60 * 60));
+        let proto = libp2p::swarm::SubstreamProtocol::new(self.transfer_in(peer_id), ())
+            .with_timeout(UPGRADE_TIMEOUT);
         Ok(Handler::new(proto))
     }
 
     fn handle_established_outbound_connection(
         &mut self,
         _connection_id: ConnectionId,
-        _peer_id: PeerId,
+        peer_id: PeerId,
         _addr: &Multiaddr,
         _role_override: libp2p::core::Endpoint,
         _port_use: libp2p::swarm::derive_prelude::PortUse,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        let tp = TransferPayload {
-            name: "default".to_string(),
-            hash: "".to_string(),
-            payload: Payload::File(".".to_string()),
-            size_bytes: 0,
-            sender_queue: self.sender.clone(),
-            receiver: Arc::clone(&self.receiver),
-            target_path: self.target_path.clone(),
-        };
-        let proto = libp2p::swarm::SubstreamProtocol::new(tp, ())
-            .with_timeout(Duration::from_secs(30 * 365 * 24 * 60 * 60));
+        let proto = libp2p::swarm::SubstreamProtocol::new(self.transfer_in(peer_id), ())
+            .with_timeout(UPGRADE_TIMEOUT);
         Ok(Handler::new(proto))
     }
 
@@ -108,26 +294,48 @@ impl NetworkBehaviour for TransferBehaviour {
                 debug!(
                     "Connection established: {:?}, {:?}, c: {:?}",
                     info.peer_id, info.endpoint, info.connection_id
-                )
+                );
+                self.connections
+                    .established(info.peer_id, info.connection_id);
             }
             FromSwarm::ConnectionClosed(ConnectionClosed {
                 peer_id,
+                connection_id,
                 remaining_established,
                 ..
             }) => {
+                self.connections.closed(peer_id, connection_id);
                 // Only act when this was the very last connection to that peer,
                 // so we don't fire prematurely when there are redundant connections.
                 if remaining_established == 0 {
-                    if let Some(file) = self.active_transfers.remove(&peer_id) {
+                    let (active, waiting) = self.queue.take_peer(&peer_id);
+                    for file in active {
                         warn!(
                             "Connection to peer {} closed while transfer of '{}' was in flight",
                             peer_id, file.name
                         );
+                        let reason = if file.is_resumable() {
+                            "Receiver disconnected during transfer. Send the file again to resume."
+                        } else {
+                            "Receiver disconnected during transfer"
+                        };
                         let _ = self.sender.try_send(PeerEvent::TransferFailed {
+                            id: file.id,
                             file_name: file.name,
-                            reason: "Receiver disconnected during transfer".to_string(),
+                            reason: reason.to_string(),
                         });
                     }
+                    let waited = !waiting.is_empty();
+                    for file in waiting {
+                        let _ = self.sender.try_send(PeerEvent::TransferFailed {
+                            id: file.id,
+                            file_name: file.name,
+                            reason: "Receiver disconnected before the transfer started".to_string(),
+                        });
+                    }
+                    if waited {
+                        self.notify_queue(peer_id);
+                    }
                 }
             }
             FromSwarm::DialFailure(info) => {
@@ -140,43 +348,44 @@ impl NetworkBehaviour for TransferBehaviour {
     fn on_connection_handler_event(
         &mut self,
         peer: PeerId,
-        _connection: ConnectionId,
+        connection: ConnectionId,
         event: THandlerOutEvent<Self>,
     ) {
         match event {
             ProtocolEvent::Received(data) => {
                 info!("Inject event: {}", data);
-                self.events.push(ToSwarm::GenerateEvent(data));
+                self.events.push_back(ToSwarm::GenerateEvent(data));
             }
-            ProtocolEvent::Sent => {
-                // Transfer completed successfully — remove from the active set so
-                // a subsequent ConnectionClosed doesn't trigger a false error.
-                self.active_transfers.remove(&peer);
+            ProtocolEvent::Sent(version) => info!("Sent to {} over {}", peer, version),
+            ProtocolEvent::RangeReceived(id) => debug!("Range of {} received from {}", id, peer),
+            ProtocolEvent::RangeSent(id) => debug!("Range of {} sent to {}", id, peer),
+            ProtocolEvent::OutboundFailed(transfer, error) => {
+                self.outbound_failed(transfer, error, connection)
             }
         };
     }
 
-    fn poll(&mut self, _: &mut Context) -> Poll<ToSwarm<TransferPayload, THandlerInEvent<Self>>> {
-        if let Some(file) = self.payloads.pop() {
-            let peer_id = file.peer.clone();
-            // Register as active before handing off to the handler, so that
-            // any ConnectionClosed arriving from this point onwards is caught.
-            self.active_transfers.insert(peer_id, file.clone());
-            let transfer = TransferOut {
-                file,
-                sender_queue: self.sender.clone(),
-            };
-
-            let event = ToSwarm::NotifyHandler {
-                // TODO: Notify particular handler, not Any
-                handler: NotifyHandler::Any,
-                peer_id,
-                event: transfer,
-            };
-            self.events.push(event);
+    fn poll(&mut self, cx: &mut Context) -> Poll<ToSwarm<TransferPayload, THandlerInEvent<Self>>> {
+        while let Poll::Ready(Some(transfer)) = self.range_receiver.poll_next_unpin(cx) {
+            self.send(transfer);
+        }
+
+        while let Poll::Ready(Some(id)) = self.finished_receiver.poll_next_unpin(cx) {
+            self.connections.finished(id);
+            if let Some(file) = self.queue.finish(id) {
+                self.notify_queue(file.peer);
+            }
+        }
+
+        // Files count as running before they are handed off to the handler,
+        // so that any ConnectionClosed arriving from this point onwards is caught.
+        while let Some(file) = self.queue.next(self.transfers_per_peer) {
+            self.notify_queue(file.peer);
+            let transfer = self.transfer_out(file);
+            self.send(transfer);
         }
 
-        match self.events.pop() {
+        match self.events.pop_front() {
             Some(event) => Poll::Ready(event),
             None => Poll::Pending,
         }
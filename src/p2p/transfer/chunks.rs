@@ -0,0 +1,300 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::{io, pin::Pin};
+
+use futures::future::poll_fn;
+use futures::prelude::*;
+use futures::ready;
+
+use crate::p2p::transfer::metadata::MAX_PACKET_SIZE;
+use crate::p2p::util::find_cause;
+
+/// Largest chunk of data written to the socket at once.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Set in the length of a chunk that carries the reason of a cancellation
+/// instead of data.
+const CANCEL_FLAG: u32 = 1 << 31;
+
+/// Resolves when the transfer has to stop, because of the user or the other peer.
+pub type CancelSignal<'a> = Pin<Box<dyn Future<Output = Cancelled> + Send + 'a>>;
+
+/// Transfer stopped on request before all data was sent.
+#[derive(Debug, Clone)]
+pub struct Cancelled {
+    pub by_remote: bool,
+    /// The receiver refused unsafe data, rather than the user cancelling
+    pub refused: bool,
+    pub reason: String,
+}
+
+impl Cancelled {
+    pub fn local(reason: &str) -> Self {
+        Cancelled {
+            by_remote: false,
+            refused: false,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn remote(reason: String) -> Self {
+        Cancelled {
+            by_remote: true,
+            refused: false,
+            reason,
+        }
+    }
+
+    /// Looks for a cancellation among the causes of `error`.
+    pub fn find(error: &io::Error) -> Option<&Cancelled> {
+        find_cause(error)
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer cancelled: {}", self.reason)
+    }
+}
+
+impl Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(cancelled: Cancelled) -> Self {
+        io::Error::new(io::ErrorKind::ConnectionAborted, cancelled)
+    }
+}
+
+/// Splits the written data into chunks prefixed with their length,
+/// so that the receiver knows where the data ends, or that it was cancelled.
+/// Closing writes an empty chunk and flushes, but leaves the inner writer open.
+pub struct ChunkWriter<W> {
+    inner: W,
+    chunk: Vec<u8>,
+    written: usize,
+    ended: bool,
+}
+
+impl<W: AsyncWrite + Unpin> ChunkWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkWriter {
+            inner,
+            chunk: Vec::with_capacity(MAX_CHUNK_SIZE + 4),
+            written: 0,
+            ended: false,
+        }
+    }
+
+    /// Tells the reader that no more data follows, because of `cancelled`.
+    /// Returns the cancellation as an error to pass on.
+    pub async fn cancel(&mut self, cancelled: Cancelled) -> io::Error {
+        if !cancelled.by_remote && !self.ended {
+            self.ended = true;
+            let written = async {
+                poll_fn(|cx| self.poll_write_chunk(cx)).await?;
+                let reason = cancelled.reason.as_bytes();
+                self.chunk
+                    .extend_from_slice(&(reason.len() as u32 | CANCEL_FLAG).to_be_bytes());
+                self.chunk.extend_from_slice(reason);
+                poll_fn(|cx| self.poll_write_chunk(cx)).await?;
+                self.inner.flush().await
+            };
+            if let Err(e) = written.await {
+                warn!("Could not notify about the cancellation: {:?}", e);
+            }
+        }
+        cancelled.into()
+    }
+
+    fn start_chunk(&mut self, data: &[u8]) {
+        self.chunk
+            .extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.chunk.extend_from_slice(data);
+    }
+
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.chunk.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.chunk[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.chunk.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ChunkWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        if buf.is_empty() {
+            // An empty chunk would end the stream
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_CHUNK_SIZE);
+        this.start_chunk(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_chunk(cx))?;
+        if !this.ended {
+            this.ended = true;
+            this.start_chunk(&[]);
+            ready!(this.poll_write_chunk(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+}
+
+/// Reads the chunks written by [`ChunkWriter`] until the empty one,
+/// never reading past it. A cancellation chunk fails the read with [`Cancelled`].
+pub struct ChunkReader<R> {
+    inner: R,
+    header: [u8; 4],
+    header_read: usize,
+    remaining: usize,
+    ended: bool,
+    reason: Option<Vec<u8>>,
+}
+
+impl<R: AsyncRead + Unpin> ChunkReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkReader {
+            inner,
+            header: [0; 4],
+            header_read: 0,
+            remaining: 0,
+            ended: false,
+            reason: None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn poll_reason(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut buf = [0u8; 256];
+        while self.remaining > 0 {
+            let len = buf.len().min(self.remaining);
+            let n = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.remaining -= n;
+            if let Some(reason) = self.reason.as_mut() {
+                reason.extend_from_slice(&buf[..n]);
+            }
+        }
+        let reason = self.reason.take().unwrap_or_default();
+        self.ended = true;
+        let cancelled = Cancelled::remote(String::from_utf8_lossy(&reason).to_string());
+        Poll::Ready(Err(cancelled.into()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChunkReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.reason.is_some() {
+            return this.poll_reason(cx);
+        }
+        while this.remaining == 0 {
+            if this.ended {
+                return Poll::Ready(Ok(0));
+            }
+            while this.header_read < this.header.len() {
+                let n =
+                    ready!(Pin::new(&mut this.inner)
+                        .poll_read(cx, &mut this.header[this.header_read..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.header_read += n;
+            }
+            this.header_read = 0;
+            let header = u32::from_be_bytes(this.header);
+            if header & CANCEL_FLAG != 0 {
+                this.remaining = (header & !CANCEL_FLAG) as usize;
+                // The length comes from the peer, so it's not trusted with the allocation
+                if this.remaining > MAX_PACKET_SIZE {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Cancel reason too large: {}", this.remaining),
+                    )));
+                }
+                this.reason = Some(Vec::with_capacity(this.remaining));
+                return this.poll_reason(cx);
+            }
+            this.remaining = header as usize;
+            this.ended = this.remaining == 0;
+        }
+
+        let len = buf.len().min(this.remaining);
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]))?;
+        if n == 0 && len > 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        this.remaining -= n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use futures::prelude::*;
+
+    use super::{Cancelled, ChunkReader, ChunkWriter, CANCEL_FLAG};
+
+    #[tokio::test]
+    async fn test_cancelled_stream() {
+        let mut socket = Cursor::new(vec![]);
+        let mut writer = ChunkWriter::new(&mut socket);
+        writer.write_all(b"Some data").await.unwrap();
+        let error = writer.cancel(Cancelled::local("Changed my mind")).await;
+        assert!(Cancelled::find(&error).is_some());
+
+        socket.set_position(0);
+        let mut reader = ChunkReader::new(&mut socket);
+        let mut received = vec![];
+        let error = reader.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(received, b"Some data");
+
+        let cancelled = Cancelled::find(&error).unwrap();
+        assert!(cancelled.by_remote);
+        assert_eq!(cancelled.reason, "Changed my mind");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_cancel_reason() {
+        let header = (1u32 << 30) | CANCEL_FLAG;
+        let mut socket = Cursor::new(header.to_be_bytes().to_vec());
+        let mut reader = ChunkReader::new(&mut socket);
+        let mut received = vec![];
+        let error = reader.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(Cancelled::find(&error).is_none());
+    }
+}
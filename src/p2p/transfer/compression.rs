@@ -0,0 +1,206 @@
+use std::path::Path;
+use std::task::{Context, Poll};
+use std::{io, pin::Pin};
+
+use async_compression::futures::bufread::ZstdDecoder;
+use async_compression::futures::write::ZstdEncoder;
+use futures::io::{self as futio, BufReader};
+use futures::prelude::*;
+use prost::Enumeration;
+
+use crate::p2p::transfer::chunks::{CancelSignal, ChunkReader, ChunkWriter};
+use crate::p2p::transfer::file::{FileToSend, Payload};
+
+/// Formats that are compressed already, so compressing them again only costs time.
+const COMPRESSED_EXTENSIONS: [&str; 30] = [
+    "7z", "apk", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "pptx", "rar", "tgz", "webm",
+    "webp", "xlsx", "xz", "zip",
+];
+
+/// Compression of the transferred data. Peers that don't name
+/// the compression in their messages send the data as it is.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+}
+
+impl Compression {
+    /// Compressions offered to the receiver of `file`. Nothing is offered
+    /// for files that are compressed already.
+    pub fn offered_for(file: &FileToSend) -> Vec<Compression> {
+        match &file.payload {
+            Payload::File(path) if is_compressed(path) => vec![],
+            _ => vec![Compression::Zstd],
+        }
+    }
+
+    pub fn negotiate(offered: &[Compression]) -> Compression {
+        if offered.contains(&Compression::Zstd) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+fn is_compressed(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .map(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+        .unwrap_or(false)
+}
+
+/// Copy `reader` into `writer` in chunks, compressing the data if agreed.
+/// Stops early when `cancel` resolves, in which case the reader
+/// is told about it. The writer is flushed, but not closed.
+/// Returns the number of bytes read.
+pub async fn copy_compressed(
+    reader: impl AsyncRead + Unpin,
+    writer: &mut (impl AsyncWrite + Unpin),
+    compression: Compression,
+    cancel: &mut CancelSignal<'_>,
+) -> io::Result<u64> {
+    let chunks = ChunkWriter::new(futio::BufWriter::new(writer));
+    match compression {
+        Compression::None => {
+            let mut chunks = chunks;
+            let copied = tokio::select! {
+                biased;
+                cancelled = cancel => return Err(chunks.cancel(cancelled).await),
+                copied = futio::copy(reader, &mut chunks) => copied?,
+            };
+            chunks.close().await?;
+            Ok(copied)
+        }
+        Compression::Zstd => {
+            let mut encoder = ZstdEncoder::new(chunks);
+            let copied = tokio::select! {
+                biased;
+                cancelled = cancel => return Err(encoder.get_mut().cancel(cancelled).await),
+                copied = futio::copy(reader, &mut encoder) => copied?,
+            };
+            // Ends the zstd frame and the chunk stream
+            encoder.close().await?;
+            Ok(copied)
+        }
+    }
+}
+
+/// Copy `reader` into `writer` as it is, the way the legacy protocol sends data.
+/// Its reader can't be told about a cancellation, so the copy just stops.
+/// The writer is flushed, but not closed. Returns the number of bytes read.
+pub async fn copy_raw(
+    reader: impl AsyncRead + Unpin,
+    writer: &mut (impl AsyncWrite + Unpin),
+    cancel: &mut CancelSignal<'_>,
+) -> io::Result<u64> {
+    let mut writer = futio::BufWriter::new(writer);
+    let copied = tokio::select! {
+        biased;
+        cancelled = cancel => return Err(cancelled.into()),
+        copied = futio::copy(reader, &mut writer) => copied?,
+    };
+    writer.flush().await?;
+    Ok(copied)
+}
+
+/// Reads data sent by [`copy_compressed`] and yields it uncompressed,
+/// or data sent by [`copy_raw`], whose length the reader has to know.
+pub enum DataReader<R> {
+    Raw(R),
+    Plain(ChunkReader<R>),
+    Zstd(ZstdDecoder<BufReader<ChunkReader<R>>>),
+}
+
+impl<R: AsyncRead + Unpin> DataReader<R> {
+    pub fn new(inner: R, compression: Compression) -> Self {
+        let chunks = ChunkReader::new(inner);
+        match compression {
+            Compression::None => DataReader::Plain(chunks),
+            Compression::Zstd => DataReader::Zstd(ZstdDecoder::new(BufReader::new(chunks))),
+        }
+    }
+
+    pub fn raw(inner: R) -> Self {
+        DataReader::Raw(inner)
+    }
+
+    /// Skips whatever is left of the data and returns
+    /// the inner reader, positioned right after it.
+    /// Raw data has no end mark, so its reader is returned where it is.
+    pub async fn finish(self) -> io::Result<R> {
+        let mut chunks = match self {
+            DataReader::Raw(inner) => return Ok(inner),
+            DataReader::Plain(chunks) => chunks,
+            DataReader::Zstd(mut decoder) => {
+                futio::copy(&mut decoder, &mut futio::sink()).await?;
+                decoder.into_inner().into_inner()
+            }
+        };
+        futio::copy(&mut chunks, &mut futio::sink()).await?;
+        Ok(chunks.into_inner())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DataReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            DataReader::Raw(inner) => Pin::new(inner).poll_read(cx, buf),
+            DataReader::Plain(chunks) => Pin::new(chunks).poll_read(cx, buf),
+            DataReader::Zstd(decoder) => Pin::new(decoder).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use futures::prelude::*;
+
+    use super::{copy_compressed, is_compressed, Compression, DataReader};
+    use crate::p2p::transfer::chunks::CancelSignal;
+
+    #[tokio::test]
+    async fn test_compressed_stream_stops_at_its_end() {
+        let data = "I'll fly to device!\n".repeat(1000);
+        let mut socket = Cursor::new(vec![]);
+        let mut cancel: CancelSignal = Box::pin(future::pending());
+        let copied = copy_compressed(data.as_bytes(), &mut socket, Compression::Zstd, &mut cancel)
+            .await
+            .unwrap();
+        socket.write_all(b"trailer").await.unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert!(socket.get_ref().len() < data.len());
+
+        socket.set_position(0);
+        let mut reader = DataReader::new(&mut socket, Compression::Zstd);
+        let mut received = String::new();
+        reader.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        let mut rest = vec![];
+        reader
+            .finish()
+            .await
+            .unwrap()
+            .read_to_end(&mut rest)
+            .await
+            .unwrap();
+        assert_eq!(rest, b"trailer");
+    }
+
+    #[test]
+    fn test_is_compressed() {
+        assert!(is_compressed("photos/IMG_001.JPG"));
+        assert!(is_compressed("backup.tar.gz"));
+        assert!(!is_compressed("notes.txt"));
+        assert!(!is_compressed("Makefile"));
+    }
+}
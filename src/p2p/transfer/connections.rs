@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet};
+
+use libp2p::swarm::ConnectionId;
+use libp2p::PeerId;
+
+use crate::p2p::peer::TransferId;
+
+#[derive(Debug)]
+struct Connection {
+    id: ConnectionId,
+    /// Hosts exchanged their details over it
+    discovered: bool,
+}
+
+/// Open connections of every peer, oldest first. After a simultaneous dial
+/// a peer has several of them for a while and some are about to close.
+#[derive(Debug, Default)]
+pub struct PeerConnections {
+    peers: HashMap<PeerId, Vec<Connection>>,
+    /// Connections that could not open a substream for the transfer
+    failed: HashMap<TransferId, HashSet<ConnectionId>>,
+}
+
+impl PeerConnections {
+    pub fn established(&mut self, peer: PeerId, id: ConnectionId) {
+        let connection = Connection {
+            id,
+            discovered: false,
+        };
+        self.peers.entry(peer).or_default().push(connection);
+    }
+
+    pub fn discovered(&mut self, peer: PeerId, id: ConnectionId) {
+        let connections = self.peers.get_mut(&peer).into_iter().flatten();
+        for connection in connections.filter(|connection| connection.id == id) {
+            connection.discovered = true;
+        }
+    }
+
+    pub fn closed(&mut self, peer: PeerId, id: ConnectionId) {
+        if let Some(connections) = self.peers.get_mut(&peer) {
+            connections.retain(|connection| connection.id != id);
+            if connections.is_empty() {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    /// Connection that opens the substreams of the transfer: the newest one that finished
+    /// discovery, otherwise the newest one, skipping those that already failed for it.
+    pub fn choose(&self, peer: PeerId, transfer: TransferId) -> Option<ConnectionId> {
+        let failed = self.failed.get(&transfer);
+        let usable: Vec<&Connection> = self
+            .peers
+            .get(&peer)?
+            .iter()
+            .filter(|connection| !failed.is_some_and(|failed| failed.contains(&connection.id)))
+            .collect();
+        usable
+            .iter()
+            .rev()
+            .find(|connection| connection.discovered)
+            .or_else(|| usable.last())
+            .map(|connection| connection.id)
+    }
+
+    /// Remembers that the transfer failed on the connection, so that it's not chosen again.
+    pub fn failed(&mut self, transfer: TransferId, id: ConnectionId) {
+        self.failed.entry(transfer).or_default().insert(id);
+    }
+
+    pub fn finished(&mut self, transfer: TransferId) {
+        self.failed.remove(&transfer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::swarm::ConnectionId;
+    use libp2p::PeerId;
+
+    use crate::p2p::transfer::connections::PeerConnections;
+
+    #[test]
+    fn test_newest_discovered_connection_is_chosen() {
+        let peer = PeerId::random();
+        let (first, second, third) = (
+            ConnectionId::new_unchecked(1),
+            ConnectionId::new_unchecked(2),
+            ConnectionId::new_unchecked(3),
+        );
+        let mut connections = PeerConnections::default();
+        assert_eq!(connections.choose(peer, 7), None);
+
+        connections.established(peer, first);
+        connections.established(peer, second);
+        assert_eq!(connections.choose(peer, 7), Some(second));
+
+        connections.discovered(peer, first);
+        connections.established(peer, third);
+        assert_eq!(connections.choose(peer, 7), Some(first));
+
+        connections.closed(peer, first);
+        assert_eq!(connections.choose(peer, 7), Some(third));
+    }
+
+    #[test]
+    fn test_failed_connections_are_skipped() {
+        let peer = PeerId::random();
+        let (first, second) = (
+            ConnectionId::new_unchecked(1),
+            ConnectionId::new_unchecked(2),
+        );
+        let mut connections = PeerConnections::default();
+        connections.established(peer, first);
+        connections.established(peer, second);
+        connections.discovered(peer, second);
+
+        connections.failed(7, second);
+        assert_eq!(connections.choose(peer, 7), Some(first));
+        assert_eq!(connections.choose(peer, 8), Some(second));
+        connections.failed(7, first);
+        assert_eq!(connections.choose(peer, 7), None);
+
+        connections.finished(7);
+        assert_eq!(connections.choose(peer, 7), Some(second));
+    }
+}
@@ -1,42 +1,133 @@
-use std::io::{Error, Result as IOResult};
-use std::path::Path;
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use async_channel::Sender;
-use futures::io::BufReader;
-use futures::AsyncRead;
-use tokio::io::{duplex, DuplexStream};
+use futures::{AsyncRead, AsyncReadExt, StreamExt};
+use tokio::fs;
+use tokio::io::{duplex, AsyncWrite, BufReader, DuplexStream};
 use tokio::task::{spawn, JoinHandle};
-use tokio_tar::{Archive, Builder};
+use tokio_tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
 
-use crate::p2p::transfer::reader::ProgressReader;
+use crate::p2p::transfer::file::Manifest;
+use crate::p2p::transfer::hash::{FileDigest, HashAlgorithm};
+use crate::p2p::transfer::reader::{HashingReader, ProgressReader, ProgressSpan};
+use crate::p2p::transfer::validation::{check_parents, ArchiveGuard};
 
-use crate::p2p::peer::Direction;
-use crate::p2p::util::{notify_progress, TSocketAlias};
+use crate::p2p::peer::{Direction, DirectoryProgress, TransferId};
+use crate::p2p::util::{notify, notify_progress};
 use crate::p2p::PeerEvent;
 
 /// Capacity of the duplex pipe between the tar builder task and the network sender.
 const DUPLEX_CHANNEL_SIZE: usize = 1024 * 512; // 512 KiB
 
 /// Read buffer size used when opening individual source files.
-#[allow(dead_code)]
 const FILE_READ_BUFFER: usize = 1024 * 256; // 256 KiB
 
-pub type MaybeTaskHandle = Option<JoinHandle<Result<(), Error>>>;
+/// The archive ends with two zero blocks, but unpacking stops after the first one.
+const END_OF_ARCHIVE_TAIL: usize = 512;
+
+/// Shortest time between two reports of the current entry,
+/// so that folders of small files don't flood the UI.
+const ENTRY_NOTIFY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The task resolves to the digests of the regular files put into the archive.
+pub type MaybeTaskHandle = Option<JoinHandle<Result<Vec<FileDigest>, Error>>>;
+
+/// Reports which file of a directory is being transferred. The bytes
+/// of the archive are reported separately by `ProgressReader`.
+pub struct EntryProgress {
+    id: TransferId,
+    sender_queue: Sender<PeerEvent>,
+    direction: Direction,
+    current: String,
+    files_done: u64,
+    files_total: u64,
+    bytes: u64,
+    total_bytes: u64,
+    last_notify: Option<Instant>,
+}
+
+impl EntryProgress {
+    pub fn new(id: TransferId, sender_queue: Sender<PeerEvent>, direction: Direction) -> Self {
+        EntryProgress {
+            id,
+            sender_queue,
+            direction,
+            current: String::new(),
+            files_done: 0,
+            files_total: 0,
+            bytes: 0,
+            total_bytes: 0,
+            last_notify: None,
+        }
+    }
+
+    /// Takes the totals from the manifest, which counts every entry but directories.
+    pub fn with_totals(mut self, manifest: Option<&Manifest>) -> Self {
+        if let Some(manifest) = manifest {
+            self.files_total = manifest.file_count;
+            self.total_bytes = manifest.total_size;
+        }
+        self
+    }
+
+    /// Called when an entry other than a directory starts.
+    async fn start(&mut self, path: &Path, size: u64) {
+        self.current = path.to_string_lossy().to_string();
+        let now = Instant::now();
+        let due = self
+            .last_notify
+            .is_none_or(|last| now.duration_since(last) >= ENTRY_NOTIFY_INTERVAL);
+        if due {
+            self.last_notify = Some(now);
+            self.notify().await;
+        }
+        self.files_done += 1;
+        self.bytes += size;
+    }
+
+    /// Reports the last entry as done, however recent the previous report was.
+    async fn finish(&mut self) {
+        self.notify().await;
+    }
+
+    async fn notify(&self) {
+        let progress = DirectoryProgress {
+            id: self.id,
+            direction: self.direction.clone(),
+            path: self.current.to_string(),
+            files_done: self.files_done,
+            files_total: self.files_total,
+            bytes: self.bytes,
+            total_bytes: self.total_bytes,
+        };
+        notify(&self.sender_queue, PeerEvent::DirectoryProgress(progress)).await;
+    }
+}
 
 /// An `AsyncRead` that yields a tar archive of `source_path` produced on the
 /// fly in a background task.  The archive is written into the writer half of a
 /// `tokio::io::duplex` channel; this struct exposes the reader half.
+/// Every regular file is hashed with `algorithm` while it is archived, and the
+/// progress is reported against the totals of the manifest given to `new`.
 pub struct TarStream {
     reader: Compat<DuplexStream>,
     task_handle: MaybeTaskHandle,
 }
 
 impl TarStream {
-    pub fn new(source_path: String) -> TarStream {
+    pub fn new(
+        source_path: String,
+        manifest: Option<&Manifest>,
+        algorithm: HashAlgorithm,
+        progress: EntryProgress,
+    ) -> TarStream {
         let (reader, writer) = duplex(DUPLEX_CHANNEL_SIZE);
+        let mut progress = progress.with_totals(manifest);
 
         let task_handle = spawn(async move {
             let src = Path::new(&source_path);
@@ -52,15 +143,17 @@ impl TarStream {
             // With follow_symlinks(true) (the default) a dangling symlink
             // causes fs::metadata to return NotFound, which aborts the task
             // before into_inner() can write the end-of-archive blocks,
-            // producing a truncated stream and a guaranteed MD5 mismatch.
+            // producing a truncated stream and a guaranteed hash mismatch.
             builder.follow_symlinks(false);
 
-            builder.append_dir_all(archive_name, src).await?;
+            let digests =
+                append_tree(&mut builder, archive_name, src, algorithm, &mut progress).await?;
 
             // Flush and write the two 512-byte end-of-archive blocks.
             builder.into_inner().await?;
+            progress.finish().await;
 
-            Ok::<(), Error>(())
+            Ok::<Vec<FileDigest>, Error>(digests)
         });
 
         TarStream {
@@ -86,46 +179,190 @@ impl AsyncRead for TarStream {
     }
 }
 
-/// Receives a tar byte stream from `buf_reader` and unpacks it into the
-/// directory that is the parent of `target_path`.
+/// Same walk as `Builder::append_dir_all`, except that regular files are
+/// read through a `HashingReader`, so that the receiver can tell which
+/// of them arrived incorrect.
+async fn append_tree<W: AsyncWrite + Send + Unpin + 'static>(
+    builder: &mut Builder<W>,
+    archive_name: &Path,
+    src: &Path,
+    algorithm: HashAlgorithm,
+    progress: &mut EntryProgress,
+) -> Result<Vec<FileDigest>, Error> {
+    let mut digests = vec![];
+    let mut stack: Vec<PathBuf> = vec![src.to_path_buf()];
+
+    while let Some(path) = stack.pop() {
+        let relative = path.strip_prefix(src).map_err(Error::other)?;
+        let name = archive_name.join(relative);
+        let stat = fs::symlink_metadata(&path).await?;
+
+        if stat.is_dir() {
+            let mut entries = fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                stack.push(entry.path());
+            }
+            builder.append_dir(&name, &path).await?;
+            continue;
+        }
+
+        // Skipped special files are counted as well, like in the manifest
+        progress.start(&name, stat.len()).await;
+        if stat.is_file() {
+            let file = fs::File::open(&path).await?;
+            let file = BufReader::with_capacity(FILE_READ_BUFFER, file);
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&stat, HeaderMode::Complete);
+
+            let mut reader = HashingReader::new(file.compat(), algorithm);
+            builder
+                .append_data(&mut header, &name, (&mut reader).compat())
+                .await?;
+            digests.push(FileDigest {
+                path: name.to_string_lossy().to_string(),
+                hash: reader.finish(),
+            });
+        } else if stat.file_type().is_symlink() {
+            builder.append_path_with_name(&path, &name).await?;
+        } else {
+            // The receiver refuses fifos, devices and sockets anyway
+            warn!("Skipping special file {:?}", path);
+        }
+    }
+    Ok(digests)
+}
+
+/// Receives a tar byte stream from `reader` and unpacks it into `base_path`.
 ///
-/// Returns a `JoinHandle` that resolves to the number of bytes announced by
-/// the sender (used by callers for consistency; the actual byte count is not
-/// re-measured here since `unpack_in` manages I/O internally).
-pub async fn untar_stream(
-    target_path: String,
-    buf_reader: BufReader<impl TSocketAlias + 'static>,
-    sender_queue: Sender<PeerEvent>,
+/// The current entry is reported through `progress`.
+///
+/// Returns a `JoinHandle` that resolves to the size of the unpacked entries,
+/// the digests of the unpacked regular files, and the reader positioned right
+/// after the archive.
+pub async fn untar_stream<R: AsyncRead + Send + Unpin + 'static>(
+    base_path: String,
+    reader: R,
     size: usize,
-    direction: Direction,
-) -> Result<JoinHandle<Result<usize, Error>>, Error> {
+    span: ProgressSpan,
+    algorithm: HashAlgorithm,
+    mut progress: EntryProgress,
+) -> Result<JoinHandle<Result<(usize, Vec<FileDigest>, R), Error>>, Error> {
     let task = spawn(async move {
-        let base_path = Path::new(&target_path)
-            .parent()
-            .unwrap_or(Path::new(&target_path));
+        let base_path = Path::new(&base_path);
 
         // Stack ProgressReader on top of the socket reader (still in
         // futures::AsyncRead land) so that every byte tokio-tar reads fires
         // throttled TransferProgress events to the UI.  The compat() call
         // then crosses the boundary into tokio::AsyncRead, which Archive
-        // requires.
-        let progress_reader =
-            ProgressReader::new(buf_reader, size, sender_queue.clone(), direction.clone());
+        // requires. The socket is not buffered, so that nothing past the
+        // end of the archive is consumed.
+        let (id, sender_queue) = (progress.id, progress.sender_queue.clone());
+        let progress_reader = ProgressReader::new(
+            reader,
+            id,
+            span.total,
+            sender_queue.clone(),
+            span.direction.clone(),
+        )
+        .resume_at(span.start);
         let compat_reader = progress_reader.compat();
 
         let mut archive = Archive::new(compat_reader);
 
-        // Extracts into base_path, creating the top-level directory
-        // (the archive name) automatically and handling files, empty
-        // directories, and symlinks natively.
-        archive.unpack(base_path).await?;
+        // Extracts into base_path entry by entry, creating the top-level
+        // directory (the archive name) automatically. Every entry is checked
+        // first, so that nothing is written outside of the received directory.
+        // Regular files are hashed while they are written.
+        let mut digests = vec![];
+        let mut guard = ArchiveGuard::default();
+        // The announced size was checked against the limit, so the contents can't exceed it
+        let mut contents: u64 = 0;
+        let mut entries = archive.entries()?;
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let kind = entry.header().entry_type();
+            let path = entry.path()?.into_owned();
+            let link = entry.link_name()?;
+            guard.check(kind, &path, link.as_deref())?;
+            check_parents(base_path, &path).await?;
+            let entry_size = entry.header().size()?;
+            contents += entry_size;
+            if contents > size as u64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Archive is larger than the announced {} bytes", size),
+                ));
+            }
+            if !kind.is_dir() {
+                progress.start(&path, entry_size).await;
+            }
+            if kind == EntryType::Regular {
+                let hash = unpack_file(&mut entry, &base_path.join(&path), algorithm).await?;
+                digests.push(FileDigest {
+                    path: path.to_string_lossy().to_string(),
+                    hash,
+                });
+            } else {
+                entry.unpack_in(base_path).await?;
+            }
+        }
+        drop(entries);
+
+        let mut reader = archive
+            .into_inner()
+            .map_err(|_| Error::other("Archive is still in use"))?
+            .into_inner()
+            .into_inner();
+        let mut tail = [0u8; END_OF_ARCHIVE_TAIL];
+        reader.read_exact(&mut tail).await?;
+        progress.finish().await;
 
         // Final 100 % event — ensures the bar reaches the end even if the
         // last ProgressReader notification fired slightly below 100 %.
-        notify_progress(&sender_queue, size, size, &direction, None).await;
+        notify_progress(
+            &sender_queue,
+            id,
+            span.start + size,
+            span.total,
+            &span.direction,
+            None,
+        )
+        .await;
 
-        Ok::<usize, Error>(size)
+        Ok::<(usize, Vec<FileDigest>, R), Error>((contents as usize, digests, reader))
     });
 
     Ok(task)
 }
+
+/// Writes the regular file `entry` to `dst` and returns the hash of its data,
+/// keeping the mtime like `unpack_in` does.
+async fn unpack_file<R: tokio::io::AsyncRead + Unpin>(
+    entry: &mut Entry<R>,
+    dst: &Path,
+    algorithm: HashAlgorithm,
+) -> IOResult<String> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    // An earlier entry at the same path is replaced rather than written through
+    match fs::remove_file(dst).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)
+        .await?;
+
+    let mtime = entry.header().mtime().ok();
+    let mut reader = HashingReader::new(entry.compat(), algorithm);
+    tokio::io::copy(&mut (&mut reader).compat(), &mut file).await?;
+    if let Some(mtime) = mtime {
+        file.into_std()
+            .await
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+    Ok(reader.finish())
+}
@@ -1,13 +1,17 @@
 use std::error::Error;
 use std::fmt;
 use std::fs::{metadata, File};
-use std::io::{self, Read, Write};
+use std::io::{self, Read, SeekFrom, Write};
 use std::path::Path;
+use std::time::SystemTime;
 
-use crate::p2p::transfer::directory::{MaybeTaskHandle, TarStream};
-use crate::p2p::TransferType;
+use crate::p2p::transfer::directory::{EntryProgress, MaybeTaskHandle, TarStream};
+use crate::p2p::transfer::hash::HashAlgorithm;
+use crate::p2p::util::next_transfer_id;
+use crate::p2p::{TransferId, TransferType};
 use libp2p::core::PeerId;
 use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
 #[derive(Debug, Clone)]
@@ -15,6 +19,8 @@ pub enum Payload {
     Dir(String),
     File(String),
     Text(String),
+    /// Files and directories sent together after a single answer
+    Batch(Vec<Payload>),
 }
 
 impl Payload {
@@ -29,6 +35,10 @@ impl Payload {
 
                 Ok(Payload::Text(contents))
             }
+            TransferType::Batch => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Batch is received item by item",
+            )),
         }
     }
     pub fn new_for_path(path: String) -> Result<Payload, io::Error> {
@@ -41,6 +51,76 @@ impl Payload {
     }
 }
 
+/// Number of top-level entries listed in a directory manifest
+pub const MANIFEST_ENTRIES: usize = 20;
+
+/// Overview of a directory payload, shown to the receiver before it accepts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub file_count: u64,
+    pub total_size: u64,
+    /// Top-level entries in alphabetical order, directories ending with a slash
+    pub entries: Vec<String>,
+    /// Top-level entries left out of `entries`
+    pub more_entries: u64,
+}
+
+impl Manifest {
+    /// Walks the whole tree under `path`. Symlinks are counted as files, not followed.
+    pub fn of_dir(path: &Path) -> Manifest {
+        let mut manifest = Manifest::default();
+        let mut entries = vec![];
+        if let Ok(children) = std::fs::read_dir(path) {
+            for child in children.flatten() {
+                let mut name = child.file_name().to_string_lossy().to_string();
+                if manifest.add_entry(&child.path()) {
+                    name.push('/');
+                }
+                entries.push(name);
+            }
+        }
+        entries.sort();
+        manifest.more_entries = entries.len().saturating_sub(MANIFEST_ENTRIES) as u64;
+        entries.truncate(MANIFEST_ENTRIES);
+        manifest.entries = entries;
+        manifest
+    }
+
+    fn add_tree(&mut self, path: &Path) {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                self.add_entry(&entry.path());
+            }
+        }
+    }
+
+    /// Counts the entry at `path`, or the tree under it. Returns whether it is a directory.
+    fn add_entry(&mut self, path: &Path) -> bool {
+        match std::fs::symlink_metadata(path) {
+            Ok(m) if m.is_dir() => {
+                self.add_tree(path);
+                return true;
+            }
+            Ok(m) => {
+                self.file_count += 1;
+                self.total_size += m.len();
+            }
+            Err(e) => warn!("Can't estimate size of {:?}: {}", path, e),
+        }
+        false
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} bytes, entries: {:?} and {} more",
+            self.file_count, self.total_size, self.entries, self.more_entries
+        )
+    }
+}
+
 pub enum StreamOption {
     Tar(TarStream, MaybeTaskHandle),
     File(Box<dyn futures::AsyncRead + Send + Unpin>),
@@ -48,6 +128,7 @@ pub enum StreamOption {
 
 #[derive(Debug, Clone)]
 pub struct FileToSend {
+    pub id: TransferId,
     pub peer: PeerId,
     pub name: String,
     pub payload: Payload,
@@ -69,6 +150,7 @@ impl FileToSend {
                     None => os_path.to_string_lossy().to_string(),
                 };
                 Ok(FileToSend {
+                    id: next_transfer_id(),
                     name,
                     payload: Payload::Dir(path),
                     peer: peer.to_owned(),
@@ -79,6 +161,7 @@ impl FileToSend {
                 let name = Self::extract_name_path(&path)?;
                 let new_payload = Payload::File(path);
                 Ok(FileToSend {
+                    id: next_transfer_id(),
                     name,
                     payload: new_payload,
                     peer: peer.to_owned(),
@@ -88,49 +171,129 @@ impl FileToSend {
             Payload::Text(text) => {
                 let name = Self::extract_name_text(&text);
                 Ok(FileToSend {
+                    id: next_transfer_id(),
                     name,
                     payload: Payload::Text(text),
                     peer: peer.to_owned(),
                     transfer_type: TransferType::Text,
                 })
             }
+            Payload::Batch(items) => {
+                for item in items.iter() {
+                    if let Payload::Text(_) | Payload::Batch(_) = item {
+                        return Err(format!("Batch can't contain {}", item).into());
+                    }
+                }
+                Ok(FileToSend {
+                    id: next_transfer_id(),
+                    name: format!("{} items", items.len()),
+                    payload: Payload::Batch(items),
+                    peer: peer.to_owned(),
+                    transfer_type: TransferType::Batch,
+                })
+            }
+        }
+    }
+
+    /// Items of a batch payload, each to be announced and streamed on its own.
+    pub fn batch_items(&self) -> Result<Vec<FileToSend>, io::Error> {
+        match &self.payload {
+            Payload::Batch(items) => items
+                .iter()
+                .map(|item| {
+                    FileToSend::new(&self.peer, item.clone())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+                })
+                .collect(),
+            _ => Ok(vec![]),
         }
     }
 
     /// Returns the byte size of the payload without reading file contents.
     /// For files and directories this is a metadata-only operation (stat or walkdir).
     /// For text payloads the size is derived from the in-memory string length.
-    pub async fn get_size(&self) -> Result<u64, io::Error> {
+    /// Directories come with their manifest, built in the same walk.
+    pub async fn get_size(&self) -> Result<(u64, Option<Manifest>), io::Error> {
         match &self.payload {
+            Payload::Batch(items) => {
+                let mut total = 0;
+                for item in items.iter() {
+                    total += Self::payload_size(item).await?.0;
+                }
+                Ok((total, None))
+            }
+            payload => Self::payload_size(payload).await,
+        }
+    }
+
+    async fn payload_size(payload: &Payload) -> Result<(u64, Option<Manifest>), io::Error> {
+        match payload {
             Payload::File(path) => {
                 let meta = tokio::fs::metadata(path).await?;
-                Ok(meta.len())
+                Ok((meta.len(), None))
             }
             Payload::Dir(path) => {
-                fn dir_size(path: &Path) -> u64 {
-                    let mut total = 0u64;
-                    if let Ok(entries) = std::fs::read_dir(path) {
-                        for entry in entries.flatten() {
-                            let p = entry.path();
-                            match std::fs::symlink_metadata(&p) {
-                                Ok(m) if m.is_dir() => total += dir_size(&p),
-                                Ok(m) => total += m.len(),
-                                Err(e) => warn!("Can't estimate size of {:?}: {}", p, e),
-                            }
-                        }
-                    }
-                    total
-                }
-                Ok(dir_size(Path::new(path)))
+                let manifest = Manifest::of_dir(Path::new(path));
+                Ok((manifest.total_size, Some(manifest)))
             }
-            Payload::Text(text) => Ok(text.len() as u64),
+            Payload::Text(text) => Ok((text.len() as u64, None)),
+            Payload::Batch(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Batches can't be nested",
+            )),
+        }
+    }
+
+    /// Modification time and Unix permission bits of a file payload.
+    /// Other payloads have none that the receiver could apply.
+    pub async fn get_attributes(&self) -> Result<(Option<SystemTime>, Option<u32>), io::Error> {
+        match &self.payload {
+            Payload::File(path) => {
+                let meta = tokio::fs::metadata(path).await?;
+                #[cfg(unix)]
+                let mode = {
+                    use std::os::unix::fs::PermissionsExt;
+                    Some(meta.permissions().mode() & 0o7777)
+                };
+                #[cfg(not(unix))]
+                let mode = None;
+                Ok((meta.modified().ok(), mode))
+            }
+            _ => Ok((None, None)),
+        }
+    }
+
+    /// MIME type of the payload, guessed from the extension for files.
+    pub fn mime_type(&self) -> String {
+        match &self.payload {
+            Payload::File(path) => mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            Payload::Text(_) => "text/plain".to_string(),
+            Payload::Dir(_) => "inode/directory".to_string(),
+            Payload::Batch(_) => String::new(),
         }
     }
 
-    pub async fn get_file_stream(&self) -> Result<StreamOption, io::Error> {
+    /// Only file payloads can be resumed. Directories are streamed as
+    /// a tar archive built on the fly, and texts are too small to bother.
+    pub fn is_resumable(&self) -> bool {
+        self.transfer_type == TransferType::File
+    }
+
+    /// Opens the payload for streaming. `offset` skips the beginning of a file
+    /// payload, which the receiver already has from an interrupted transfer.
+    /// Directories are reported against `manifest`, as returned by `get_size`.
+    pub async fn get_file_stream(
+        &self,
+        offset: u64,
+        manifest: Option<&Manifest>,
+        algorithm: HashAlgorithm,
+        progress: EntryProgress,
+    ) -> Result<StreamOption, io::Error> {
         match &self.payload {
             Payload::Dir(path) => {
-                let mut tar_stream = TarStream::new(path.to_owned());
+                let mut tar_stream = TarStream::new(path.to_owned(), manifest, algorithm, progress);
                 let handle = tar_stream.take_handle();
                 Ok(StreamOption::Tar(tar_stream, handle))
             }
@@ -140,9 +303,53 @@ impl FileToSend {
                 Ok(StreamOption::File(Box::new(tokio_file.compat())))
             }
             Payload::File(path) => {
-                let tokio_file = tokio::fs::File::open(path).await?;
+                let mut tokio_file = tokio::fs::File::open(path).await?;
+                if offset > 0 {
+                    tokio_file.seek(SeekFrom::Start(offset)).await?;
+                }
                 Ok(StreamOption::File(Box::new(tokio_file.compat())))
             }
+            Payload::Batch(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Batch is streamed item by item",
+            )),
+        }
+    }
+
+    /// Opens the first `len` bytes of a file payload. A resumed transfer
+    /// doesn't send them, but they still have to be hashed.
+    pub async fn get_prefix_stream(
+        &self,
+        len: u64,
+    ) -> Result<Box<dyn futures::AsyncRead + Send + Unpin>, io::Error> {
+        match &self.payload {
+            Payload::File(path) => {
+                let tokio_file = tokio::fs::File::open(path).await?;
+                Ok(Box::new(tokio_file.take(len).compat()))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Only file payloads can be resumed",
+            )),
+        }
+    }
+
+    /// Opens `len` bytes of a file payload, starting at `offset`.
+    pub async fn get_range_stream(
+        &self,
+        offset: u64,
+        len: u64,
+    ) -> Result<Box<dyn futures::AsyncRead + Send + Unpin>, io::Error> {
+        match &self.payload {
+            Payload::File(path) => {
+                let mut tokio_file = tokio::fs::File::open(path).await?;
+                tokio_file.seek(SeekFrom::Start(offset)).await?;
+                Ok(Box::new(tokio_file.take(len).compat()))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Only file payloads are sent in ranges",
+            )),
         }
     }
 
@@ -150,7 +357,7 @@ impl FileToSend {
     /// can be treated as file by the transfer protocol.
     pub fn create_temp_file(text: &str) -> Result<File, io::Error> {
         let mut tmp_file = NamedTempFile::new()?;
-        tmp_file.write(text.as_bytes())?;
+        tmp_file.write_all(text.as_bytes())?;
         let file = tmp_file.reopen()?;
         Ok(file)
     }
@@ -175,8 +382,8 @@ impl fmt::Display for FileToSend {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "FileToSend name: {}, type: {}",
-            self.name, self.transfer_type
+            "FileToSend id: {}, name: {}, type: {}",
+            self.id, self.name, self.transfer_type
         )
     }
 }
@@ -187,13 +394,21 @@ impl fmt::Display for Payload {
             Self::Dir(path) => write!(f, "DirPayload({})", path),
             Self::File(path) => write!(f, "FilePayload({})", path),
             Self::Text(text) => write!(f, "TextPayload({})", text.len()),
+            Self::Batch(items) => write!(f, "BatchPayload({})", items.len()),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::p2p::transfer::file::FileToSend;
+    use libp2p::PeerId;
+
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use crate::p2p::transfer::file::{FileToSend, Manifest, Payload, MANIFEST_ENTRIES};
 
     #[test]
     fn test_extract_name_text() {
@@ -202,4 +417,44 @@ mod tests {
 
         assert_eq!(result, "here  (...)");
     }
+
+    #[test]
+    fn test_mime_type() {
+        let peer = PeerId::random();
+        let mime = |payload| FileToSend::new(&peer, payload).unwrap().mime_type();
+
+        assert_eq!(
+            mime(Payload::File("tests/data/file.txt".into())),
+            "text/plain"
+        );
+        assert_eq!(
+            mime(Payload::File("tests/data/bench_1mb.bin".into())),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            mime(Payload::Dir("tests/data/test_dir".into())),
+            "inode/directory"
+        );
+    }
+
+    #[test]
+    fn test_manifest_of_dir() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        fs::create_dir_all(path.join("nested/deeper")).unwrap();
+        fs::write(path.join("nested/deeper/a.txt"), "abc").unwrap();
+        for i in 0..MANIFEST_ENTRIES {
+            fs::write(path.join(format!("file_{:02}.txt", i)), "I'll fly").unwrap();
+        }
+
+        let manifest = Manifest::of_dir(Path::new(path));
+
+        assert_eq!(manifest.file_count, MANIFEST_ENTRIES as u64 + 1);
+        assert_eq!(manifest.total_size, 8 * MANIFEST_ENTRIES as u64 + 3);
+        assert_eq!(manifest.entries.len(), MANIFEST_ENTRIES);
+        assert_eq!(manifest.entries[0], "file_00.txt");
+        // "nested/" sorts last, past the limit
+        assert!(!manifest.entries.contains(&"nested/".to_string()));
+        assert_eq!(manifest.more_entries, 1);
+    }
 }
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use md5::{Digest, Md5};
+use prost::Enumeration;
+use sha2::Sha256;
+
+use crate::p2p::util::find_cause;
+
+/// Algorithm used to verify the transferred data.
+/// MD5 is the default, so that peers that don't name
+/// the algorithm in their messages are still understood.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration)]
+pub enum HashAlgorithm {
+    Md5 = 0,
+    Sha256 = 1,
+    Blake3 = 2,
+}
+
+impl HashAlgorithm {
+    /// Algorithms offered to the other side, from the most preferred one.
+    /// MD5 is never listed, because it is the implicit fallback.
+    pub const OFFERED: [HashAlgorithm; 2] = [HashAlgorithm::Blake3, HashAlgorithm::Sha256];
+
+    /// Pick the most preferred algorithm out of the ones offered by the sender.
+    pub fn negotiate(offered: &[HashAlgorithm]) -> HashAlgorithm {
+        Self::OFFERED
+            .into_iter()
+            .find(|algorithm| offered.contains(algorithm))
+            .unwrap_or(HashAlgorithm::Md5)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Md5 => write!(f, "MD5"),
+            Self::Sha256 => write!(f, "SHA-256"),
+            Self::Blake3 => write!(f, "BLAKE3"),
+        }
+    }
+}
+
+/// Running digest of one of the supported algorithms.
+pub enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(state) => state.update(data),
+            Hasher::Sha256(state) => state.update(data),
+            Hasher::Blake3(state) => {
+                state.update(data);
+            }
+        }
+    }
+
+    /// Returns the hex-encoded digest.
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(state) => hex::encode(state.finalize()),
+            Hasher::Sha256(state) => hex::encode(state.finalize()),
+            Hasher::Blake3(state) => state.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Digest of a single file of a directory, named by its path within the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDigest {
+    pub path: String,
+    pub hash: String,
+}
+
+/// Received data doesn't match the digest computed by the sender.
+#[derive(Debug, Clone)]
+pub struct HashMismatch {
+    /// Names of the incorrect files
+    pub files: Vec<String>,
+}
+
+impl HashMismatch {
+    /// Files of `expected` whose digests are different in `received`, or missing there.
+    pub fn compare(expected: &[FileDigest], received: &[FileDigest]) -> Vec<String> {
+        expected
+            .iter()
+            .filter(|digest| !received.contains(digest))
+            .map(|digest| digest.path.to_string())
+            .collect()
+    }
+
+    /// Looks for a mismatch among the causes of `error`.
+    pub fn find(error: &io::Error) -> Option<&HashMismatch> {
+        find_cause(error)
+    }
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash mismatch in {}", self.files.join(", "))
+    }
+}
+
+impl Error for HashMismatch {}
+
+impl From<HashMismatch> for io::Error {
+    fn from(mismatch: HashMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileDigest, HashAlgorithm, HashMismatch, Hasher};
+
+    #[test]
+    fn test_negotiate_prefers_blake3() {
+        let offered = [HashAlgorithm::Sha256, HashAlgorithm::Blake3];
+        assert_eq!(HashAlgorithm::negotiate(&offered), HashAlgorithm::Blake3);
+        assert_eq!(
+            HashAlgorithm::negotiate(&[HashAlgorithm::Sha256]),
+            HashAlgorithm::Sha256
+        );
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_md5() {
+        assert_eq!(HashAlgorithm::negotiate(&[]), HashAlgorithm::Md5);
+    }
+
+    #[test]
+    fn test_compare_file_digests() {
+        let digest = |path: &str, hash: &str| FileDigest {
+            path: path.to_string(),
+            hash: hash.to_string(),
+        };
+        let expected = [
+            digest("dir/a", "1"),
+            digest("dir/b", "2"),
+            digest("dir/c", "3"),
+        ];
+        let received = [digest("dir/a", "1"), digest("dir/b", "0")];
+
+        assert_eq!(
+            HashMismatch::compare(&expected, &received),
+            vec!["dir/b", "dir/c"]
+        );
+        assert!(HashMismatch::compare(&expected, &expected).is_empty());
+    }
+
+    #[test]
+    fn test_hasher_digests() {
+        let digest = |algorithm| {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"abc");
+            hasher.finish()
+        };
+        assert_eq!(
+            digest(HashAlgorithm::Md5),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+}
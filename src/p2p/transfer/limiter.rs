@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::AsyncRead;
+use libp2p::PeerId;
+use tokio::time::{sleep, Sleep};
+
+use crate::p2p::peer::Direction;
+use crate::user_data::RateLimit;
+
+/// Reads are not split into pieces smaller than this, so that a slow
+/// limit doesn't turn into a stream of tiny reads.
+const MIN_GRANT: usize = 4 * 1024; // 4 KiB
+
+/// How much a bucket saves up while the transfer is idle, in seconds of its rate.
+const BURST_SECONDS: f64 = 0.5;
+
+/// Longest single wait for tokens, so that a changed limit takes effect soon.
+const MAX_WAIT: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second, None when unlimited
+    rate: Option<f64>,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(kib_per_second: u64) -> Self {
+        let mut bucket = TokenBucket {
+            rate: None,
+            tokens: 0.0,
+            updated: Instant::now(),
+        };
+        bucket.set_rate(kib_per_second);
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn set_rate(&mut self, kib_per_second: u64) {
+        self.rate = match kib_per_second {
+            0 => None,
+            kib => Some(kib as f64 * 1024.0),
+        };
+        self.tokens = self.tokens.min(self.capacity());
+    }
+
+    fn capacity(&self) -> f64 {
+        match self.rate {
+            Some(rate) => (rate * BURST_SECONDS).max(MIN_GRANT as f64),
+            None => 0.0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(self.capacity());
+        }
+        self.updated = now;
+    }
+
+    /// Number of bytes that can be read now, out of `wanted`,
+    /// or how long to wait until there are enough tokens.
+    fn allowance(&self, wanted: usize) -> Result<usize, Duration> {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return Ok(wanted),
+        };
+        let needed = wanted.min(MIN_GRANT) as f64;
+        if self.tokens >= needed {
+            Ok(wanted.min(self.tokens as usize))
+        } else {
+            Err(Duration::from_secs_f64((needed - self.tokens) / rate))
+        }
+    }
+
+    /// Concurrent readers can take more than there is, the debt is paid off by waiting.
+    fn consume(&mut self, bytes: usize) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    limit: RateLimit,
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Self {
+        Buckets {
+            limit,
+            upload: TokenBucket::new(limit.upload),
+            download: TokenBucket::new(limit.download),
+        }
+    }
+
+    fn set_limit(&mut self, limit: RateLimit) {
+        self.limit = limit;
+        self.upload.set_rate(limit.upload);
+        self.download.set_rate(limit.download);
+    }
+
+    fn bucket(&mut self, direction: &Direction) -> &mut TokenBucket {
+        match direction {
+            Direction::Outgoing => &mut self.upload,
+            Direction::Incoming => &mut self.download,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BandwidthState {
+    global: Buckets,
+    peers: HashMap<PeerId, Buckets>,
+}
+
+/// Bandwidth available to the transfers. It is shared by all of them, so that
+/// the global limits hold for concurrent transfers together, and the limits
+/// can be changed while the transfers run.
+#[derive(Clone, Debug)]
+pub struct Bandwidth {
+    state: Arc<Mutex<BandwidthState>>,
+}
+
+impl Bandwidth {
+    pub fn new(global: RateLimit, peers: HashMap<PeerId, RateLimit>) -> Self {
+        let state = BandwidthState {
+            global: Buckets::new(global),
+            peers: peers
+                .into_iter()
+                .map(|(peer, limit)| (peer, Buckets::new(limit)))
+                .collect(),
+        };
+        Bandwidth {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn global_limit(&self) -> RateLimit {
+        self.state.lock().unwrap().global.limit
+    }
+
+    pub fn set_global_limit(&self, limit: RateLimit) {
+        info!("Setting rate limit: {:?}", limit);
+        self.state.lock().unwrap().global.set_limit(limit);
+    }
+
+    /// Limit transfers with `peer` on top of the global limit.
+    pub fn set_peer_limit(&self, peer: PeerId, limit: RateLimit) {
+        let mut state = self.state.lock().unwrap();
+        match state.peers.get_mut(&peer) {
+            Some(buckets) => buckets.set_limit(limit),
+            None => {
+                state.peers.insert(peer, Buckets::new(limit));
+            }
+        }
+    }
+
+    fn allowance(
+        &self,
+        peer: &PeerId,
+        direction: &Direction,
+        wanted: usize,
+    ) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let global = state.global.bucket(direction);
+        global.refill(now);
+        let mut allowance = global.allowance(wanted);
+
+        if let Some(buckets) = state.peers.get_mut(peer) {
+            let bucket = buckets.bucket(direction);
+            bucket.refill(now);
+            allowance = match (allowance, bucket.allowance(wanted)) {
+                (Ok(global), Ok(peer)) => Ok(global.min(peer)),
+                (Err(global), Err(peer)) => Err(global.max(peer)),
+                (Err(wait), _) | (_, Err(wait)) => Err(wait),
+            };
+        }
+        allowance
+    }
+
+    fn consume(&self, peer: &PeerId, direction: &Direction, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.global.bucket(direction).consume(bytes);
+        if let Some(buckets) = state.peers.get_mut(peer) {
+            buckets.bucket(direction).consume(bytes);
+        }
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth::new(RateLimit::default(), HashMap::new())
+    }
+}
+
+/// Wraps an `AsyncRead` and holds reads back, so that the data of transfers
+/// with `peer` in `direction` doesn't flow faster than the `Bandwidth` allows.
+pub struct ThrottledReader<R> {
+    inner: R,
+    bandwidth: Bandwidth,
+    peer: PeerId,
+    direction: Direction,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> ThrottledReader<R> {
+    pub fn new(inner: R, bandwidth: Bandwidth, peer: PeerId, direction: Direction) -> Self {
+        Self {
+            inner,
+            bandwidth,
+            peer,
+            direction,
+            delay: None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            match this
+                .bandwidth
+                .allowance(&this.peer, &this.direction, buf.len())
+            {
+                Ok(allowed) => {
+                    let result = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..allowed]);
+                    if let Poll::Ready(Ok(n)) = result {
+                        this.bandwidth.consume(&this.peer, &this.direction, n);
+                    }
+                    return result;
+                }
+                Err(wait) => this.delay = Some(Box::pin(sleep(wait.min(MAX_WAIT)))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use futures::io::{copy, sink, Cursor};
+    use libp2p::PeerId;
+
+    use crate::p2p::peer::Direction;
+    use crate::p2p::transfer::limiter::{Bandwidth, ThrottledReader};
+    use crate::user_data::RateLimit;
+
+    #[tokio::test]
+    async fn test_throttled_reader_keeps_the_limit() {
+        let peer = PeerId::random();
+        let limit = RateLimit {
+            upload: 0,
+            download: 64,
+        };
+        let bandwidth = Bandwidth::new(RateLimit::default(), HashMap::from([(peer, limit)]));
+
+        // Half a second of burst, then another half a second of waiting
+        let data = Cursor::new(vec![0u8; 64 * 1024]);
+        let mut reader = ThrottledReader::new(data, bandwidth.clone(), peer, Direction::Incoming);
+        let start = Instant::now();
+        copy(&mut reader, &mut sink()).await.unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed > 0.4 && elapsed < 0.9, "took {}s", elapsed);
+
+        // Uploads are not limited
+        let data = Cursor::new(vec![0u8; 1024 * 1024]);
+        let mut reader = ThrottledReader::new(data, bandwidth, peer, Direction::Outgoing);
+        let start = Instant::now();
+        copy(&mut reader, &mut sink()).await.unwrap();
+        assert!(start.elapsed().as_secs_f64() < 0.1);
+    }
+}
@@ -1,71 +1,259 @@
 use std::fmt;
+use std::fs::File;
 use std::io::{self, Error};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::proto::Answer as ProtoAnswer;
+use super::proto::Batch as ProtoBatch;
+use super::proto::Cancel as ProtoCancel;
+use super::proto::FileDigest as ProtoFileDigest;
+use super::proto::Manifest as ProtoManifest;
 use super::proto::Metadata as ProtoMetadata;
+use super::proto::Parallel as ProtoParallel;
+use super::proto::Range as ProtoRange;
 use super::proto::Trailer as ProtoTrailer;
 use futures::prelude::*;
 use hex;
+use libp2p::PeerId;
 use md5::{Digest, Md5};
+use prost::encoding::{self, WireType};
 use prost::Message;
 
-use crate::p2p::transfer::FileToSend;
-use crate::p2p::util::TSocketAlias;
+use crate::p2p::transfer::compression::Compression;
+use crate::p2p::transfer::file::{Manifest, MANIFEST_ENTRIES};
+use crate::p2p::transfer::hash::{FileDigest, HashAlgorithm, Hasher};
+use crate::p2p::transfer::ranges::{FileRange, ParallelOffer, Parallelism};
+use crate::p2p::transfer::version::ProtocolVersion;
+use crate::p2p::transfer::{FileToSend, Payload};
 use crate::p2p::TransferType;
-
-pub const ANSWER_SIZE: usize = 2;
-pub const PACKET_SIZE: usize = 1024;
+use crate::user_data::PreservedAttributes;
+
+/// Largest control message accepted from the other peer
+pub const MAX_PACKET_SIZE: usize = 64 * 1024;
+/// Largest trailer accepted, as it lists every file of a directory
+pub const MAX_TRAILER_SIZE: usize = 32 * 1024 * 1024;
+/// Size of every control message of the legacy protocol, padded with zeros
+pub const LEGACY_PACKET_SIZE: usize = 1024;
 pub const HASH_BUFFER_SIZE: usize = 1024;
 
 pub struct Metadata {
     pub name: String,
     pub size: usize,
     pub transfer_type: TransferType,
+    pub resumable: bool,
+    /// Number of items that follow, for the batch transfer type
+    pub item_count: usize,
+    /// Hash algorithms offered by the sender, MD5 excluded
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    /// Compressions offered by the sender
+    pub compressions: Vec<Compression>,
+    pub mtime: Option<SystemTime>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    /// Empty when the sender doesn't know it
+    pub mime_type: String,
+    /// Overview of the contents of a directory
+    pub manifest: Option<Manifest>,
+    /// Offer to send the file in ranges
+    pub parallel: Option<ParallelOffer>,
+    /// Set when only a range of an accepted file follows
+    pub range: Option<FileRange>,
 }
 
 impl Metadata {
-    pub async fn read(socket: impl TSocketAlias) -> Result<(Self, impl TSocketAlias), io::Error> {
-        let (data, socket) = read_from_socket(socket).await?;
+    pub async fn read<S: AsyncRead + Unpin>(
+        socket: S,
+        version: ProtocolVersion,
+    ) -> Result<(Self, S), io::Error> {
+        let (data, socket) = read_from_socket(socket, version).await?;
         let proto = ProtoMetadata::decode(&data[..])?;
 
         let name = proto.name;
         let size = proto.size as usize;
         let transfer_type =
             TransferType::try_from(proto.transfer_type).unwrap_or(TransferType::File);
+        let resumable = proto.resumable;
+        let item_count = proto.batch.map(|batch| batch.count as usize).unwrap_or(0);
+        let hash_algorithms = proto
+            .hash_algorithms
+            .into_iter()
+            .filter_map(|algorithm| HashAlgorithm::try_from(algorithm).ok())
+            .collect();
+        let compressions = match version {
+            ProtocolVersion::V1_2 => vec![],
+            _ => proto
+                .compressions
+                .into_iter()
+                .filter_map(|compression| Compression::try_from(compression).ok())
+                .collect(),
+        };
+        let mtime = match proto.mtime {
+            0 => None,
+            seconds => Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+        };
+        let mode = match proto.mode {
+            0 => None,
+            mode => Some(mode),
+        };
+        let manifest = proto.manifest.map(|manifest| {
+            // Longer lists wouldn't fit in the dialog anyway
+            let mut entries = manifest.entries;
+            let left_out = entries.len().saturating_sub(MANIFEST_ENTRIES) as u64;
+            entries.truncate(MANIFEST_ENTRIES);
+            Manifest {
+                file_count: manifest.file_count,
+                total_size: manifest.total_size,
+                entries,
+                more_entries: manifest.more_entries + left_out,
+            }
+        });
+        let parallel = proto.parallel.map(|parallel| ParallelOffer {
+            key: parallel.key,
+            streams: parallel.streams as usize,
+        });
+        let range = proto.range.map(|range| FileRange {
+            key: range.key,
+            offset: range.offset,
+            length: range.length,
+        });
         info!("Read: Name: {}, Size: {}", name, size);
         Ok((
             Metadata {
                 name,
                 size,
                 transfer_type,
+                resumable,
+                item_count,
+                hash_algorithms,
+                compressions,
+                mtime,
+                mode,
+                mime_type: proto.mime_type,
+                manifest,
+                parallel,
+                range,
             },
             socket,
         ))
     }
 
-    pub async fn write(
+    pub async fn write<S: AsyncWrite + Unpin>(
+        file: &FileToSend,
+        socket: S,
+        version: ProtocolVersion,
+    ) -> Result<(usize, S), io::Error> {
+        Self::write_parallel(file, socket, version, Parallelism::default()).await
+    }
+
+    /// Like `write`, but offers to send the file in ranges, if it's large enough.
+    pub async fn write_parallel<S: AsyncWrite + Unpin>(
+        file: &FileToSend,
+        socket: S,
+        version: ProtocolVersion,
+        parallelism: Parallelism,
+    ) -> Result<(usize, S), io::Error> {
+        let sized = file.get_size().await?;
+        Self::write_sized(file, sized, socket, version, parallelism).await
+    }
+
+    /// Like `write_parallel`, with the size and manifest from `FileToSend::get_size`,
+    /// so that directories are not walked again.
+    pub async fn write_sized<S: AsyncWrite + Unpin>(
         file: &FileToSend,
-        mut socket: impl TSocketAlias,
-    ) -> Result<(usize, impl TSocketAlias), io::Error> {
-        let size = file.get_size().await?;
+        (size, manifest): (u64, Option<Manifest>),
+        mut socket: S,
+        version: ProtocolVersion,
+        parallelism: Parallelism,
+    ) -> Result<(usize, S), io::Error> {
+        // Receivers that don't know the offer answer without any streams
+        let streams = parallelism.streams_for(size);
+        let parallel = match &file.payload {
+            Payload::File(_) if streams > 1 && !version.is_legacy() => Some(ProtoParallel {
+                key: file.id,
+                streams: streams as u32,
+            }),
+            _ => None,
+        };
+        // Legacy data isn't chunked, so the end of a compressed stream couldn't be found
+        let compressions = match version {
+            ProtocolVersion::V1_2 => vec![],
+            _ => Compression::offered_for(file),
+        };
+        let (mtime, mode) = file.get_attributes().await?;
 
         let proto = ProtoMetadata {
             name: file.name.to_string(),
             size,
             transfer_type: file.transfer_type as i32,
+            resumable: file.is_resumable(),
+            batch: match &file.payload {
+                Payload::Batch(items) => Some(ProtoBatch {
+                    count: items.len() as u32,
+                }),
+                _ => None,
+            },
+            hash_algorithms: HashAlgorithm::OFFERED
+                .iter()
+                .map(|algorithm| *algorithm as i32)
+                .collect(),
+            compressions: compressions
+                .into_iter()
+                .map(|compression| compression as i32)
+                .collect(),
+            mtime: mtime
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs()),
+            mode: mode.unwrap_or(0),
+            mime_type: file.mime_type(),
+            // Legacy packets are too short for a list of names
+            manifest: manifest
+                .filter(|_| !version.is_legacy())
+                .map(|manifest| ProtoManifest {
+                    file_count: manifest.file_count,
+                    total_size: manifest.total_size,
+                    entries: manifest.entries,
+                    more_entries: manifest.more_entries,
+                }),
+            parallel,
+            range: None,
         };
-        let len = proto.encoded_len();
-        let fill = vec![0; PACKET_SIZE - len];
-        let mut buf = Vec::with_capacity(len);
-        proto.encode(&mut buf)?;
-
-        socket.write(&buf[..len]).await?;
+        write_packet(&mut socket, &proto, version).await?;
+        Ok((size as usize, socket))
+    }
 
-        // Append null bytes to the stream to transmit the full packet
-        socket.write(&fill).await?;
-        socket.flush().await?;
+    /// Identifies the file offered by `peer`, so that a partial file is only
+    /// resumed with the data of the same one. Senders that don't tell the
+    /// mtime are only told apart by the size.
+    pub fn source(&self, peer: &PeerId) -> String {
+        let since = self
+            .mtime
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok());
+        match since {
+            Some(since) => format!("{} {} {}", peer, self.size, since.as_secs()),
+            None => format!("{} {}", peer, self.size),
+        }
+    }
 
-        Ok((size as usize, socket))
+    /// Announce `range` of `file`, which the receiver accepted already.
+    pub async fn write_range<S: AsyncWrite + Unpin>(
+        file: &FileToSend,
+        range: &FileRange,
+        mut socket: S,
+        version: ProtocolVersion,
+    ) -> Result<S, io::Error> {
+        let proto = ProtoMetadata {
+            name: file.name.to_string(),
+            size: range.length,
+            transfer_type: file.transfer_type as i32,
+            range: Some(ProtoRange {
+                key: range.key,
+                offset: range.offset,
+                length: range.length,
+            }),
+            ..Default::default()
+        };
+        write_packet(&mut socket, &proto, version).await?;
+        Ok(socket)
     }
 
     /// Produce predictable file name for both file and text payloads.
@@ -75,132 +263,357 @@ impl Metadata {
         match self.transfer_type {
             TransferType::File => self.name.to_string(),
             TransferType::Dir => self.name.to_string(),
+            TransferType::Batch => self.name.to_string(),
             TransferType::Text => {
                 let mut hasher = Md5::new();
-                hasher.update(self.name.to_string());
+                hasher.update(&self.name);
                 let result = hasher.finalize();
-                hex::encode::<Vec<u8>>(result.to_vec())
+                hex::encode(result)
             }
         }
     }
+
+    /// Give the received file at `path` the attributes it had on the sender's side,
+    /// as far as `preserved` allows it.
+    pub fn apply_attributes(&self, path: &str, preserved: PreservedAttributes) -> io::Result<()> {
+        if let (true, Some(mtime)) = (preserved.mtime, self.mtime) {
+            File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(mtime)?;
+        }
+        #[cfg(unix)]
+        if let (true, Some(mode)) = (preserved.permissions, self.mode) {
+            use std::os::unix::fs::PermissionsExt;
+            // Special bits and write access of others are not taken over,
+            // and the owner can always read and write the file.
+            let mode = (mode & 0o755) | 0o600;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Metadata:\n name: {}\n size: {}\n type: {}\n",
-            self.name, self.size, self.transfer_type
+            "Metadata:\n name: {}\n size: {}\n type: {}\n resumable: {}\n items: {}\n hashes: {:?}\n compressions: {:?}\n mtime: {:?}\n mode: {:?}\n mime: {}\n manifest: {:?}\n parallel: {:?}\n range: {:?}\n",
+            self.name,
+            self.size,
+            self.transfer_type,
+            self.resumable,
+            self.item_count,
+            self.hash_algorithms,
+            self.compressions,
+            self.mtime,
+            self.mode.map(|mode| format!("{:o}", mode)),
+            self.mime_type,
+            self.manifest.as_ref().map(|manifest| manifest.to_string()),
+            self.parallel,
+            self.range
         )
     }
 }
 
 #[derive(Debug)]
-pub struct Answer;
+pub struct Answer {
+    pub accepted: bool,
+    /// Offset from which the receiver wants the data
+    pub offset: u64,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    /// Why the payload was refused, empty if the user denied it
+    pub reason: String,
+    /// Number of substreams for the ranges, 0 when the data follows the Answer
+    pub streams: usize,
+}
 
 impl Answer {
-    pub async fn read(socket: impl TSocketAlias) -> Result<(bool, impl TSocketAlias), io::Error> {
-        let (data, socket) = read_from_socket(socket).await?;
-        let proto = ProtoAnswer::decode(&data[..])?;
+    pub fn denied() -> Self {
+        Answer {
+            accepted: false,
+            offset: 0,
+            hash_algorithm: HashAlgorithm::Md5,
+            compression: Compression::None,
+            reason: String::new(),
+            streams: 0,
+        }
+    }
 
-        Ok((proto.accepted, socket))
+    pub fn refused(reason: &str) -> Self {
+        Answer {
+            reason: reason.to_string(),
+            ..Self::denied()
+        }
     }
 
-    pub async fn write(
-        mut socket: impl TSocketAlias,
-        accepted: bool,
-        hash: String,
-    ) -> Result<((), impl TSocketAlias), io::Error> {
-        let proto = ProtoAnswer { accepted, hash };
-        let len = proto.encoded_len();
-        let fill = vec![0; PACKET_SIZE - len];
-        let mut buf = Vec::with_capacity(len);
-        proto.encode(&mut buf)?;
+    pub async fn read<S: AsyncRead + Unpin>(
+        socket: S,
+        version: ProtocolVersion,
+    ) -> Result<(Self, S), io::Error> {
+        let (data, socket) = read_from_socket(socket, version).await?;
+        let proto = ProtoAnswer::decode(&data[..])?;
+        let hash_algorithm =
+            HashAlgorithm::try_from(proto.hash_algorithm).unwrap_or(HashAlgorithm::Md5);
+        let compression = Compression::try_from(proto.compression).unwrap_or(Compression::None);
+
+        Ok((
+            Answer {
+                accepted: proto.accepted,
+                offset: proto.offset,
+                hash_algorithm,
+                compression,
+                reason: proto.reason,
+                streams: proto.streams as usize,
+            },
+            socket,
+        ))
+    }
 
-        socket.write(&buf[..len]).await?;
-        socket.write(&fill).await?;
-        socket.flush().await?;
+    pub async fn write<S: AsyncWrite + Unpin>(
+        &self,
+        mut socket: S,
+        version: ProtocolVersion,
+    ) -> Result<((), S), io::Error> {
+        let proto = ProtoAnswer {
+            accepted: self.accepted,
+            hash: String::new(),
+            offset: self.offset,
+            hash_algorithm: self.hash_algorithm as i32,
+            compression: self.compression as i32,
+            reason: self.reason.to_string(),
+            streams: self.streams as u32,
+        };
+        write_packet(&mut socket, &proto, version).await?;
         Ok(((), socket))
     }
 }
 
+/// Hash of the sent data and the algorithm that produced it.
 #[derive(Debug)]
-pub struct Trailer;
+pub struct Trailer {
+    pub hash: String,
+    pub algorithm: HashAlgorithm,
+    /// Digests of the files of a directory
+    pub files: Vec<FileDigest>,
+}
 
 impl Trailer {
-    pub async fn read(socket: &mut impl TSocketAlias) -> Result<String, io::Error> {
-        let (data, _rest) = read_from_socket_ref(socket).await?;
-        let proto = ProtoTrailer::decode(&data[..])?;
-        Ok(proto.hash)
+    pub fn new(hash: String, algorithm: HashAlgorithm) -> Self {
+        Trailer {
+            hash,
+            algorithm,
+            files: vec![],
+        }
     }
 
-    pub async fn write(socket: &mut impl TSocketAlias, hash: String) -> Result<(), io::Error> {
-        let proto = ProtoTrailer { hash };
-        let len = proto.encoded_len();
-        let fill = vec![0; PACKET_SIZE - len];
-        let mut buf = Vec::with_capacity(len);
-        proto.encode(&mut buf)?;
+    pub async fn read(
+        socket: &mut (impl AsyncRead + Unpin),
+        version: ProtocolVersion,
+    ) -> Result<Self, io::Error> {
+        let data = read_packet_up_to(socket, version, MAX_TRAILER_SIZE).await?;
+        let proto = ProtoTrailer::decode(&data[..])?;
+        let algorithm = HashAlgorithm::try_from(proto.algorithm).unwrap_or(HashAlgorithm::Md5);
+        let files = proto
+            .files
+            .into_iter()
+            .map(|file| FileDigest {
+                path: file.path,
+                hash: file.hash,
+            })
+            .collect();
+        Ok(Trailer {
+            hash: proto.hash,
+            algorithm,
+            files,
+        })
+    }
 
-        socket.write(&buf[..len]).await?;
-        socket.write(&fill).await?;
-        socket.flush().await?;
-        Ok(())
+    pub async fn write(
+        &self,
+        socket: &mut (impl AsyncWrite + Unpin),
+        version: ProtocolVersion,
+    ) -> Result<(), io::Error> {
+        // Legacy packets are too short for a list of files
+        let files = match version {
+            ProtocolVersion::V1_2 => vec![],
+            _ => self
+                .files
+                .iter()
+                .map(|file| ProtoFileDigest {
+                    path: file.path.to_string(),
+                    hash: file.hash.to_string(),
+                })
+                .collect(),
+        };
+        let mut proto = ProtoTrailer {
+            hash: self.hash.to_string(),
+            algorithm: self.algorithm as i32,
+            files,
+        };
+        if proto.encoded_len() > MAX_TRAILER_SIZE {
+            // The receiver can still verify the whole archive
+            warn!(
+                "Too many files to send their digests: {}",
+                proto.files.len()
+            );
+            proto.files.clear();
+        }
+        write_packet(socket, &proto, version).await
     }
 }
 
-async fn read_from_socket(
-    mut socket: impl TSocketAlias,
-) -> Result<(Vec<u8>, impl TSocketAlias), io::Error> {
-    let mut read = 0;
-    let mut data: Vec<u8> = vec![];
-    loop {
-        let mut buff = [0u8; PACKET_SIZE];
-        match socket.read(&mut buff).await {
-            Ok(n) => {
-                read += n;
-                data.extend(&buff[..n]);
-                if read >= PACKET_SIZE {
-                    break;
-                }
-            }
-            Err(e) => return Err(e),
-        }
+/// Cancellation of the transfer sent by the receiver. The sender
+/// tells about the cancellation in the data chunks instead.
+/// Peers on the legacy protocol can't cancel, they close the stream.
+#[derive(Debug)]
+pub struct Cancel;
+
+impl Cancel {
+    /// Returns the reason of the cancellation, and whether
+    /// the receiver refused unsafe data.
+    pub async fn read(socket: &mut (impl AsyncRead + Unpin)) -> Result<(String, bool), io::Error> {
+        let data = read_packet(socket, ProtocolVersion::V1_4).await?;
+        let proto = ProtoCancel::decode(&data[..])?;
+        Ok((proto.reason, proto.refused))
+    }
+
+    pub async fn write(
+        socket: &mut (impl AsyncWrite + Unpin),
+        reason: &str,
+        refused: bool,
+    ) -> Result<(), io::Error> {
+        let proto = ProtoCancel {
+            reason: reason.to_string(),
+            refused,
+        };
+        write_packet(socket, &proto, ProtocolVersion::V1_4).await
     }
+}
 
-    // Remove all extra null bytes from the buffer
-    data.retain(|x| *x != 0u8);
+async fn read_from_socket<S: AsyncRead + Unpin>(
+    mut socket: S,
+    version: ProtocolVersion,
+) -> Result<(Vec<u8>, S), io::Error> {
+    let data = read_packet(&mut socket, version).await?;
     Ok((data, socket))
 }
 
 // Same as read_from_socket but borrows the socket so the caller keeps ownership.
-async fn read_from_socket_ref(socket: &mut impl TSocketAlias) -> Result<(Vec<u8>, ()), io::Error> {
-    let mut read = 0;
-    let mut data: Vec<u8> = vec![];
-    loop {
-        let mut buff = [0u8; PACKET_SIZE];
-        match socket.read(&mut buff).await {
-            Ok(n) => {
-                read += n;
-                data.extend(&buff[..n]);
-                if read >= PACKET_SIZE {
-                    break;
-                }
+// Reads exactly one u32 big-endian length-prefixed packet, so that nothing
+// that follows it is consumed.
+async fn read_packet(
+    socket: &mut (impl AsyncRead + Unpin),
+    version: ProtocolVersion,
+) -> Result<Vec<u8>, io::Error> {
+    read_packet_up_to(socket, version, MAX_PACKET_SIZE).await
+}
+
+async fn read_packet_up_to(
+    socket: &mut (impl AsyncRead + Unpin),
+    version: ProtocolVersion,
+    max_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+    if version.is_legacy() {
+        return read_legacy_packet(socket).await;
+    }
+    let mut len_buf = [0u8; 4];
+    socket.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Control message too large: {}", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    socket.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Legacy packets are padded with zeros up to the fixed size. Field values
+/// can end with zero bytes too, so the message is cut where the padding starts.
+async fn read_legacy_packet(socket: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, io::Error> {
+    let mut data = vec![0u8; LEGACY_PACKET_SIZE];
+    socket.read_exact(&mut data).await?;
+    let len = legacy_message_len(&data)?;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Length of the protobuf message at the start of a legacy packet. Every field
+/// starts with a key, which is never zero, so the first zero key is the padding.
+fn legacy_message_len(packet: &[u8]) -> Result<usize, io::Error> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, format!("Legacy packet: {}", e));
+    let mut rest = packet;
+    while rest.first().is_some_and(|byte| *byte != 0) {
+        let key = encoding::decode_varint(&mut rest).map_err(invalid)?;
+        let skip = match WireType::try_from(key & 0x7).map_err(invalid)? {
+            WireType::Varint => {
+                encoding::decode_varint(&mut rest).map_err(invalid)?;
+                0
             }
-            Err(e) => return Err(e),
+            WireType::SixtyFourBit => 8,
+            WireType::ThirtyTwoBit => 4,
+            WireType::LengthDelimited => {
+                encoding::decode_varint(&mut rest).map_err(invalid)? as usize
+            }
+            WireType::StartGroup | WireType::EndGroup => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Legacy packet: groups are not used",
+                ))
+            }
+        };
+        rest = rest.get(skip..).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Legacy packet: field is cut off",
+            )
+        })?;
+    }
+    Ok(packet.len() - rest.len())
+}
+
+/// Encode `proto` as a u32 big-endian length-prefixed packet, the same way
+/// as discovery messages, or as a zero-padded packet of the legacy protocol.
+async fn write_packet(
+    socket: &mut (impl AsyncWrite + Unpin),
+    proto: &impl Message,
+    version: ProtocolVersion,
+) -> Result<(), io::Error> {
+    let len = proto.encoded_len();
+    let mut buf = Vec::with_capacity(4 + len);
+    if version.is_legacy() {
+        if len > LEGACY_PACKET_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Control message too large for the legacy protocol: {}", len),
+            ));
         }
+        proto.encode(&mut buf)?;
+        buf.resize(LEGACY_PACKET_SIZE, 0);
+    } else {
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        proto.encode(&mut buf)?;
     }
 
-    data.retain(|x| *x != 0u8);
-    Ok((data, ()))
+    socket.write_all(&buf).await?;
+    socket.flush().await?;
+    Ok(())
 }
 
-pub async fn hash_contents(mut file: impl AsyncRead + Unpin) -> Result<(String, u64), Error> {
-    let mut state = Md5::default();
+pub async fn hash_contents(
+    mut file: impl AsyncRead + Unpin,
+    algorithm: HashAlgorithm,
+) -> Result<(String, u64), Error> {
+    let mut state = Hasher::new(algorithm);
     let mut buffer = [0u8; HASH_BUFFER_SIZE];
     let mut i: u64 = 0;
     loop {
         match file.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
+            Ok(0) => {
                 break;
             }
             Ok(n) => {
@@ -210,16 +623,148 @@ pub async fn hash_contents(mut file: impl AsyncRead + Unpin) -> Result<(String,
             Err(e) => return Err(e),
         };
     }
-    let hash = hex::encode::<Vec<u8>>(state.finalize().to_vec());
-    Ok((hash, i))
+    Ok((state.finish(), i))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::p2p::transfer::metadata::hash_contents;
+    use crate::p2p::transfer::compression::Compression;
+    use crate::p2p::transfer::file::{FileToSend, Payload};
+    use crate::p2p::transfer::hash::HashAlgorithm;
+    use crate::p2p::transfer::metadata::{
+        hash_contents, Answer, Metadata, Trailer, LEGACY_PACKET_SIZE,
+    };
+    use crate::p2p::transfer::ranges::{split, ParallelOffer, Parallelism};
+    use crate::p2p::transfer::version::ProtocolVersion;
+    use futures::io::Cursor;
+    use libp2p::PeerId;
     use std::io::{Seek, SeekFrom, Write};
     use tokio_util::compat::TokioAsyncReadCompatExt;
 
+    #[tokio::test]
+    async fn test_directory_manifest() {
+        let payload = Payload::Dir("tests/data/test_dir".to_string());
+        let file = FileToSend::new(&PeerId::random(), payload).unwrap();
+        for version in ProtocolVersion::SUPPORTED {
+            let mut socket = Cursor::new(vec![]);
+            let (size, _) = Metadata::write(&file, &mut socket, version).await.unwrap();
+
+            socket.set_position(0);
+            let (meta, _) = Metadata::read(&mut socket, version).await.unwrap();
+            match meta.manifest {
+                Some(manifest) => {
+                    assert_eq!(manifest.total_size, size as u64);
+                    assert!(manifest.entries.contains(&"dir/".to_string()));
+                    assert!(manifest.entries.contains(&"test.odt".to_string()));
+                }
+                // Legacy packets have no room for it
+                None => assert!(version.is_legacy()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parallel_offer_and_range() {
+        let payload = Payload::File("tests/data/bench_1mb.bin".to_string());
+        let file = FileToSend::new(&PeerId::random(), payload).unwrap();
+        let parallelism = Parallelism {
+            streams: 2,
+            min_size: 1024,
+        };
+        for version in ProtocolVersion::SUPPORTED {
+            let mut socket = Cursor::new(vec![]);
+            let (size, _) = Metadata::write_parallel(&file, &mut socket, version, parallelism)
+                .await
+                .unwrap();
+            let range = split(file.id, size as u64, 2)[1];
+            Metadata::write_range(&file, &range, &mut socket, version)
+                .await
+                .unwrap();
+
+            socket.set_position(0);
+            let (meta, _) = Metadata::read(&mut socket, version).await.unwrap();
+            let (range_meta, _) = Metadata::read(&mut socket, version).await.unwrap();
+            assert_eq!(range_meta.range, Some(range));
+            assert_eq!(range_meta.size, size / 2);
+            match meta.parallel {
+                Some(offer) => assert_eq!(
+                    offer,
+                    ParallelOffer {
+                        key: file.id,
+                        streams: 2
+                    }
+                ),
+                // Legacy receivers don't know the offer
+                None => assert!(version.is_legacy()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packets_keep_zero_bytes() {
+        let mut socket = Cursor::new(vec![]);
+        let answer = Answer {
+            accepted: false,
+            offset: 256,
+            hash_algorithm: HashAlgorithm::Blake3,
+            compression: Compression::None,
+            reason: String::new(),
+            streams: 0,
+        };
+        let version = ProtocolVersion::V1_4;
+        answer.write(&mut socket, version).await.unwrap();
+        Trailer::new("abc".to_string(), HashAlgorithm::Md5)
+            .write(&mut socket, version)
+            .await
+            .unwrap();
+
+        socket.set_position(0);
+        let (received, _) = Answer::read(&mut socket, version).await.unwrap();
+        assert!(!received.accepted);
+        assert_eq!(received.offset, 256);
+        assert_eq!(received.hash_algorithm, HashAlgorithm::Blake3);
+
+        let trailer = Trailer::read(&mut socket, version).await.unwrap();
+        assert_eq!(trailer.hash, "abc");
+        assert_eq!(trailer.algorithm, HashAlgorithm::Md5);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_packets_are_padded() {
+        let mut socket = Cursor::new(vec![]);
+        let version = ProtocolVersion::V1_2;
+        let answer = Answer {
+            accepted: true,
+            ..Answer::denied()
+        };
+        answer.write(&mut socket, version).await.unwrap();
+        Trailer::new("abc".to_string(), HashAlgorithm::Md5)
+            .write(&mut socket, version)
+            .await
+            .unwrap();
+        assert_eq!(socket.get_ref().len(), 2 * LEGACY_PACKET_SIZE);
+
+        socket.set_position(0);
+        let (received, _) = Answer::read(&mut socket, version).await.unwrap();
+        assert!(received.accepted);
+        let trailer = Trailer::read(&mut socket, version).await.unwrap();
+        assert_eq!(trailer.hash, "abc");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_packet_ending_with_zero() {
+        let mut socket = Cursor::new(vec![]);
+        let version = ProtocolVersion::V1_2;
+        Trailer::new("abc\0".to_string(), HashAlgorithm::Md5)
+            .write(&mut socket, version)
+            .await
+            .unwrap();
+
+        socket.set_position(0);
+        let trailer = Trailer::read(&mut socket, version).await.unwrap();
+        assert_eq!(trailer.hash, "abc\0");
+    }
+
     #[tokio::test]
     #[cfg(not(target_os = "windows"))]
     async fn test_hash_local_file() {
@@ -228,7 +773,7 @@ mod tests {
         file.seek(SeekFrom::Start(0)).unwrap();
         let tokio_file = tokio::fs::File::from_std(file);
         let async_file = tokio_file.compat();
-        let (hash, size) = hash_contents(async_file).await.unwrap();
+        let (hash, size) = hash_contents(async_file, HashAlgorithm::Md5).await.unwrap();
 
         assert_eq!(hash, "a909b834a8f95194ee2ce975e38cec31".to_string());
         assert_eq!(size, 19);
@@ -243,9 +788,27 @@ mod tests {
         file.seek(SeekFrom::Start(0)).unwrap();
         let tokio_file = tokio::fs::File::from_std(file);
         let async_file = tokio_file.compat();
-        let (hash, size) = hash_contents(async_file).await.unwrap();
+        let (hash, size) = hash_contents(async_file, HashAlgorithm::Md5).await.unwrap();
 
         assert_eq!(hash, "a909b834a8f95194ee2ce975e38cec31".to_string());
         assert_eq!(size, 19);
     }
+
+    #[tokio::test]
+    async fn test_hash_local_file_sha256() {
+        let mut file = tempfile::tempfile().unwrap();
+        write!(file, "I'll fly to device!").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let tokio_file = tokio::fs::File::from_std(file);
+        let async_file = tokio_file.compat();
+        let (hash, size) = hash_contents(async_file, HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            hash,
+            "8b8de9ed7122ddb61b6481c46c009e757778b41d2986ea43d97415404c83dacd".to_string()
+        );
+        assert_eq!(size, 19);
+    }
 }
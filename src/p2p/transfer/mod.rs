@@ -1,13 +1,27 @@
 pub mod behaviour;
+pub mod chunks;
+pub mod compression;
+pub mod connections;
 pub mod directory;
 pub mod file;
+pub mod hash;
+pub mod limiter;
 pub mod metadata;
+pub mod partial;
 pub mod protocol;
+pub mod queue;
+pub mod ranges;
 pub mod reader;
+pub mod stall;
+pub mod validation;
+pub mod version;
 
 pub use behaviour::TransferBehaviour;
+pub use compression::Compression;
 pub use file::{FileToSend, Payload};
+pub use hash::HashAlgorithm;
 pub use protocol::{TransferOut, TransferPayload};
+pub use version::ProtocolVersion;
 
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/dragit.p2p.transfer.metadata.rs"));
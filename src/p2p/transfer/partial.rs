@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use crate::user_data;
+
+/// Partial files that incoming transfers write into, shared by all of them,
+/// so that two transfers of the same name never write into the same file.
+#[derive(Clone, Debug, Default)]
+pub struct PartialFiles {
+    paths: Arc<Mutex<HashSet<String>>>,
+}
+
+impl PartialFiles {
+    /// Reserve the partial file of `path` until the lock is dropped.
+    /// None when another transfer writes into it already.
+    pub fn lock(&self, path: &str) -> Option<PartialLock> {
+        let partial_path = user_data::get_partial_path(path);
+        if !self.paths.lock().unwrap().insert(partial_path.clone()) {
+            return None;
+        }
+        Some(PartialLock {
+            files: self.clone(),
+            partial_path,
+        })
+    }
+}
+
+/// Partial file reserved for a single transfer.
+#[derive(Debug)]
+pub struct PartialLock {
+    files: PartialFiles,
+    partial_path: String,
+}
+
+impl Drop for PartialLock {
+    fn drop(&mut self) {
+        self.files.paths.lock().unwrap().remove(&self.partial_path);
+    }
+}
+
+/// The file a partial file was received from is noted next to it,
+/// so that it's only resumed with data of the same file.
+pub fn get_source_path(path: &str) -> String {
+    format!("{}.source", user_data::get_partial_path(path))
+}
+
+/// Source of the partial file of `path`, None if it's not known.
+pub async fn read_source(path: &str) -> Option<String> {
+    tokio::fs::read_to_string(get_source_path(path)).await.ok()
+}
+
+/// Note the source of the partial file of `path`, None forgets it.
+pub async fn write_source(path: &str, source: Option<&str>) -> io::Result<()> {
+    match source {
+        Some(source) => tokio::fs::write(get_source_path(path), source).await,
+        None => forget_source(path),
+    }
+}
+
+/// Forget the source of the partial file of `path`, it's not resumed anymore.
+pub fn forget_source(path: &str) -> io::Result<()> {
+    match std::fs::remove_file(get_source_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Remove the partial file of `path` together with its source.
+pub fn remove(path: &str) -> io::Result<()> {
+    forget_source(path)?;
+    std::fs::remove_file(user_data::get_partial_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::transfer::partial::PartialFiles;
+
+    #[test]
+    fn test_partial_file_is_locked_once() {
+        let files = PartialFiles::default();
+
+        let lock = files.lock("/tmp/a-file.txt");
+        assert!(lock.is_some());
+        assert!(files.lock("/tmp/a-file.txt").is_none());
+        assert!(files.lock("/tmp/another-file.txt").is_some());
+
+        drop(lock);
+        assert!(files.lock("/tmp/a-file.txt").is_some());
+    }
+}
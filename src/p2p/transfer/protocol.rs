@@ -1,33 +1,91 @@
 use std::fmt;
-use std::fs::remove_file;
+use std::fs::{remove_file, rename};
 use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-
 use std::time::Instant;
-use std::{io, iter, pin::Pin};
+use std::{array, io, pin::Pin};
 
-use async_channel::{Receiver, Sender};
-use tokio::sync::Mutex;
+use async_channel::Sender;
 
+use futures::channel::mpsc::UnboundedSender;
 use futures::io as futio;
 use futures::prelude::*;
 use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::swarm::StreamUpgradeError;
+use libp2p::PeerId;
 use tokio::fs::OpenOptions;
+use tokio::io::AsyncSeekExt;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tokio_util::sync::CancellationToken;
 
-use crate::p2p::commands::TransferCommand;
-use crate::p2p::peer::{Direction, PeerEvent};
-use crate::p2p::transfer::directory::untar_stream;
-use crate::p2p::transfer::file::{FileToSend, Payload, StreamOption};
-use crate::p2p::transfer::metadata::{Answer, Metadata, Trailer};
-use crate::p2p::transfer::reader::{HashingReader, ProgressReader};
+use crate::p2p::commands::{CommandRouter, TransferCommand};
+use crate::p2p::discovery::handler::OutboundFailure;
+use crate::p2p::peer::{Direction, IncomingFile, PeerEvent, PeerTrust, TransferId};
+use crate::p2p::transfer::chunks::{CancelSignal, Cancelled};
+use crate::p2p::transfer::compression::{copy_compressed, copy_raw, Compression, DataReader};
+use crate::p2p::transfer::directory::{untar_stream, EntryProgress};
+use crate::p2p::transfer::file::{FileToSend, Manifest, Payload, StreamOption};
+use crate::p2p::transfer::hash::{HashAlgorithm, HashMismatch};
+use crate::p2p::transfer::limiter::{Bandwidth, ThrottledReader};
+use crate::p2p::transfer::metadata::{hash_contents, Answer, Cancel, Metadata, Trailer};
+use crate::p2p::transfer::partial::{self, PartialFiles};
+use crate::p2p::transfer::ranges::{
+    self, FileRange, IncomingRanges, OutgoingRange, Parallelism, RangeRegistry, MAX_STREAMS,
+};
+use crate::p2p::transfer::reader::{CountingReader, HashingReader, ProgressReader, ProgressSpan};
+use crate::p2p::transfer::stall::{StallGuard, Stalled};
+use crate::p2p::transfer::validation::{self, Refused};
+use crate::p2p::transfer::version::ProtocolVersion;
 use crate::p2p::util::{self, TSocketAlias};
 use crate::p2p::TransferType;
-use crate::user_data;
+use crate::user_data::{self, ConflictPolicy, PreservedAttributes, Timeouts};
+
+const RECEIVER_CANCELLED: &str = "Receiver cancelled the transfer";
+const SENDER_CANCELLED: &str = "Sender cancelled the transfer";
 
 #[derive(Clone, Debug)]
 pub enum ProtocolEvent {
     Received(TransferPayload),
-    Sent,
+    Sent(ProtocolVersion),
+    /// A range of the file of the transfer arrived,
+    /// which reports the whole file once complete
+    RangeReceived(TransferId),
+    RangeSent(TransferId),
+    /// The substream of the outgoing transfer failed
+    OutboundFailed(TransferOut, OutboundError),
+}
+
+/// Why the substream of an outgoing transfer failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutboundError {
+    /// The substream didn't open in time
+    Timeout,
+    /// The receiver supports none of our protocol versions
+    NegotiationFailed,
+    /// The connection failed while the substream was opened
+    Io(ErrorKind),
+    /// The transfer failed after it started
+    Transfer(ErrorKind, String),
+}
+
+impl OutboundError {
+    /// The transfer didn't start, so it may succeed over another connection
+    pub fn unopened(&self) -> bool {
+        !matches!(self, OutboundError::Transfer(..))
+    }
+
+    pub fn reason(&self) -> String {
+        match self {
+            OutboundError::Timeout => "Could not open a stream to the receiver in time".to_string(),
+            OutboundError::NegotiationFailed => {
+                "The receiver doesn't support this version of the transfer protocol".to_string()
+            }
+            OutboundError::Io(kind) => format!("Could not open a stream to the receiver: {}", kind),
+            OutboundError::Transfer(_, reason) => reason.to_string(),
+        }
+    }
 }
 
 // Outgoing transfer to remote peer
@@ -35,18 +93,91 @@ pub enum ProtocolEvent {
 pub struct TransferOut {
     pub file: FileToSend,
     pub sender_queue: Sender<PeerEvent>,
+    pub commands: CommandRouter,
+    pub bandwidth: Bandwidth,
+    pub timeouts: Timeouts,
+    pub parallelism: Parallelism,
+    /// Queue of the behaviour, which opens a substream for every range
+    pub range_requests: UnboundedSender<TransferOut>,
+    /// Set when only a range of the file is sent
+    pub range: Option<OutgoingRange>,
+    /// Tells the behaviour that the transfer ended, so that the next one can start
+    pub finished: UnboundedSender<TransferId>,
+    /// Largest payload the receiver advertised it accepts
+    pub receiver_max_size: Option<u64>,
+}
+
+/// How the data of a single item is sent, as agreed in the Answer.
+#[derive(Clone, Copy, Debug)]
+struct StreamFormat {
+    hash_algorithm: HashAlgorithm,
+    compression: Compression,
+    version: ProtocolVersion,
+    /// Substreams the ranges of the file come over, 0 when it follows the Answer
+    streams: usize,
+}
+
+impl StreamFormat {
+    fn agreed(answer: &Answer, version: ProtocolVersion) -> Self {
+        StreamFormat {
+            hash_algorithm: answer.hash_algorithm,
+            compression: answer.compression,
+            version,
+            streams: answer.streams,
+        }
+    }
+
+    /// Reader of the data that follows, which the legacy protocol sends raw.
+    fn reader<R: AsyncRead + Unpin>(&self, inner: R) -> DataReader<R> {
+        match self.version {
+            ProtocolVersion::V1_2 => DataReader::raw(inner),
+            _ => DataReader::new(inner, self.compression),
+        }
+    }
+
+    /// Format of a batch item. Items that are compressed already
+    /// are sent as they are, even if the batch is compressed.
+    fn for_item(self, offered: &[Compression]) -> Self {
+        let compression = match self.compression {
+            Compression::None => Compression::None,
+            _ => Compression::negotiate(offered),
+        };
+        StreamFormat {
+            compression,
+            ..self
+        }
+    }
 }
 
 // Incoming transfer to current host
 #[derive(Clone, Debug)]
 pub struct TransferPayload {
+    pub id: TransferId,
+    /// Sender of the payload
+    pub peer: PeerId,
     pub name: String,
     pub payload: Payload,
     pub hash: String,
     pub size_bytes: usize,
     pub sender_queue: Sender<PeerEvent>,
-    pub receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+    pub commands: CommandRouter,
     pub target_path: Option<String>,
+    pub conflict_policy: ConflictPolicy,
+    pub preserved: PreservedAttributes,
+    pub bandwidth: Bandwidth,
+    pub timeouts: Timeouts,
+    /// Larger payloads are refused, None accepts any
+    pub max_size: Option<u64>,
+    /// Transfers that wait for the ranges of their files
+    pub ranges: RangeRegistry,
+    /// Partial files being written into
+    pub partials: PartialFiles,
+    /// Whether the sender is a paired device
+    pub peer_trust: PeerTrust,
+    /// Set when only a range of the file of that transfer was received
+    pub part_of: Option<TransferId>,
+    /// Version negotiated with the sender
+    pub version: ProtocolVersion,
 }
 
 impl TransferPayload {
@@ -63,81 +194,169 @@ impl TransferPayload {
         Ok(())
     }
 
-    async fn notify_incoming_file_event(&self, meta: &Metadata) {
-        let name = meta.name.to_string();
-        let size = meta.size;
-        let transfer_type = meta.transfer_type;
-        let event = PeerEvent::FileIncoming(name, String::new(), size, transfer_type);
+    async fn notify_incoming_file_event(&self, meta: &Metadata, ask_about_name: bool) {
+        let event = PeerEvent::FileIncoming(IncomingFile {
+            id: self.id,
+            peer: self.peer,
+            trust: self.peer_trust.get(&self.peer),
+            name: meta.name.to_string(),
+            size: meta.size,
+            transfer_type: meta.transfer_type,
+            name_taken: ask_about_name,
+            mime_type: meta.mime_type.to_string(),
+            manifest: meta.manifest.clone(),
+        });
         util::notify(&self.sender_queue, event).await;
     }
 
-    async fn block_for_answer(
-        &self,
-        receiver: Arc<Mutex<Receiver<TransferCommand>>>,
-    ) -> TransferCommand {
-        let r = receiver.lock().await;
-        // Wait for the user to confirm the incoming file
-        loop {
-            match r.recv().await {
-                Ok(choice) => {
-                    info!("Got the choice: {:?}", choice);
-                    return choice;
-                }
-                Err(_) => {
-                    info!("Receiver closed, retrying...");
-                    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
-                }
+    /// Wait for the user to confirm or reject this particular transfer.
+    async fn block_for_answer(&self) -> TransferCommand {
+        self.commands.wait_for(self.id).await
+    }
+
+    /// Number of bytes already received for `meta` by an earlier, interrupted
+    /// transfer into `path`. Zero means that the transfer starts from scratch.
+    /// The partial file has to come from the same file of the same peer.
+    async fn resume_offset(&self, meta: &Metadata, path: &str) -> u64 {
+        if !meta.resumable {
+            return 0;
+        }
+        if partial::read_source(path).await != Some(meta.source(&self.peer)) {
+            return 0;
+        }
+        let partial_path = user_data::get_partial_path(path);
+        match tokio::fs::metadata(&partial_path).await {
+            Ok(partial) if partial.len() < meta.size as u64 => {
+                info!("Found {} bytes of {}", partial.len(), partial_path);
+                partial.len()
             }
+            _ => 0,
         }
     }
 
-    /// Stream file data from `reader` into `path`, computing an MD5 hash
+    /// Stream file data from `reader` into `path`, computing the negotiated hash
     /// in-flight, then read the sender's trailer and verify the hash matches.
+    /// Data is written into a partial file first, so that an interrupted transfer
+    /// can continue at `offset` next time, unless it was cancelled.
     /// Returns the number of bytes written.
     async fn stream_file(
         &mut self,
         path: &str,
-        mut socket: impl TSocketAlias,
+        socket: &mut (impl AsyncRead + Unpin),
         size: usize,
-        direction: &Direction,
+        offset: u64,
+        span: &ProgressSpan,
+        format: StreamFormat,
     ) -> Result<usize, io::Error> {
         info!("Path: {}", path);
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path)
-            .await
-            .expect("Opening failed!");
+        let partial_path = user_data::get_partial_path(path);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true);
+        if offset > 0 {
+            options.append(true);
+        } else {
+            options.truncate(true);
+        }
+        let file = options.open(&partial_path).await?;
 
         // Wrap tokio file as futures AsyncWrite, then buffer writes to disk
-        use tokio_util::compat::TokioAsyncWriteCompatExt;
         let mut buf_file = futio::BufWriter::new(file.compat_write());
 
-        // .take(size) bounds the copy to exactly the file bytes so that the
+        // .take() bounds the copy to exactly the file bytes so that the
         // socket is not read past the end of the data into the trailer region.
+        // The data is decompressed first, so that hashing and progress
+        // count the bytes of the file rather than those on the wire.
         // HashingReader observes every byte in that bounded window.
         // ProgressReader is stacked on top so all three run in one copy pass.
-        let bounded = (&mut socket).take(size as u64);
-        let hashing = HashingReader::new(bounded);
-        let mut progress_reader =
-            ProgressReader::new(hashing, size, self.sender_queue.clone(), direction.clone());
+        let algorithm = format.hash_algorithm;
+        let mut data = format.reader(&mut *socket);
+        let bounded = (&mut data).take(size as u64 - offset);
+        let mut hashing = HashingReader::new(bounded, algorithm);
+        if offset > 0 {
+            // The sender hashes the whole file, so the data we already have
+            // has to go into the digest before the rest arrives.
+            let existing = tokio::fs::File::open(&partial_path).await?;
+            hashing.absorb(existing.compat().take(offset)).await?;
+        }
+        let progress_reader = ProgressReader::new(
+            hashing,
+            self.id,
+            span.total,
+            self.sender_queue.clone(),
+            span.direction.clone(),
+        )
+        .resume_at(span.start + offset as usize);
+        let mut throttled = ThrottledReader::new(
+            progress_reader,
+            self.bandwidth.clone(),
+            self.peer,
+            span.direction.clone(),
+        );
 
-        let counter = futio::copy(&mut progress_reader, &mut buf_file).await?;
+        let copied = tokio::select! {
+            biased;
+            _ = self.commands.cancelled(self.id) => {
+                Err(io::Error::from(Cancelled::local(RECEIVER_CANCELLED)))
+            }
+            copied = futio::copy(&mut throttled, &mut buf_file) => copied,
+        };
+        let copied = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                if Cancelled::find(&e).is_some() {
+                    // Cancelled transfers are not resumed, so the data is of no use
+                    drop(buf_file);
+                    partial::remove(path)?;
+                }
+                return Err(e);
+            }
+        };
         buf_file.close().await?;
 
-        util::notify_progress(&self.sender_queue, counter as usize, size, direction, None).await;
+        let counter = (offset + copied) as usize;
+        if counter < size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Connection closed after {} of {} bytes", counter, size),
+            ));
+        }
+
+        util::notify_progress(
+            &self.sender_queue,
+            self.id,
+            span.start + counter,
+            span.total,
+            &span.direction,
+            None,
+        )
+        .await;
 
         // Recover the HashingReader from inside the ProgressReader, then
         // unwrap the Take to finalise the digest.
-        let local_hash = progress_reader.into_inner().finish();
+        let local_hash = throttled.into_inner().into_inner().finish();
         info!("Computed local hash: {}", local_hash);
+        data.finish().await?;
 
         // The sender writes a fixed-size trailer packet right after the data.
         // The socket is still open and positioned right at the trailer now.
-        let sender_hash = Trailer::read(&mut socket).await?;
-        info!("Received sender hash: {}", sender_hash);
+        let trailer = Trailer::read(socket, format.version).await?;
+        let (sender_hash, sender_algorithm) = (trailer.hash, trailer.algorithm);
+        info!("Received sender {} hash: {}", sender_algorithm, sender_hash);
+
+        if sender_algorithm != algorithm {
+            partial::remove(path)?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Hash algorithm mismatch: agreed on {}, got {}",
+                    algorithm, sender_algorithm
+                ),
+            ));
+        }
 
         if local_hash != sender_hash {
+            // Don't try to resume from corrupted data next time.
+            partial::remove(path)?;
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
                 format!(
@@ -147,68 +366,640 @@ impl TransferPayload {
             ));
         }
 
-        Ok(counter as usize)
+        // Renaming replaces a file, but not a directory
+        if Path::new(path).is_dir() {
+            user_data::remove_existing(path)?;
+        }
+        rename(&partial_path, path)?;
+        partial::forget_source(path)?;
+        Ok(counter)
     }
 
-    async fn stream_dir(
+    /// Preallocate the partial file for the ranges of the file offered under `key`,
+    /// so that they can be written in any order, and register it for the ranges.
+    async fn expect_ranges(
         &self,
-        path: String,
-        reader: futures::io::BufReader<impl TSocketAlias + 'static>,
+        key: u64,
+        path: &str,
         size: usize,
-        direction: &Direction,
+        answer: &Answer,
+    ) -> Result<(), io::Error> {
+        let partial_path = user_data::get_partial_path(path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial_path)
+            .await?;
+        file.set_len(size as u64).await?;
+
+        let (done, results) = async_channel::unbounded();
+        let ranges = IncomingRanges {
+            id: self.id,
+            path: partial_path,
+            hash_algorithm: answer.hash_algorithm,
+            compression: answer.compression,
+            received: Arc::new(AtomicU64::new(0)),
+            done,
+            results,
+        };
+        self.ranges.insert(self.peer, key, ranges);
+        Ok(())
+    }
+
+    /// Wait until the ranges of the file arrive over their own substreams,
+    /// then read the trailer and verify the hash of the whole file.
+    /// The ranges were verified one by one already.
+    async fn stream_ranges(
+        &self,
+        path: &str,
+        socket: &mut (impl AsyncRead + Unpin),
+        meta: &Metadata,
+        span: &ProgressSpan,
+        format: StreamFormat,
     ) -> Result<usize, io::Error> {
-        let sender_copy = self.sender_queue.clone();
-        let task = untar_stream(path, reader, sender_copy, size, direction.clone()).await?;
-        let received_bytes = task.await??;
-        Ok(received_bytes)
+        let key = match meta.parallel {
+            Some(offer) => offer.key,
+            None => return Err(io::Error::new(ErrorKind::InvalidData, "No ranges offered")),
+        };
+        let ranges = self
+            .ranges
+            .get(self.peer, key)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Ranges are not expected"))?;
+        let count = ranges::split(key, meta.size as u64, format.streams).len();
+
+        let verified = async {
+            let waiting = ranges::wait_for_ranges(
+                &ranges.results,
+                count,
+                &ranges.received,
+                &self.sender_queue,
+                self.id,
+                span,
+            );
+            // The sender closes this substream if it fails before the ranges are done
+            let (_, trailer) =
+                future::try_join(waiting, Trailer::read(socket, format.version)).await?;
+            info!(
+                "Received sender {} hash: {}",
+                trailer.algorithm, trailer.hash
+            );
+
+            let file = tokio::fs::File::open(&ranges.path).await?;
+            let (local_hash, _) = hash_contents(file.compat(), format.hash_algorithm).await?;
+            if trailer.algorithm != format.hash_algorithm || trailer.hash != local_hash {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Hash mismatch: expected {} {}, got {}",
+                        trailer.algorithm, trailer.hash, local_hash
+                    ),
+                ));
+            }
+            Ok(())
+        };
+        let result = tokio::select! {
+            biased;
+            _ = self.commands.cancelled(self.id) => {
+                Err(io::Error::from(Cancelled::local(RECEIVER_CANCELLED)))
+            }
+            verified = verified => verified,
+        };
+        self.ranges.remove(self.peer, key);
+        if let Err(e) = result {
+            remove_file(&ranges.path)?;
+            return Err(e);
+        }
+
+        util::notify_progress(
+            &self.sender_queue,
+            self.id,
+            span.start + meta.size,
+            span.total,
+            &span.direction,
+            None,
+        )
+        .await;
+        if Path::new(path).is_dir() {
+            user_data::remove_existing(path)?;
+        }
+        rename(&ranges.path, path)?;
+        Ok(meta.size)
     }
 
-    async fn read_file_payload(
+    /// Write `range` of a file accepted over another substream into its place
+    /// in the partial file, and report the result to the transfer of the file.
+    async fn read_range(
         &mut self,
-        socket: impl TSocketAlias + 'static,
-        meta: &Metadata,
+        range: FileRange,
+        mut socket: impl TSocketAlias,
+    ) -> Result<(), io::Error> {
+        let ranges = match self.ranges.get(self.peer, range.key) {
+            Some(ranges) => ranges,
+            None => {
+                warn!("Unexpected range of file {} from {}", range.key, self.peer);
+                Answer::denied().write(&mut socket, self.version).await?;
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Unexpected range",
+                ));
+            }
+        };
+        self.id = ranges.id;
+        self.part_of = Some(ranges.id);
+        let answer = Answer {
+            accepted: true,
+            hash_algorithm: ranges.hash_algorithm,
+            compression: ranges.compression,
+            ..Answer::denied()
+        };
+        answer.write(&mut socket, self.version).await?;
+        let format = StreamFormat::agreed(&answer, self.version);
+        let mut socket = StallGuard::new(socket, self.timeouts.stall);
+
+        let result = self
+            .receive_range(&ranges, range, &mut socket, format)
+            .await;
+        let failed = result
+            .as_ref()
+            .err()
+            .map(|e| io::Error::new(e.kind(), e.to_string()));
+        // Nobody waits for the result anymore if the transfer failed already
+        let _ = ranges.done.send(result).await;
+        failed.map_or(Ok(()), Err)
+    }
+
+    async fn receive_range(
+        &self,
+        ranges: &IncomingRanges,
+        range: FileRange,
+        socket: &mut (impl AsyncRead + Unpin),
+        format: StreamFormat,
+    ) -> Result<(), io::Error> {
+        let mut file = OpenOptions::new().write(true).open(&ranges.path).await?;
+        file.seek(io::SeekFrom::Start(range.offset)).await?;
+        let mut buf_file = futio::BufWriter::new(file.compat_write());
+
+        let mut data = format.reader(&mut *socket);
+        let bounded = (&mut data).take(range.length);
+        let hashing = HashingReader::new(bounded, format.hash_algorithm);
+        let counting = CountingReader::new(hashing, ranges.received.clone());
+        let mut throttled = ThrottledReader::new(
+            counting,
+            self.bandwidth.clone(),
+            self.peer,
+            Direction::Incoming,
+        );
+        let copied = futio::copy(&mut throttled, &mut buf_file).await?;
+        buf_file.close().await?;
+        if copied < range.length {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "Range at byte {} closed after {} of {} bytes",
+                    range.offset, copied, range.length
+                ),
+            ));
+        }
+        let local_hash = throttled.into_inner().into_inner().finish();
+        data.finish().await?;
+
+        let trailer = Trailer::read(socket, format.version).await?;
+        if trailer.algorithm != format.hash_algorithm || trailer.hash != local_hash {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Range at byte {} is incorrect", range.offset),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Unpack the archive, then read the trailer that follows it, so that
+    /// the returned socket is positioned at whatever the sender writes next.
+    /// The archive is unpacked next to `path` first, and moved there once complete.
+    /// The unpacked files are checked against the digests in the trailer,
+    /// and the whole archive against its hash.
+    async fn stream_dir<S: AsyncRead + Send + Unpin + 'static>(
+        &self,
+        path: String,
+        socket: S,
         size: usize,
-        direction: &Direction,
-    ) -> Result<(usize, String), io::Error> {
-        let path =
-            user_data::get_target_path(&meta.get_safe_file_name(), self.target_path.as_ref())?;
+        span: &ProgressSpan,
+        format: StreamFormat,
+        manifest: Option<&Manifest>,
+    ) -> Result<(usize, S), io::Error> {
+        let partial_path = user_data::get_partial_path(&path);
+        user_data::remove_existing(&partial_path)?;
+        tokio::fs::create_dir_all(&partial_path).await?;
+
+        let progress =
+            EntryProgress::new(self.id, self.sender_queue.clone(), span.direction.clone())
+                .with_totals(manifest);
+        let reader = ThrottledReader::new(
+            HashingReader::new(format.reader(socket), format.hash_algorithm),
+            self.bandwidth.clone(),
+            self.peer,
+            span.direction.clone(),
+        );
+        let mut task = untar_stream(
+            partial_path.clone(),
+            reader,
+            size,
+            span.clone(),
+            format.hash_algorithm,
+            progress,
+        )
+        .await?;
+        let unpacked = tokio::select! {
+            biased;
+            _ = self.commands.cancelled(self.id) => {
+                task.abort();
+                let _ = (&mut task).await;
+                Err(io::Error::from(Cancelled::local(RECEIVER_CANCELLED)))
+            }
+            joined = &mut task => joined?,
+        };
+        let (received_bytes, digests, reader) = match unpacked {
+            Ok(unpacked) => unpacked,
+            Err(e) => {
+                // Directories are not resumed
+                user_data::remove_existing(&partial_path)?;
+                return Err(e);
+            }
+        };
+        let (local_hash, reader) = reader.into_inner().finish_with_inner();
+        let mut socket = reader.finish().await?;
+
+        if format.version.is_legacy() {
+            // The end of the raw archive is unknown, so the trailer can't be found.
+            // Older builds don't verify directories either.
+            info!("Archive from a {} peer is not verified", format.version);
+        } else {
+            let trailer = Trailer::read(&mut socket, format.version).await?;
+            info!(
+                "Received archive {} hash: {}, computed: {}",
+                trailer.algorithm, trailer.hash, local_hash
+            );
+            if trailer.algorithm != format.hash_algorithm {
+                user_data::remove_existing(&partial_path)?;
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Hash algorithm mismatch: agreed on {}, got {}",
+                        format.hash_algorithm, trailer.algorithm
+                    ),
+                ));
+            }
+
+            let mut files = HashMismatch::compare(&trailer.files, &digests);
+            if files.is_empty() && trailer.hash != local_hash {
+                // Older builds send no digests of the files
+                let name = Path::new(&path).file_name().unwrap_or_default();
+                files.push(name.to_string_lossy().to_string());
+            }
+            if !files.is_empty() {
+                user_data::remove_existing(&partial_path)?;
+                return Err(HashMismatch { files }.into());
+            }
+        }
+
+        // The archive holds a single directory, named as on the sender's side
+        let mut entries = std::fs::read_dir(&partial_path)?;
+        let unpacked = match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry?.path(),
+            _ => {
+                user_data::remove_existing(&partial_path)?;
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Archive should contain a single directory",
+                ));
+            }
+        };
+        user_data::remove_existing(&path)?;
+        rename(&unpacked, &path)?;
+        std::fs::remove_dir(&partial_path)?;
+        Ok((received_bytes, socket))
+    }
 
-        let counter = match meta.transfer_type {
-            TransferType::File => self.stream_file(&path, socket, size, direction).await?,
-            TransferType::Text => self.stream_file(&path, socket, size, direction).await?,
+    async fn read_file_payload<S: AsyncRead + Send + Unpin + 'static>(
+        &mut self,
+        mut socket: S,
+        meta: &Metadata,
+        path: &str,
+        offset: u64,
+        span: &ProgressSpan,
+        format: StreamFormat,
+    ) -> Result<(usize, S), io::Error> {
+        let size = meta.size;
+        match meta.transfer_type {
+            TransferType::File | TransferType::Text => {
+                if offset == 0 {
+                    // Anything left in the partial file is overwritten.
+                    // Ranges arrive in any order, so they are never resumed.
+                    let resumable = meta.resumable && format.streams == 0;
+                    let source = resumable.then(|| meta.source(&self.peer));
+                    partial::write_source(path, source.as_deref()).await?;
+                }
+                let counter = match format.streams {
+                    0 => {
+                        self.stream_file(path, &mut socket, size, offset, span, format)
+                            .await?
+                    }
+                    _ => {
+                        self.stream_ranges(path, &mut socket, meta, span, format)
+                            .await?
+                    }
+                };
+                if meta.transfer_type == TransferType::File {
+                    // The file is complete already, so it's kept anyway
+                    if let Err(e) = meta.apply_attributes(path, self.preserved) {
+                        warn!("Could not apply attributes to {}: {:?}", path, e);
+                    }
+                }
+                Ok((counter, socket))
+            }
             TransferType::Dir => {
-                let reader = futures::io::BufReader::new(socket);
-                self.stream_dir(path.clone(), reader, size, direction)
-                    .await?
+                let manifest = meta.manifest.as_ref();
+                self.stream_dir(path.to_string(), socket, size, span, format, manifest)
+                    .await
+            }
+            TransferType::Batch => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Batches can't be nested",
+            )),
+        }
+    }
+
+    /// Receive the items of a batch one after another. Each of them is
+    /// announced with its own metadata packet, but shares the batch progress.
+    /// Items whose names are taken are resolved with `policy`.
+    async fn read_batch(
+        &mut self,
+        mut socket: impl AsyncRead + Send + Unpin + 'static,
+        meta: &Metadata,
+        direction: &Direction,
+        format: StreamFormat,
+        policy: ConflictPolicy,
+    ) -> Result<(usize, Payload), io::Error> {
+        if format.version.is_legacy() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Batches can't be sent with {}", format.version),
+            ));
+        }
+        let mut counter = 0;
+        let mut items = Vec::with_capacity(meta.item_count);
+        for _ in 0..meta.item_count {
+            let (item, _) = Metadata::read(&mut socket, format.version).await?;
+            info!("Batch item received! \n{}", item);
+            if !matches!(item.transfer_type, TransferType::File | TransferType::Dir) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected batch item: {}", item.transfer_type),
+                ));
             }
+
+            validation::check_name(&item.get_safe_file_name())?;
+            // The total was checked against the limit, the items have to fit in it
+            let remaining = meta.size.saturating_sub(counter) as u64;
+            validation::check_size(item.size, Some(remaining))?;
+            let item_format = format.for_item(&item.compressions);
+            let target = self.target_path.as_ref();
+            let path = match user_data::get_target_path(&item.get_safe_file_name(), target, policy)?
+            {
+                Some(path) => path,
+                None => {
+                    info!("Skipping {}, the name is taken", item.name);
+                    let mut data = item_format.reader(&mut socket);
+                    futio::copy(&mut data, &mut futio::sink()).await?;
+                    data.finish().await?;
+                    Trailer::read(&mut socket, format.version).await?;
+                    counter += item.size;
+                    util::notify(&self.sender_queue, PeerEvent::FileSkipped(item.name)).await;
+                    continue;
+                }
+            };
+            let span = ProgressSpan {
+                direction: direction.clone(),
+                start: counter,
+                total: meta.size,
+            };
+            let Some(_partial) = self.partials.lock(&path) else {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} is being received already", item.name),
+                ));
+            };
+            let (received, rest) = self
+                .read_file_payload(socket, &item, &path, 0, &span, item_format)
+                .await?;
+            socket = rest;
+            counter += received;
+            items.push(Payload::new(item.transfer_type, path)?);
+        }
+        Ok((counter, Payload::Batch(items)))
+    }
+
+    /// Turn down a payload whose name is taken, without asking the user.
+    async fn skip(
+        &self,
+        meta: &Metadata,
+        mut socket: impl AsyncWrite + Unpin,
+    ) -> Result<(), io::Error> {
+        info!("Skipping {}, the name is taken", meta.name);
+        Answer::denied().write(&mut socket, self.version).await?;
+        let event = PeerEvent::FileSkipped(meta.name.to_string());
+        util::notify(&self.sender_queue, event).await;
+        Err(io::Error::new(ErrorKind::AlreadyExists, "Skipped"))
+    }
+
+    /// Turn down a payload that is unsafe to write, telling the sender why.
+    async fn refuse(
+        &self,
+        meta: &Metadata,
+        mut socket: impl AsyncWrite + Unpin,
+        refused: Refused,
+    ) -> Result<(), io::Error> {
+        warn!("Refusing transfer {}: {}", self.id, refused);
+        Answer::refused(&refused.reason)
+            .write(&mut socket, self.version)
+            .await?;
+        self.notify_refused(meta, &refused).await;
+        Err(refused.into())
+    }
+
+    /// Turn down a payload whose partial file is written by another transfer.
+    async fn refuse_busy(
+        &self,
+        meta: &Metadata,
+        mut socket: impl AsyncWrite + Unpin,
+    ) -> Result<(), io::Error> {
+        let reason = format!("{} is being received already", meta.name);
+        warn!("Refusing transfer {}: {}", self.id, reason);
+        Answer::refused(&reason)
+            .write(&mut socket, self.version)
+            .await?;
+        Err(io::Error::new(ErrorKind::AlreadyExists, reason))
+    }
+
+    async fn notify_refused(&self, meta: &Metadata, refused: &Refused) {
+        let event = PeerEvent::PayloadRefused {
+            id: self.id,
+            file_name: meta.name.to_string(),
+            reason: refused.reason.to_string(),
         };
+        util::notify(&self.sender_queue, event).await;
+    }
 
-        Ok((counter, path))
+    /// Tell the sender to stop after the transfer was accepted.
+    /// Senders on the legacy protocol only notice that the stream is closed.
+    async fn stop_sender(
+        writer: &mut (impl AsyncWrite + Unpin),
+        reason: &str,
+        refused: bool,
+        version: ProtocolVersion,
+    ) {
+        let sent = async {
+            if !version.is_legacy() {
+                Cancel::write(&mut *writer, reason, refused).await?;
+            }
+            writer.close().await
+        };
+        if let Err(e) = sent.await {
+            warn!("Could not notify the sender: {:?}", e);
+        }
     }
 
     async fn read_socket(&mut self, socket: impl TSocketAlias + 'static) -> Result<(), io::Error> {
         let direction = Direction::Incoming;
-        let (meta, mut socket) = Metadata::read(socket).await?;
-        info!("Meta received! \n{}", meta);
+        let (meta, mut socket) = Metadata::read(socket, self.version).await?;
+        if let Some(range) = meta.range {
+            return self.read_range(range, socket).await;
+        }
+        self.id = util::next_transfer_id();
+        info!(
+            "Meta received for transfer {} over {}! \n{}",
+            self.id, self.version, meta
+        );
 
-        self.notify_incoming_file_event(&meta).await;
-        let rec_cp = Arc::clone(&self.receiver);
+        // Names of batch items are known only once they arrive
+        let name = meta.get_safe_file_name();
+        let target = self.target_path.as_ref();
+        if let Err(refused) = validation::check_name(&name) {
+            return self.refuse(&meta, socket, refused).await;
+        }
+        if let Err(refused) = validation::check_size(meta.size, self.max_size) {
+            return self.refuse(&meta, socket, refused).await;
+        }
+        let name_taken =
+            meta.transfer_type != TransferType::Batch && user_data::is_name_taken(&name, target)?;
+        if name_taken && self.conflict_policy == ConflictPolicy::Skip {
+            return self.skip(&meta, socket).await;
+        }
+        let ask_about_name = name_taken && self.conflict_policy == ConflictPolicy::Ask;
+        self.notify_incoming_file_event(&meta, ask_about_name).await;
+
+        match self.block_for_answer().await {
+            command @ (TransferCommand::Accept(_) | TransferCommand::AcceptWith(..)) => {
+                let policy = command.conflict_policy().unwrap_or(self.conflict_policy);
+                let path = match user_data::get_target_path(&name, target, policy)? {
+                    Some(path) => path,
+                    None => return self.skip(&meta, socket).await,
+                };
+                // Held until the payload is written
+                let Some(_partial) = self.partials.lock(&path) else {
+                    return self.refuse_busy(&meta, socket).await;
+                };
+                let offset = self.resume_offset(&meta, &path).await;
+                // Files split into ranges are not resumed
+                let parallel = meta
+                    .parallel
+                    .filter(|_| offset == 0 && meta.transfer_type == TransferType::File);
+                let answer = Answer {
+                    accepted: true,
+                    offset,
+                    hash_algorithm: HashAlgorithm::negotiate(&meta.hash_algorithms),
+                    compression: Compression::negotiate(&meta.compressions),
+                    reason: String::new(),
+                    streams: parallel.map_or(0, |offer| offer.streams.min(MAX_STREAMS)),
+                };
+                if let Some(offer) = parallel {
+                    self.expect_ranges(offer.key, &path, meta.size, &answer)
+                        .await?;
+                }
+                info!("Answering transfer {}: {:?}", self.id, answer);
+                answer.write(&mut socket, self.version).await?;
+                let format = StreamFormat::agreed(&answer, self.version);
 
-        match self.block_for_answer(rec_cp).await {
-            TransferCommand::Accept(hash) => {
-                Answer::write(&mut socket, true, hash).await?;
+                // The data comes in on the read half, while the write half
+                // stays free to tell the sender about a cancellation.
+                // Ranges bring the data over other substreams, this one stays idle meanwhile.
+                let (socket, mut writer) = socket.split();
+                let stall = self.timeouts.stall.filter(|_| format.streams == 0);
+                let socket = StallGuard::new(socket, stall);
 
-                util::notify_progress(&self.sender_queue, 0, meta.size, &direction, None).await;
+                util::notify_progress(
+                    &self.sender_queue,
+                    self.id,
+                    offset as usize,
+                    meta.size,
+                    &direction,
+                    None,
+                )
+                .await;
 
-                let (counter, path) = match self
-                    .read_file_payload(socket, &meta, meta.size, &direction)
-                    .await
-                {
-                    Ok((counter, path)) => (counter, path),
+                let received = match meta.transfer_type {
+                    TransferType::Batch => {
+                        self.read_batch(socket, &meta, &direction, format, policy)
+                            .await
+                    }
+                    transfer_type => {
+                        let span = ProgressSpan::new(direction, meta.size);
+                        self.read_file_payload(socket, &meta, &path, offset, &span, format)
+                            .await
+                            .and_then(|(counter, _)| {
+                                Ok((counter, Payload::new(transfer_type, path.clone())?))
+                            })
+                    }
+                };
+                let (counter, payload) = match received {
+                    Ok(received) => received,
                     Err(err) => {
                         error!("Reading payload failed: {:?}", err);
-                        if err.kind() == ErrorKind::InvalidData {
-                            util::notify(&self.sender_queue, PeerEvent::FileIncorrect).await;
+                        if let Some(refused) = Refused::find(&err) {
+                            Self::stop_sender(&mut writer, &refused.reason, true, self.version)
+                                .await;
+                            self.notify_refused(&meta, refused).await;
+                        } else if let Some(cancelled) = Cancelled::find(&err) {
+                            if !cancelled.by_remote {
+                                Self::stop_sender(
+                                    &mut writer,
+                                    &cancelled.reason,
+                                    false,
+                                    self.version,
+                                )
+                                .await;
+                            }
+                            util::notify_cancelled(
+                                &self.sender_queue,
+                                self.id,
+                                &meta.name,
+                                cancelled,
+                            )
+                            .await;
+                        } else if let Some(stalled) = Stalled::find(&err) {
+                            let event = PeerEvent::TransferFailed {
+                                id: self.id,
+                                file_name: meta.name.to_string(),
+                                reason: stalled.to_string(),
+                            };
+                            util::notify(&self.sender_queue, event).await;
+                        } else if let Some(mismatch) = HashMismatch::find(&err) {
+                            let files = mismatch.files.clone();
+                            util::notify(&self.sender_queue, PeerEvent::FileIncorrect(files)).await;
+                        } else if err.kind() == ErrorKind::InvalidData {
+                            let files = vec![meta.name.to_string()];
+                            util::notify(&self.sender_queue, PeerEvent::FileIncorrect(files)).await;
                         } else {
                             util::notify_error(&self.sender_queue, "Reading payload failed").await;
                         }
@@ -220,7 +1011,7 @@ impl TransferPayload {
                 // hash is now verified in-flight; store an empty sentinel so
                 // the field stays populated for Display / callers that read it.
                 self.hash = String::new();
-                self.payload = Payload::new(meta.transfer_type, path.clone())?;
+                self.payload = payload;
                 self.size_bytes = counter;
 
                 // TransferPayload needs to know where is the actual file after successful transfer
@@ -228,9 +1019,10 @@ impl TransferPayload {
 
                 Ok(())
             }
-            TransferCommand::Deny(hash) => {
-                warn!("Denied hash: {}", hash);
-                Answer::write(&mut socket, false, hash).await?;
+            // Cancelling before the answer is the same as rejecting
+            TransferCommand::Deny(_) | TransferCommand::Cancel(_) => {
+                warn!("Denied transfer: {}", self.id);
+                Answer::denied().write(&mut socket, self.version).await?;
                 Err(io::Error::new(ErrorKind::PermissionDenied, "Rejected"))
             }
         }
@@ -238,94 +1030,475 @@ impl TransferPayload {
 }
 
 impl UpgradeInfo for TransferPayload {
-    type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type Info = ProtocolVersion;
+    type InfoIter = array::IntoIter<Self::Info, 2>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once("/transfer/1.2")
+        ProtocolVersion::SUPPORTED.into_iter()
     }
 }
 
 impl TransferOut {
-    async fn write_socket(&self, socket: impl TSocketAlias) -> Result<(), io::Error> {
+    async fn write_socket(
+        &self,
+        mut socket: impl TSocketAlias,
+        version: ProtocolVersion,
+    ) -> Result<(), io::Error> {
+        if let Some(range) = &self.range {
+            return self.write_range(socket, range, version).await;
+        }
         let direction = Direction::Outgoing;
-        info!("File to send: {}", self.file);
+        info!("File to send over {}: {}", version, self.file);
+
+        if version.is_legacy() && self.file.transfer_type == TransferType::Batch {
+            warn!("Peer on {} can't receive batches", version);
+            let event = PeerEvent::TransferFailed {
+                id: self.file.id,
+                file_name: self.file.name.to_string(),
+                reason: "The receiver runs an older version, send the items one by one".to_string(),
+            };
+            util::notify(&self.sender_queue, event).await;
+            return Ok(());
+        }
+
+        let sized = self.file.get_size().await?;
+        // No need to offer what the receiver would refuse anyway
+        if let Some(max_size) = self.receiver_max_size {
+            if sized.0 > max_size {
+                warn!(
+                    "Receiver accepts at most {} bytes, got {}",
+                    max_size, sized.0
+                );
+                let event = PeerEvent::TransferFailed {
+                    id: self.file.id,
+                    file_name: self.file.name.to_string(),
+                    reason: format!(
+                        "The receiver accepts at most {} MiB",
+                        max_size / (1024 * 1024)
+                    ),
+                };
+                util::notify(&self.sender_queue, event).await;
+                return Ok(());
+            }
+        }
 
         util::notify_waiting(&self.sender_queue).await;
 
-        let (size, socket) = Metadata::write(&self.file, socket).await?;
+        let manifest = sized.1.clone();
+        let (size, _) =
+            Metadata::write_sized(&self.file, sized, &mut socket, version, self.parallelism)
+                .await?;
 
         // Check if remote is willing to accept our file
-        let (accepted, socket) = Answer::read(socket).await?;
-        info!("File accepted? {:?}", accepted);
+        let (answer, _) = self.wait_for_answer(&mut socket, version).await?;
+        info!("File accepted? {:?}", answer);
 
-        if accepted {
-            let result = match self.file.get_file_stream().await? {
-                StreamOption::File(file) => {
-                    self.stream_data(socket, file, size, direction).await?;
-                    Ok(())
+        if answer.accepted {
+            let span = ProgressSpan::new(direction, size);
+            let format = StreamFormat::agreed(&answer, version);
+            let (mut reader, writer) = socket.split();
+            let mut writer = StallGuard::new(writer, self.timeouts.stall);
+            let mut cancel = self.cancel_signal(&mut reader, version);
+            let result = match self.file.transfer_type {
+                TransferType::Batch => {
+                    self.write_batch(&mut writer, &span, format, &mut cancel)
+                        .await
                 }
-                StreamOption::Tar(file, task_handle) => {
-                    self.stream_data(socket, file, size, direction).await?;
-                    if let Some(handle) = task_handle {
-                        let _ = handle.await?;
-                    }
-                    Ok(())
+                TransferType::File if format.streams > 0 => {
+                    self.write_ranges(&mut writer, &span, format, &mut cancel)
+                        .await
+                }
+                _ => {
+                    self.write_item(
+                        (&self.file, manifest.as_ref()),
+                        &mut writer,
+                        answer.offset,
+                        &span,
+                        format,
+                        &mut cancel,
+                    )
+                    .await
                 }
             };
-            if let Err(ref e) = result {
-                error!("Stream data failed: {:?}", e);
-                util::notify_error(&self.sender_queue, "Transfer failed at the receiver end.")
-                    .await;
+            // Refusals and cancellations are reported here, the behaviour reports other errors
+            match result {
+                Ok(()) => util::notify_completed(&self.sender_queue).await,
+                Err(ref e) => match Cancelled::find(e) {
+                    Some(cancelled) if cancelled.refused => {
+                        warn!(
+                            "Receiver refused transfer {}: {}",
+                            self.file.id, cancelled.reason
+                        );
+                        self.notify_refused(&cancelled.reason).await;
+                    }
+                    Some(cancelled) => {
+                        info!("Transfer {} cancelled: {:?}", self.file.id, cancelled);
+                        util::notify_cancelled(
+                            &self.sender_queue,
+                            self.file.id,
+                            &self.file.name,
+                            cancelled,
+                        )
+                        .await;
+                    }
+                    None => {
+                        error!("Stream data failed: {:?}", e);
+                        return result;
+                    }
+                },
             }
-            result
+            Ok(())
+        } else if !answer.reason.is_empty() {
+            warn!(
+                "Receiver refused transfer {}: {}",
+                self.file.id, answer.reason
+            );
+            self.notify_refused(&answer.reason).await;
+            Ok(())
         } else {
             util::notify_rejected(&self.sender_queue).await;
             Ok(())
         }
     }
 
-    /// Stream `file` to `socket`, computing an MD5 hash in-flight, then send
-    /// a trailer packet containing the hash so the receiver can verify without
-    /// re-reading from disk.
-    async fn stream_data(
+    /// Read the answer, unless the receiving user takes longer than the answer timeout.
+    async fn wait_for_answer<S: AsyncRead + Unpin>(
+        &self,
+        socket: S,
+        version: ProtocolVersion,
+    ) -> Result<(Answer, S), io::Error> {
+        let timeout = match self.timeouts.answer {
+            Some(timeout) => timeout,
+            None => return Answer::read(socket, version).await,
+        };
+        match tokio::time::timeout(timeout, Answer::read(socket, version)).await {
+            Ok(answered) => answered,
+            Err(_) => {
+                let reason = format!(
+                    "The receiver didn't answer within {} seconds",
+                    timeout.as_secs()
+                );
+                Err(io::Error::new(ErrorKind::TimedOut, reason))
+            }
+        }
+    }
+
+    async fn notify_refused(&self, reason: &str) {
+        let event = PeerEvent::TransferFailed {
+            id: self.file.id,
+            file_name: self.file.name.to_string(),
+            reason: format!("Receiver refused it, {}", reason),
+        };
+        util::notify(&self.sender_queue, event).await;
+    }
+
+    /// Resolves when the user cancels the transfer, or when the receiver
+    /// sends its reason for cancelling on the otherwise idle `reader`.
+    /// Receivers on the legacy protocol never send it.
+    fn cancel_signal<'a>(
+        &'a self,
+        reader: &'a mut (impl AsyncRead + Send + Unpin),
+        version: ProtocolVersion,
+    ) -> CancelSignal<'a> {
+        Box::pin(async move {
+            tokio::select! {
+                _ = self.commands.cancelled(self.file.id) => Cancelled::local(SENDER_CANCELLED),
+                Ok((reason, refused)) = Cancel::read(reader), if !version.is_legacy() => Cancelled {
+                    refused,
+                    ..Cancelled::remote(reason)
+                },
+            }
+        })
+    }
+
+    /// Announce and stream every item of the batch. The receiver has
+    /// already accepted all of them with a single answer.
+    async fn write_batch(
+        &self,
+        socket: &mut (impl AsyncWrite + Unpin),
+        span: &ProgressSpan,
+        format: StreamFormat,
+        cancel: &mut CancelSignal<'_>,
+    ) -> Result<(), io::Error> {
+        let mut span = span.clone();
+        for item in self.file.batch_items()? {
+            let sized = item.get_size().await?;
+            let manifest = sized.1.clone();
+            let (size, _) = Metadata::write_sized(
+                &item,
+                sized,
+                &mut *socket,
+                format.version,
+                Parallelism::default(),
+            )
+            .await?;
+            let item_format = format.for_item(&Compression::offered_for(&item));
+            self.write_item(
+                (&item, manifest.as_ref()),
+                &mut *socket,
+                0,
+                &span,
+                item_format,
+                cancel,
+            )
+            .await?;
+            span.start += size;
+        }
+        Ok(())
+    }
+
+    /// Send the file in ranges over substreams of their own, which the behaviour
+    /// opens, then the hash of the whole file once all of them are done.
+    async fn write_ranges(
+        &self,
+        socket: &mut (impl AsyncWrite + Unpin),
+        span: &ProgressSpan,
+        format: StreamFormat,
+        cancel: &mut CancelSignal<'_>,
+    ) -> Result<(), io::Error> {
+        let path = match &self.file.payload {
+            Payload::File(path) => path,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Only files are sent in ranges",
+                ))
+            }
+        };
+        // Stops the ranges as well, however this ends
+        let stopped = CancellationToken::new();
+        let _stop = stopped.clone().drop_guard();
+        let (done, results) = async_channel::unbounded();
+        let sent = Arc::new(AtomicU64::new(0));
+
+        let ranges = ranges::split(self.file.id, span.total as u64, format.streams);
+        info!("Sending {} in {} ranges", self.file.name, ranges.len());
+        for range in ranges.iter() {
+            let transfer = TransferOut {
+                range: Some(OutgoingRange {
+                    range: *range,
+                    hash_algorithm: format.hash_algorithm,
+                    compression: format.compression,
+                    sent: sent.clone(),
+                    done: done.clone(),
+                    stopped: stopped.clone(),
+                }),
+                ..self.clone()
+            };
+            self.range_requests
+                .unbounded_send(transfer)
+                .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Could not open substreams"))?;
+        }
+        // The results close once every range is done
+        drop(done);
+
+        let hashed = async {
+            let file = tokio::fs::File::open(path).await?;
+            let (hash, _) = hash_contents(file.compat(), format.hash_algorithm).await?;
+            Ok(hash)
+        };
+        let waiting = ranges::wait_for_ranges(
+            &results,
+            ranges.len(),
+            &sent,
+            &self.sender_queue,
+            self.file.id,
+            span,
+        );
+        let hash = tokio::select! {
+            biased;
+            cancelled = cancel => {
+                // The ranges tell the receiver, before this substream closes
+                stopped.cancel();
+                while results.recv().await.is_ok() {}
+                return Err(cancelled.into());
+            }
+            hashed = future::try_join(hashed, waiting) => hashed?.0,
+        };
+        info!("Sending trailer hash: {}", hash);
+        Trailer::new(hash, format.hash_algorithm)
+            .write(socket, format.version)
+            .await
+    }
+
+    /// Send a range of the file, whose transfer was accepted over another substream,
+    /// and report the result to that transfer.
+    async fn write_range(
         &self,
         mut socket: impl TSocketAlias,
-        file: impl AsyncRead + Unpin,
-        size: usize,
-        direction: Direction,
+        range: &OutgoingRange,
+        version: ProtocolVersion,
+    ) -> Result<(), io::Error> {
+        let result = self.send_range(&mut socket, range, version).await;
+        if let Err(e) = &result {
+            warn!("Range at byte {} failed: {:?}", range.range.offset, e);
+        }
+        let failed = result
+            .as_ref()
+            .err()
+            .map(|e| io::Error::new(e.kind(), e.to_string()));
+        let _ = range.done.send(result).await;
+        failed.map_or(Ok(()), Err)
+    }
+
+    async fn send_range(
+        &self,
+        socket: &mut (impl AsyncRead + AsyncWrite + Send + Unpin),
+        range: &OutgoingRange,
+        version: ProtocolVersion,
     ) -> Result<(), io::Error> {
-        let mut writer = futio::BufWriter::new(&mut socket);
-        util::notify_progress(&self.sender_queue, 0, size, &direction, None).await;
+        Metadata::write_range(&self.file, &range.range, &mut *socket, version).await?;
+        let (answer, _) = Answer::read(&mut *socket, version).await?;
+        if !answer.accepted {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                "Receiver didn't expect the range",
+            ));
+        }
+
+        let mut socket = StallGuard::new(socket, self.timeouts.stall);
+        let stream = self
+            .file
+            .get_range_stream(range.range.offset, range.range.length)
+            .await?;
+        let hashing = HashingReader::new(stream, range.hash_algorithm);
+        let counting = CountingReader::new(hashing, range.sent.clone());
+        let mut reader = ThrottledReader::new(
+            counting,
+            self.bandwidth.clone(),
+            self.file.peer,
+            Direction::Outgoing,
+        );
+        let stopped = range.stopped.clone();
+        let mut cancel: CancelSignal = Box::pin(async move {
+            stopped.cancelled().await;
+            Cancelled::local(SENDER_CANCELLED)
+        });
+        copy_compressed(&mut reader, &mut socket, range.compression, &mut cancel).await?;
+
+        let hash = reader.into_inner().into_inner().finish();
+        Trailer::new(hash, range.hash_algorithm)
+            .write(&mut socket, version)
+            .await?;
+        socket.close().await
+    }
+
+    /// Stream `file`, skipping the first `offset` bytes the receiver already has.
+    /// They still go into the hash, which has to cover the whole file.
+    /// Directories come with the manifest from `FileToSend::get_size`.
+    async fn write_item(
+        &self,
+        (file, manifest): (&FileToSend, Option<&Manifest>),
+        socket: &mut (impl AsyncWrite + Unpin),
+        offset: u64,
+        span: &ProgressSpan,
+        format: StreamFormat,
+        cancel: &mut CancelSignal<'_>,
+    ) -> Result<(), io::Error> {
+        let progress = EntryProgress::new(
+            self.file.id,
+            self.sender_queue.clone(),
+            span.direction.clone(),
+        );
+        match file
+            .get_file_stream(offset, manifest, format.hash_algorithm, progress)
+            .await?
+        {
+            StreamOption::File(stream) => {
+                let mut hashing = HashingReader::new(stream, format.hash_algorithm);
+                if offset > 0 {
+                    info!("Resuming transfer at byte {}", offset);
+                    let prefix = file.get_prefix_stream(offset).await?;
+                    let hashed = hashing.absorb(prefix).await?;
+                    if hashed != offset {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            format!("File is shorter than the resume offset {}", offset),
+                        ));
+                    }
+                }
+                let hash = self
+                    .stream_data(socket, hashing, offset, span, format, cancel)
+                    .await?;
+                Trailer::new(hash, format.hash_algorithm)
+                    .write(socket, format.version)
+                    .await
+            }
+            StreamOption::Tar(stream, task_handle) => {
+                let hashing = HashingReader::new(stream, format.hash_algorithm);
+                let hash = self
+                    .stream_data(socket, hashing, 0, span, format, cancel)
+                    .await?;
+                let mut trailer = Trailer::new(hash, format.hash_algorithm);
+                if let Some(handle) = task_handle {
+                    trailer.files = handle.await??;
+                }
+                trailer.write(socket, format.version).await
+            }
+        }
+    }
+
+    /// Stream `hashing` to `socket`, computing the negotiated hash in-flight, and
+    /// return the hash for the trailer, so the receiver can verify without
+    /// re-reading from disk. A non-zero `offset` means that the stream is already
+    /// positioned past the part the receiver has.
+    async fn stream_data(
+        &self,
+        socket: &mut (impl AsyncWrite + Unpin),
+        hashing: HashingReader<impl AsyncRead + Unpin>,
+        offset: u64,
+        span: &ProgressSpan,
+        format: StreamFormat,
+        cancel: &mut CancelSignal<'_>,
+    ) -> Result<String, io::Error> {
+        util::notify_progress(
+            &self.sender_queue,
+            self.file.id,
+            span.start + offset as usize,
+            span.total,
+            &span.direction,
+            None,
+        )
+        .await;
 
         // HashingReader sits between the file and the network writer so that
         // we compute the digest in the same pass as the transfer.
-        let hashing = HashingReader::new(file);
-        let mut reader = ProgressReader::new(hashing, size, self.sender_queue.clone(), direction);
+        let progress_reader = ProgressReader::new(
+            hashing,
+            self.file.id,
+            span.total,
+            self.sender_queue.clone(),
+            span.direction.clone(),
+        )
+        .resume_at(span.start + offset as usize);
+        let mut reader = ThrottledReader::new(
+            progress_reader,
+            self.bandwidth.clone(),
+            self.file.peer,
+            span.direction.clone(),
+        );
 
-        futio::copy(&mut reader, &mut writer).await?;
-        // Flush the BufWriter's internal buffer to the socket without closing
-        // the underlying connection — the trailer still needs to be written.
-        writer.flush().await?;
+        // Compression happens after hashing and progress, so both of them
+        // count the bytes of the payload. The socket stays open afterwards,
+        // because the trailer still needs to be written.
+        match format.version {
+            ProtocolVersion::V1_2 => copy_raw(&mut reader, &mut *socket, cancel).await?,
+            _ => copy_compressed(&mut reader, &mut *socket, format.compression, cancel).await?,
+        };
 
         // Retrieve the digest now that all bytes have been written to the socket.
-        let hash = reader.into_inner().finish();
+        let hash = reader.into_inner().into_inner().finish();
         info!("Sending trailer hash: {}", hash);
-
-        // Send the trailer so the receiver can verify without a second disk read.
-        Trailer::write(&mut socket, hash).await?;
-
-        util::notify_completed(&self.sender_queue).await;
-        Ok(())
+        Ok(hash)
     }
 }
 
 impl UpgradeInfo for TransferOut {
-    type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type Info = ProtocolVersion;
+    type InfoIter = array::IntoIter<Self::Info, 2>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once("/transfer/1.2")
+        ProtocolVersion::SUPPORTED.into_iter()
     }
 }
 
@@ -337,11 +1510,17 @@ where
     type Error = io::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
-    fn upgrade_inbound(mut self, socket: TSocket, _: Self::Info) -> Self::Future {
+    fn upgrade_inbound(mut self, socket: TSocket, version: Self::Info) -> Self::Future {
         Box::pin(async move {
-            info!("Upgrade inbound");
+            info!("Upgrade inbound with {}", version);
+            self.version = version;
             let start = Instant::now();
-            self.read_socket(socket).await?;
+            let result = self.read_socket(socket).await;
+            // Ranges end before the transfer they belong to
+            if self.part_of.is_none() {
+                self.commands.forget(self.id);
+            }
+            result?;
 
             info!("Finished {:?} ms", start.elapsed().as_millis());
             Ok(self)
@@ -349,23 +1528,43 @@ where
     }
 }
 
+impl OutboundFailure<ProtocolEvent> for TransferOut {
+    fn failed(self, error: StreamUpgradeError<io::Error>) -> Option<ProtocolEvent> {
+        let error = match error {
+            StreamUpgradeError::Timeout => OutboundError::Timeout,
+            StreamUpgradeError::NegotiationFailed => OutboundError::NegotiationFailed,
+            StreamUpgradeError::Io(e) => OutboundError::Io(e.kind()),
+            StreamUpgradeError::Apply(e) => OutboundError::Transfer(e.kind(), e.to_string()),
+        };
+        Some(ProtocolEvent::OutboundFailed(self, error))
+    }
+}
+
 impl<TSocket> OutboundUpgrade<TSocket> for TransferOut
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
+    type Output = ProtocolEvent;
     type Error = io::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
-    fn upgrade_outbound(self, socket: TSocket, _: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, socket: TSocket, version: Self::Info) -> Self::Future {
         Box::pin(async move {
-            info!("Upgrade outbound");
+            info!("Upgrade outbound with {}", version);
             let start = Instant::now();
 
-            self.write_socket(socket).await?;
+            let result = self.write_socket(socket, version).await;
+            if self.range.is_none() {
+                self.commands.forget(self.file.id);
+                let _ = self.finished.unbounded_send(self.file.id);
+            }
+            result?;
 
             info!("Finished {:?} ms", start.elapsed().as_millis());
-            Ok(())
+            match self.range {
+                Some(_) => Ok(ProtocolEvent::RangeSent(self.file.id)),
+                None => Ok(ProtocolEvent::Sent(version)),
+            }
         })
     }
 }
@@ -380,8 +1579,8 @@ impl fmt::Display for TransferPayload {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "TransferPayload name: {}, payload: {}, hash: {}, size: {} bytes",
-            self.name, self.payload, self.hash, self.size_bytes
+            "TransferPayload id: {}, name: {}, payload: {}, hash: {}, size: {} bytes, over {}",
+            self.id, self.name, self.payload, self.hash, self.size_bytes, self.version
         )
     }
 }
@@ -390,19 +1589,19 @@ impl fmt::Display for ProtocolEvent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ProtocolEvent::Received(e) => write!(f, "Received {}", e),
-            ProtocolEvent::Sent => write!(f, "Sent"),
+            ProtocolEvent::Sent(version) => write!(f, "Sent over {}", version),
+            ProtocolEvent::RangeReceived(id) => write!(f, "Received a range of {}", id),
+            ProtocolEvent::RangeSent(id) => write!(f, "Sent a range of {}", id),
+            ProtocolEvent::OutboundFailed(e, error) => write!(f, "{} failed: {:?}", e, error),
         }
     }
 }
 
-impl From<()> for ProtocolEvent {
-    fn from(_: ()) -> Self {
-        ProtocolEvent::Sent
-    }
-}
-
 impl From<TransferPayload> for ProtocolEvent {
     fn from(transfer: TransferPayload) -> Self {
-        ProtocolEvent::Received(transfer)
+        match transfer.part_of {
+            Some(id) => ProtocolEvent::RangeReceived(id),
+            None => ProtocolEvent::Received(transfer),
+        }
     }
 }
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+
+use libp2p::PeerId;
+
+use crate::p2p::peer::{PeerQueue, QueuedFile, TransferId};
+use crate::p2p::transfer::file::FileToSend;
+
+/// Outgoing files of every peer. They wait in the order they were dropped
+/// until fewer than the allowed number of transfers to their peer run.
+#[derive(Debug, Default)]
+pub struct OutgoingQueue {
+    waiting: HashMap<PeerId, VecDeque<FileToSend>>,
+    active: HashMap<PeerId, Vec<FileToSend>>,
+}
+
+impl OutgoingQueue {
+    pub fn push(&mut self, file: FileToSend) {
+        self.waiting.entry(file.peer).or_default().push_back(file);
+    }
+
+    /// The first waiting file of a peer with fewer than `limit` running transfers.
+    /// It counts as running until it's finished.
+    pub fn next(&mut self, limit: usize) -> Option<FileToSend> {
+        let active = &self.active;
+        let (peer, files) = self.waiting.iter_mut().find(|(peer, files)| {
+            !files.is_empty() && active.get(*peer).map_or(0, |files| files.len()) < limit.max(1)
+        })?;
+        let (peer, file) = (*peer, files.pop_front()?);
+        self.active.entry(peer).or_default().push(file.clone());
+        Some(file)
+    }
+
+    /// Frees the place of a running transfer.
+    pub fn finish(&mut self, id: TransferId) -> Option<FileToSend> {
+        let files = self
+            .active
+            .values_mut()
+            .find(|files| files.iter().any(|file| file.id == id))?;
+        let index = files.iter().position(|file| file.id == id)?;
+        Some(files.remove(index))
+    }
+
+    /// Takes out the running and waiting files of `peer`, which can't get them anymore.
+    pub fn take_peer(&mut self, peer: &PeerId) -> (Vec<FileToSend>, Vec<FileToSend>) {
+        let active = self.active.remove(peer).unwrap_or_default();
+        let waiting = self.waiting.remove(peer).unwrap_or_default();
+        (active, waiting.into())
+    }
+
+    /// Removes a file that waits, running ones have to be cancelled instead.
+    pub fn remove(&mut self, id: TransferId) -> Option<FileToSend> {
+        let files = self
+            .waiting
+            .values_mut()
+            .find(|files| files.iter().any(|file| file.id == id))?;
+        let index = files.iter().position(|file| file.id == id)?;
+        files.remove(index)
+    }
+
+    /// Moves a waiting file to `position` among the files waiting for the same peer.
+    /// Returns the peer, if the file was found.
+    pub fn move_to(&mut self, id: TransferId, position: usize) -> Option<PeerId> {
+        let file = self.remove(id)?;
+        let peer = file.peer;
+        let files = self.waiting.entry(peer).or_default();
+        files.insert(position.min(files.len()), file);
+        Some(peer)
+    }
+
+    pub fn state(&self, peer: PeerId) -> PeerQueue {
+        let queued = |file: &FileToSend| QueuedFile {
+            id: file.id,
+            name: file.name.to_string(),
+            transfer_type: file.transfer_type,
+        };
+        PeerQueue {
+            peer,
+            active: self
+                .active
+                .get(&peer)
+                .map(|files| files.iter().map(queued).collect())
+                .unwrap_or_default(),
+            waiting: self
+                .waiting
+                .get(&peer)
+                .map(|files| files.iter().map(queued).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use crate::p2p::peer::TransferId;
+    use crate::p2p::transfer::file::{FileToSend, Payload};
+    use crate::p2p::transfer::queue::OutgoingQueue;
+
+    fn text(peer: &PeerId, text: &str) -> FileToSend {
+        FileToSend::new(peer, Payload::Text(text.to_string())).unwrap()
+    }
+
+    fn waiting(queue: &OutgoingQueue, peer: PeerId) -> Vec<TransferId> {
+        let state = queue.state(peer);
+        state.waiting.into_iter().map(|file| file.id).collect()
+    }
+
+    #[test]
+    fn test_files_go_out_in_order_within_the_limit() {
+        let (peer1, peer2) = (PeerId::random(), PeerId::random());
+        let mut queue = OutgoingQueue::default();
+        let first = text(&peer1, "first");
+        let second = text(&peer1, "second");
+        queue.push(first.clone());
+        queue.push(second.clone());
+        queue.push(text(&peer2, "other"));
+
+        let mut sent = [queue.next(1).unwrap(), queue.next(1).unwrap()];
+        sent.sort_by_key(|file| file.id);
+        assert_eq!(sent[0].id, first.id);
+        assert_eq!(sent[1].peer, peer2);
+        assert!(queue.next(1).is_none());
+        assert_eq!(waiting(&queue, peer1), vec![second.id]);
+
+        assert!(queue.finish(first.id).is_some());
+        assert_eq!(queue.next(1).unwrap().id, second.id);
+        assert!(queue.state(peer1).waiting.is_empty());
+    }
+
+    #[test]
+    fn test_reorder_and_remove_waiting_files() {
+        let peer = PeerId::random();
+        let mut queue = OutgoingQueue::default();
+        let files: Vec<FileToSend> = ["a", "b", "c"].iter().map(|t| text(&peer, t)).collect();
+        for file in files.iter() {
+            queue.push(file.clone());
+        }
+
+        assert_eq!(queue.move_to(files[2].id, 0), Some(peer));
+        let ids: Vec<TransferId> = files.iter().map(|file| file.id).collect();
+        assert_eq!(waiting(&queue, peer), vec![ids[2], ids[0], ids[1]]);
+        assert!(queue.remove(files[0].id).is_some());
+        assert_eq!(waiting(&queue, peer), vec![ids[2], ids[1]]);
+        assert!(queue.remove(files[0].id).is_none());
+
+        let (active, waiting) = queue.take_peer(&peer);
+        assert!(active.is_empty());
+        assert_eq!(waiting.len(), 2);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_channel::{Receiver, Sender};
+use libp2p::PeerId;
+use tokio_util::sync::CancellationToken;
+
+use crate::p2p::peer::{PeerEvent, TransferId};
+use crate::p2p::transfer::compression::Compression;
+use crate::p2p::transfer::hash::HashAlgorithm;
+use crate::p2p::transfer::reader::ProgressSpan;
+use crate::p2p::util;
+
+/// Most substreams the ranges of a single file are sent over.
+/// One more substream carries the Metadata, all of them have to fit in
+/// the outbound substreams a connection opens at once.
+pub const MAX_STREAMS: usize = 4;
+
+/// Smaller files are sent over a single substream by default
+pub const MIN_PARALLEL_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many substreams large files are split over.
+#[derive(Clone, Copy, Debug)]
+pub struct Parallelism {
+    /// 1 sends every file over a single substream
+    pub streams: usize,
+    /// Smaller files are not split
+    pub min_size: u64,
+}
+
+impl Parallelism {
+    /// Number of substreams for the ranges of a file of `size` bytes,
+    /// 1 when it's not worth splitting.
+    pub fn streams_for(&self, size: u64) -> usize {
+        if size < self.min_size.max(1) {
+            return 1;
+        }
+        self.streams.clamp(1, MAX_STREAMS)
+    }
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Parallelism {
+            streams: 1,
+            min_size: MIN_PARALLEL_SIZE,
+        }
+    }
+}
+
+/// Sender's offer to split the file into ranges, sent in the Metadata.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParallelOffer {
+    /// Identifies the file in the Metadata of its ranges
+    pub key: u64,
+    pub streams: usize,
+}
+
+/// Part of the file identified by `key`, sent over its own substream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileRange {
+    pub key: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Split `size` bytes into `streams` contiguous ranges of about the same length.
+pub fn split(key: u64, size: u64, streams: usize) -> Vec<FileRange> {
+    let streams = streams.max(1) as u64;
+    let length = size.div_ceil(streams);
+    (0..streams)
+        .map(|i| i * length)
+        .filter(|offset| *offset < size)
+        .map(|offset| FileRange {
+            key,
+            offset,
+            length: length.min(size - offset),
+        })
+        .collect()
+}
+
+/// Range the sender writes over a substream of its own.
+#[derive(Clone, Debug)]
+pub struct OutgoingRange {
+    pub range: FileRange,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    /// Bytes sent over all substreams of the file
+    pub sent: Arc<AtomicU64>,
+    pub done: Sender<io::Result<()>>,
+    /// Cancelled when the transfer of the file stops
+    pub stopped: CancellationToken,
+}
+
+/// File the receiver preallocated for the ranges of an accepted transfer.
+#[derive(Debug)]
+pub struct IncomingRanges {
+    pub id: TransferId,
+    /// Partial file that the ranges are written into
+    pub path: String,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    /// Bytes received over all substreams of the file
+    pub received: Arc<AtomicU64>,
+    pub done: Sender<io::Result<()>>,
+    pub results: Receiver<io::Result<()>>,
+}
+
+/// Ranges of a transfer, by its sender and key
+type RangeMap = HashMap<(PeerId, u64), Arc<IncomingRanges>>;
+
+/// Transfers that wait for their ranges, shared by all inbound substreams.
+#[derive(Clone, Debug, Default)]
+pub struct RangeRegistry {
+    transfers: Arc<Mutex<RangeMap>>,
+}
+
+impl RangeRegistry {
+    pub fn insert(&self, peer: PeerId, key: u64, ranges: IncomingRanges) {
+        let mut transfers = self.transfers.lock().unwrap();
+        transfers.insert((peer, key), Arc::new(ranges));
+    }
+
+    pub fn get(&self, peer: PeerId, key: u64) -> Option<Arc<IncomingRanges>> {
+        self.transfers.lock().unwrap().get(&(peer, key)).cloned()
+    }
+
+    pub fn remove(&self, peer: PeerId, key: u64) {
+        self.transfers.lock().unwrap().remove(&(peer, key));
+    }
+}
+
+/// Wait until `count` ranges are done, reporting the bytes `counter`
+/// counts meanwhile. Fails with the first range that fails.
+pub async fn wait_for_ranges(
+    results: &Receiver<io::Result<()>>,
+    count: usize,
+    counter: &AtomicU64,
+    sender_queue: &Sender<PeerEvent>,
+    id: TransferId,
+    span: &ProgressSpan,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    let mut done = 0;
+    while done < count {
+        tokio::select! {
+            result = results.recv() => {
+                result.map_err(|_| {
+                    io::Error::new(ErrorKind::BrokenPipe, "Substreams of the ranges closed")
+                })??;
+                done += 1;
+            }
+            _ = ticker.tick() => {
+                let bytes = counter.load(Ordering::Relaxed);
+                let speed_bps = bytes as f64 / start.elapsed().as_secs_f64().max(0.001);
+                util::notify_progress(
+                    sender_queue,
+                    id,
+                    span.start + bytes as usize,
+                    span.total,
+                    &span.direction,
+                    Some(speed_bps),
+                )
+                .await;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::transfer::ranges::{split, Parallelism, MAX_STREAMS};
+
+    #[test]
+    fn test_split_covers_the_file() {
+        for (size, streams) in [(100, 4), (101, 4), (3, 4), (1, 1), (1 << 30, 3)] {
+            let ranges = split(7, size, streams);
+            assert!(ranges.len() <= streams);
+            let mut offset = 0;
+            for range in ranges {
+                assert_eq!(range.key, 7);
+                assert_eq!(range.offset, offset);
+                assert!(range.length > 0);
+                offset += range.length;
+            }
+            assert_eq!(offset, size);
+        }
+    }
+
+    #[test]
+    fn test_small_files_are_not_split() {
+        let parallelism = Parallelism {
+            streams: 16,
+            min_size: 1024,
+        };
+        assert_eq!(parallelism.streams_for(1023), 1);
+        assert_eq!(parallelism.streams_for(1024), MAX_STREAMS);
+        assert_eq!(Parallelism::default().streams_for(u64::MAX), 1);
+    }
+}
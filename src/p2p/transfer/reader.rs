@@ -1,16 +1,40 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Instant;
 use std::{io, pin::Pin};
 
 use async_channel::Sender;
 use futures::prelude::*;
-use md5::{Digest, Md5};
 
-use crate::p2p::peer::{Direction, PeerEvent};
+use crate::p2p::peer::{Direction, PeerEvent, TransferId};
+use crate::p2p::transfer::hash::{HashAlgorithm, Hasher};
+use crate::p2p::transfer::metadata::HASH_BUFFER_SIZE;
 use crate::p2p::util;
 
+/// The part of the reported progress that a single item covers.
+/// Items of a batch share one progress bar, so every item starts
+/// where the previous one ended.
+#[derive(Clone, Debug)]
+pub struct ProgressSpan {
+    pub direction: Direction,
+    pub start: usize,
+    pub total: usize,
+}
+
+impl ProgressSpan {
+    pub fn new(direction: Direction, total: usize) -> Self {
+        ProgressSpan {
+            direction,
+            start: 0,
+            total,
+        }
+    }
+}
+
 pub struct ProgressReader<R> {
     inner: R,
+    id: TransferId,
     size: usize,
     counter: usize,
     current_size: usize,
@@ -22,12 +46,14 @@ pub struct ProgressReader<R> {
 impl<R: AsyncRead + Unpin> ProgressReader<R> {
     pub fn new(
         inner: R,
+        id: TransferId,
         size: usize,
         sender_queue: Sender<PeerEvent>,
         direction: Direction,
     ) -> Self {
         Self {
             inner,
+            id,
             size,
             counter: 0,
             current_size: 0,
@@ -37,6 +63,13 @@ impl<R: AsyncRead + Unpin> ProgressReader<R> {
         }
     }
 
+    /// Starts counting at `offset`, so that a resumed transfer reports
+    /// progress of the whole payload rather than of the remaining part.
+    pub fn resume_at(mut self, offset: usize) -> Self {
+        self.counter = offset;
+        self
+    }
+
     /// Consumes the `ProgressReader` and returns the wrapped inner reader.
     /// Use this after the copy is complete to recover e.g. a [`HashingReader`]
     /// and call its `finish()` method.
@@ -74,11 +107,12 @@ impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
                 self.last_notify = Some(now);
 
                 let sender = self.sender_queue.clone();
+                let id = self.id;
                 let counter = self.counter;
                 let size = self.size;
                 let direction = self.direction.clone();
                 tokio::spawn(async move {
-                    util::notify_progress(&sender, counter, size, &direction, speed_bps).await;
+                    util::notify_progress(&sender, id, counter, size, &direction, speed_bps).await;
                 });
                 self.current_size = 0;
             }
@@ -87,26 +121,78 @@ impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
     }
 }
 
-/// Wraps an `AsyncRead` and computes an MD5 digest of all bytes that pass
-/// through it. Call [`HashingReader::finish`] after the stream reaches EOF
+/// Adds the bytes read to a counter shared by several readers,
+/// such as those of the ranges of a single file.
+pub struct CountingReader<R> {
+    inner: R,
+    counter: Arc<AtomicU64>,
+}
+
+impl<R: AsyncRead + Unpin> CountingReader<R> {
+    pub fn new(inner: R, counter: Arc<AtomicU64>) -> Self {
+        Self { inner, counter }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Wraps an `AsyncRead` and computes a digest of all bytes that pass
+/// through it, using the algorithm negotiated for the transfer. Call [`HashingReader::finish`] after the stream reaches EOF
 /// to obtain the hex-encoded digest.
 pub struct HashingReader<R> {
     inner: R,
-    state: Md5,
+    state: Hasher,
 }
 
 impl<R: AsyncRead + Unpin> HashingReader<R> {
-    pub fn new(inner: R) -> Self {
+    pub fn new(inner: R, algorithm: HashAlgorithm) -> Self {
         Self {
             inner,
-            state: Md5::new(),
+            state: Hasher::new(algorithm),
         }
     }
 
-    /// Consumes the reader and returns the hex-encoded MD5 digest of all bytes
+    /// Feeds all bytes of `prefix` into the digest without passing them on.
+    /// Used when resuming, so that the digest still covers the whole payload.
+    /// Returns the number of bytes hashed.
+    pub async fn absorb(&mut self, mut prefix: impl AsyncRead + Unpin) -> io::Result<u64> {
+        let mut buffer = [0u8; HASH_BUFFER_SIZE];
+        let mut total: u64 = 0;
+        loop {
+            let n = prefix.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+            self.state.update(&buffer[..n]);
+        }
+    }
+
+    /// Consumes the reader and returns the hex-encoded digest of all bytes
     /// that were read through it so far.
     pub fn finish(self) -> String {
-        hex::encode(self.state.finalize())
+        self.state.finish()
+    }
+
+    /// Like [`HashingReader::finish`], but gives the inner reader back as well.
+    pub fn finish_with_inner(self) -> (String, R) {
+        (self.state.finish(), self.inner)
     }
 }
 
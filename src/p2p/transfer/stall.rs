@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, Sleep};
+
+use crate::p2p::util::find_cause;
+
+/// No data moved over the substream for too long, because the other peer
+/// stopped reading or sending.
+#[derive(Debug, Clone)]
+pub struct Stalled {
+    pub timeout: Duration,
+}
+
+impl Stalled {
+    /// Looks for a stall among the causes of `error`.
+    pub fn find(error: &io::Error) -> Option<&Stalled> {
+        find_cause(error)
+    }
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transfer stalled, no data moved for {} seconds",
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl Error for Stalled {}
+
+impl From<Stalled> for io::Error {
+    fn from(stalled: Stalled) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, stalled)
+    }
+}
+
+/// Fails reads and writes that can't make progress for `timeout`.
+/// Time spent between them doesn't count, so the transfer may stay idle
+/// while it waits for something else, such as the rate limit.
+pub struct StallGuard<S> {
+    inner: S,
+    /// None never fails
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> StallGuard<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        StallGuard {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let timeout = match (poll.is_pending(), self.timeout) {
+            (true, Some(timeout)) => timeout,
+            _ => {
+                if poll.is_ready() {
+                    self.deadline = None;
+                }
+                return poll;
+            }
+        };
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Stalled { timeout }.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StallGuard<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.check(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StallGuard<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.check(cx, poll)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_close(cx);
+        this.check(cx, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::prelude::*;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::p2p::transfer::stall::{StallGuard, Stalled};
+
+    #[tokio::test]
+    async fn test_peer_that_stops_reading_stalls_the_transfer() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let timeout = Duration::from_millis(100);
+        let mut writer = StallGuard::new(local.compat(), Some(timeout));
+
+        // The remote end reads the first part only
+        writer.write_all(&[1; 1024]).await.unwrap();
+        let mut received = [0; 1024];
+        tokio::io::AsyncReadExt::read_exact(&mut remote, &mut received)
+            .await
+            .unwrap();
+        tokio::time::sleep(timeout * 2).await;
+
+        let error = writer.write_all(&[2; 4096]).await.unwrap_err();
+        assert_eq!(Stalled::find(&error).unwrap().timeout, timeout);
+    }
+}
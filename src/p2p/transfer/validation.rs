@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path};
+
+use tokio::fs;
+use tokio_tar::EntryType;
+
+use crate::p2p::util::find_cause;
+
+/// Data from the other peer that could write outside the target directory,
+/// or that this host doesn't accept, so it was refused.
+#[derive(Debug, Clone)]
+pub struct Refused {
+    pub reason: String,
+}
+
+impl Refused {
+    fn new(reason: String) -> Self {
+        Refused { reason }
+    }
+
+    /// Looks for a refusal among the causes of `error`.
+    pub fn find(error: &io::Error) -> Option<&Refused> {
+        find_cause(error)
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Refused: {}", self.reason)
+    }
+}
+
+impl Error for Refused {}
+
+impl From<Refused> for io::Error {
+    fn from(refused: Refused) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, refused)
+    }
+}
+
+/// Accepts only a plain name of a file or directory. Separators, NUL bytes,
+/// `..` and absolute paths could point outside of the target directory.
+pub fn check_name(name: &str) -> Result<(), Refused> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !plain || name.contains(['/', '\\', '\0']) {
+        return Err(Refused::new(format!("unsafe name {:?}", name)));
+    }
+    Ok(())
+}
+
+/// Accepts payloads up to `max_size` bytes, any when it's None.
+pub fn check_size(size: usize, max_size: Option<u64>) -> Result<(), Refused> {
+    match max_size {
+        Some(max_size) if size as u64 > max_size => Err(Refused::new(format!(
+            "size of {} bytes is over the limit of {} bytes",
+            size, max_size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Path within the archive, split into its names
+type Components = Vec<OsString>;
+
+/// Checks the entries of a received archive as they are unpacked, so that
+/// nothing is written outside of its top-level directory. Links are resolved
+/// through the links unpacked before them, so that a chain of them can't
+/// lead outside either. Device nodes and FIFOs are never accepted.
+#[derive(Debug, Default)]
+pub struct ArchiveGuard {
+    /// Top-level directory, named after the sent one
+    root: Option<OsString>,
+    /// Symlinks unpacked so far, with the paths they resolve to
+    links: HashMap<Components, Components>,
+    /// Paths that links were resolved through as plain directories.
+    /// A symlink unpacked there later would change where those links lead.
+    traversed: HashSet<Components>,
+}
+
+impl ArchiveGuard {
+    pub fn check(
+        &mut self,
+        kind: EntryType,
+        path: &Path,
+        link: Option<&Path>,
+    ) -> Result<(), Refused> {
+        if kind.is_block_special() || kind.is_character_special() || kind.is_fifo() {
+            return Err(Refused::new(format!("special file {:?}", path)));
+        }
+        let entry = self
+            .archive_path(path)
+            .ok_or_else(|| Refused::new(format!("path {:?} leads outside", path)))?;
+        if self.through_link(&entry) {
+            return Err(Refused::new(format!(
+                "path {:?} leads through a link",
+                path
+            )));
+        }
+        let target = match link {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let outside = || Refused::new(format!("link {:?} points outside to {:?}", path, target));
+        if kind.is_symlink() {
+            if self.traversed.contains(&entry) {
+                return Err(Refused::new(format!(
+                    "link {:?} replaces a directory other links lead through",
+                    path
+                )));
+            }
+            // Symlinks are resolved from their own directory
+            let resolved = self
+                .resolve(&entry[..entry.len() - 1], target)
+                .ok_or_else(outside)?;
+            self.links.insert(entry, resolved);
+        } else if kind.is_hard_link() {
+            // Hard links name another entry of the archive
+            let linked = self.archive_path(target).ok_or_else(outside)?;
+            if self.through_link(&linked) {
+                return Err(outside());
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of a path that stays under the top-level directory, which the first entry sets.
+    fn archive_path(&mut self, path: &Path) -> Option<Components> {
+        let mut components = Components::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_os_string()),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+        let first = components.first()?;
+        match &self.root {
+            Some(root) if root != first => None,
+            Some(_) => Some(components),
+            None => {
+                self.root = Some(first.clone());
+                Some(components)
+            }
+        }
+    }
+
+    /// The path or one of its parents is a symlink unpacked before.
+    fn through_link(&self, path: &[OsString]) -> bool {
+        (1..=path.len()).any(|len| self.links.contains_key(&path[..len]))
+    }
+
+    /// Where `target` leads from `dir`, None when it leaves the top-level directory.
+    fn resolve(&mut self, dir: &[OsString], target: &Path) -> Option<Components> {
+        for len in 1..=dir.len() {
+            self.traversed.insert(dir[..len].to_vec());
+        }
+        let mut resolved = dir.to_vec();
+        for component in target.components() {
+            match component {
+                Component::Normal(name) => {
+                    resolved.push(name.to_os_string());
+                    match self.links.get(&resolved) {
+                        Some(link) => resolved = link.clone(),
+                        None => {
+                            self.traversed.insert(resolved.clone());
+                        }
+                    }
+                }
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                    if resolved.is_empty() {
+                        return None;
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(resolved).filter(|resolved| !resolved.is_empty())
+    }
+}
+
+/// Symlinks on the disk could lead anywhere, so entries are only written into real directories.
+pub async fn check_parents(base: &Path, path: &Path) -> Result<(), Refused> {
+    let mut current = base.to_path_buf();
+    for parent in path
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+    {
+        current.push(parent);
+        if let Ok(metadata) = fs::symlink_metadata(&current).await {
+            if metadata.file_type().is_symlink() {
+                return Err(Refused::new(format!(
+                    "path {:?} leads through a link",
+                    path
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio_tar::EntryType;
+
+    use super::{check_name, check_size, ArchiveGuard};
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("report.pdf").is_ok());
+        assert!(check_name("..hidden").is_ok());
+        assert!(check_name("../../.bashrc").is_err());
+        assert!(check_name("..").is_err());
+        assert!(check_name("/etc/passwd").is_err());
+        assert!(check_name("dir\\file").is_err());
+        assert!(check_name("file\0.txt").is_err());
+        assert!(check_name("").is_err());
+    }
+
+    #[test]
+    fn test_archive_entries() {
+        let path = Path::new;
+        let mut guard = ArchiveGuard::default();
+        let mut check = |kind, entry, link: Option<&str>| {
+            guard.check(kind, path(entry), link.map(path)).is_ok()
+        };
+        assert!(check(EntryType::Directory, "dir", None));
+        assert!(check(EntryType::Regular, "dir/a/b.txt", None));
+        assert!(!check(EntryType::Regular, "dir/../../b.txt", None));
+        assert!(!check(EntryType::Regular, "/dir/b.txt", None));
+        // Everything is under the top-level directory of the first entry
+        assert!(!check(EntryType::Regular, "other/b.txt", None));
+        assert!(!check(EntryType::Fifo, "dir/pipe", None));
+        assert!(!check(EntryType::Char, "dir/tty", None));
+
+        assert!(check(EntryType::Symlink, "dir/a/link", Some("../b.txt")));
+        assert!(!check(EntryType::Symlink, "dir/a/up", Some("../..")));
+        assert!(!check(
+            EntryType::Symlink,
+            "dir/a/ssh",
+            Some("/home/user/.ssh")
+        ));
+        assert!(check(EntryType::Link, "dir/a/hard", Some("dir/b.txt")));
+        assert!(!check(EntryType::Link, "dir/a/hard", Some("other/b.txt")));
+        assert!(!check(EntryType::Link, "dir/a/hard", Some("../b.txt")));
+        assert!(!check(EntryType::Link, "dir/a/hard", Some("dir/a/link")));
+        // Nothing is written through a symlink
+        assert!(!check(EntryType::Regular, "dir/a/link/c.txt", None));
+    }
+
+    #[test]
+    fn test_chained_links_are_resolved() {
+        let path = Path::new;
+        let mut guard = ArchiveGuard::default();
+        let mut check = |kind, entry, link: Option<&str>| {
+            guard.check(kind, path(entry), link.map(path)).is_ok()
+        };
+        assert!(check(EntryType::Directory, "dir", None));
+        // Leads to dir, which is still inside
+        assert!(check(EntryType::Symlink, "dir/c/b", Some("..")));
+        // Leads to dir/c/b/.., which is outside through the link above
+        assert!(!check(EntryType::Symlink, "dir/a", Some("c/b/..")));
+        assert!(check(EntryType::Symlink, "dir/d", Some("c/b/c")));
+
+        // Resolved through dir/e as a directory, so it can't become a link later
+        assert!(check(EntryType::Symlink, "dir/f", Some("e/g/../..")));
+        assert!(!check(EntryType::Symlink, "dir/e/g", Some("..")));
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert!(check_size(100, None).is_ok());
+        assert!(check_size(100, Some(100)).is_ok());
+        assert!(check_size(101, Some(100)).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Revision of the transfer protocol, as negotiated on the substream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// Spoken by older builds: zero-padded control packets and raw data.
+    /// Batches, compression and cancelling are not possible with it.
+    V1_2,
+    /// Length-prefixed control packets and chunked data
+    V1_4,
+}
+
+impl ProtocolVersion {
+    /// Versions advertised to the other peer, the preferred one first.
+    /// The peer that opens the substream decides with its order, so the
+    /// newest version both peers know is picked.
+    pub const SUPPORTED: [ProtocolVersion; 2] = [ProtocolVersion::V1_4, ProtocolVersion::V1_2];
+
+    pub const LATEST: ProtocolVersion = ProtocolVersion::SUPPORTED[0];
+
+    pub fn protocol_name(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V1_2 => "/transfer/1.2",
+            ProtocolVersion::V1_4 => "/transfer/1.4",
+        }
+    }
+
+    /// None for versions this build doesn't know.
+    pub fn from_name(name: &str) -> Option<ProtocolVersion> {
+        Self::SUPPORTED
+            .into_iter()
+            .find(|version| version.protocol_name() == name)
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        *self == ProtocolVersion::V1_2
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::LATEST
+    }
+}
+
+impl AsRef<str> for ProtocolVersion {
+    fn as_ref(&self) -> &str {
+        self.protocol_name()
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.protocol_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion;
+
+    #[test]
+    fn test_newest_version_is_preferred() {
+        assert_eq!(ProtocolVersion::LATEST, ProtocolVersion::V1_4);
+        let mut sorted = ProtocolVersion::SUPPORTED;
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(sorted, ProtocolVersion::SUPPORTED);
+        assert_eq!(ProtocolVersion::V1_2.to_string(), "/transfer/1.2");
+        assert_eq!(
+            ProtocolVersion::from_name("/transfer/1.4"),
+            Some(ProtocolVersion::V1_4)
+        );
+        assert_eq!(ProtocolVersion::from_name("/transfer/2.0"), None);
+    }
+}
@@ -1,4 +1,6 @@
+use std::error::Error as StdError;
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use async_channel::Sender as AsyncSender;
 use futures::prelude::*;
@@ -9,7 +11,8 @@ use pnet_datalink;
 #[cfg(windows)]
 use ipconfig;
 
-use super::peer::{Direction, PeerEvent};
+use super::peer::{Direction, PeerEvent, TransferId};
+use super::transfer::chunks::Cancelled;
 
 // Convenience trait implementation, which helps to alias socket type
 pub trait TSocketAlias: AsyncRead + AsyncWrite + Send + Unpin {}
@@ -17,6 +20,26 @@ impl<T: AsyncRead + AsyncWrite + Send + Unpin> TSocketAlias for T {}
 
 pub const CHUNK_SIZE: usize = 1024;
 
+/// Looks for an error of type `T` among the causes of `error`.
+pub fn find_cause<T: StdError + 'static>(error: &Error) -> Option<&T> {
+    let mut cause: &(dyn StdError + 'static) = error.get_ref()?;
+    loop {
+        if let Some(found) = cause.downcast_ref::<T>() {
+            return Some(found);
+        }
+        cause = match cause.downcast_ref::<Error>() {
+            Some(inner) => inner.get_ref()?,
+            None => cause.source()?,
+        };
+    }
+}
+
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_transfer_id() -> TransferId {
+    NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub async fn notify(sender_queue: &AsyncSender<PeerEvent>, event: PeerEvent) {
     if let Err(err) = sender_queue.to_owned().send(event).await {
         error!("Failed to send message, {}", err)
@@ -25,12 +48,14 @@ pub async fn notify(sender_queue: &AsyncSender<PeerEvent>, event: PeerEvent) {
 
 pub async fn notify_progress(
     sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
     counter: usize,
     total_size: usize,
     direction: &Direction,
     speed_bps: Option<f64>,
 ) {
-    let event = PeerEvent::TransferProgress((counter, total_size, direction.to_owned(), speed_bps));
+    let event =
+        PeerEvent::TransferProgress((id, counter, total_size, direction.to_owned(), speed_bps));
     notify(sender_queue, event).await;
 }
 
@@ -38,6 +63,21 @@ pub async fn notify_error(sender_queue: &AsyncSender<PeerEvent>, error_text: &st
     notify(sender_queue, PeerEvent::Error(error_text.to_string())).await;
 }
 
+pub async fn notify_cancelled(
+    sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
+    file_name: &str,
+    cancelled: &Cancelled,
+) {
+    let event = PeerEvent::TransferCancelled {
+        id,
+        file_name: file_name.to_string(),
+        reason: cancelled.reason.to_string(),
+        by_remote: cancelled.by_remote,
+    };
+    notify(sender_queue, event).await
+}
+
 pub async fn notify_completed(sender_queue: &AsyncSender<PeerEvent>) {
     notify(sender_queue, PeerEvent::TransferCompleted).await
 }
@@ -51,11 +91,7 @@ pub async fn notify_rejected(sender_queue: &AsyncSender<PeerEvent>) {
 }
 
 pub fn time_to_notify(current_size: usize, total_size: usize) -> bool {
-    if current_size >= ((total_size / 10) + CHUNK_SIZE * 256) {
-        true
-    } else {
-        false
-    }
+    current_size >= ((total_size / 10) + CHUNK_SIZE * 256)
 }
 
 #[cfg(unix)]
@@ -63,8 +99,7 @@ pub fn check_network_interfaces() -> Result<(), Error> {
     let interfaces = pnet_datalink::interfaces();
     let default_interface = interfaces
         .iter()
-        .filter(|e| !e.is_loopback() && e.ips.len() > 0)
-        .next();
+        .find(|e| !e.is_loopback() && !e.ips.is_empty());
     debug!("Default network interface: {:?}", default_interface);
     match default_interface {
         Some(_) => {
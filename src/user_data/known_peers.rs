@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::p2p::{OperatingSystem, Peer};
+
+/// Addresses kept for every peer, the most recent ones
+const MAX_ADDRESSES: usize = 4;
+
+/// Peer that finished discovery at some point, so that it can be
+/// dialed without waiting for mDNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownPeer {
+    /// Addresses it can be dialed at, newest first
+    pub addresses: Vec<Multiaddr>,
+    pub hostname: String,
+    pub os: OperatingSystem,
+    pub display_name: String,
+    pub last_seen: SystemTime,
+}
+
+impl KnownPeer {
+    /// Display name, or the hostname when the user didn't choose one.
+    pub fn shown_name(&self) -> &str {
+        if self.display_name.is_empty() {
+            &self.hostname
+        } else {
+            &self.display_name
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    peer_id: String,
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    hostname: String,
+    os: OperatingSystem,
+    #[serde(default)]
+    display_name: String,
+    /// Unix time in seconds
+    last_seen: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KnownPeersFile {
+    #[serde(default)]
+    peers: Vec<Entry>,
+}
+
+/// Known peers saved in a file next to the identity key.
+/// Without a path they are only kept in memory.
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    peers: HashMap<PeerId, KnownPeer>,
+}
+
+impl KnownPeers {
+    /// Missing file means that no peer is known yet.
+    pub fn load(path: PathBuf) -> Result<KnownPeers, Error> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let file: KnownPeersFile =
+            toml::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let peers = file
+            .peers
+            .into_iter()
+            .filter_map(|entry| {
+                let peer_id = match entry.peer_id.parse::<PeerId>() {
+                    Ok(peer_id) => peer_id,
+                    Err(e) => {
+                        warn!("Ignoring known peer {}: {}", entry.peer_id, e);
+                        return None;
+                    }
+                };
+                let peer = KnownPeer {
+                    addresses: entry
+                        .addresses
+                        .iter()
+                        .filter_map(|address| address.parse().ok())
+                        .collect(),
+                    hostname: entry.hostname,
+                    os: entry.os,
+                    display_name: entry.display_name,
+                    last_seen: UNIX_EPOCH + Duration::from_secs(entry.last_seen),
+                };
+                Some((peer_id, peer))
+            })
+            .collect();
+        Ok(KnownPeers {
+            path: Some(path),
+            peers,
+        })
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&KnownPeer> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &KnownPeer)> {
+        self.peers.iter()
+    }
+
+    /// Records that the peer finished discovery just now, and saves the file.
+    /// `address` is the one it can be dialed at, if there's one.
+    pub fn seen(&mut self, seen: &Peer, address: Option<&Multiaddr>) -> Result<(), Error> {
+        let peer = self.peers.entry(seen.peer_id).or_insert_with(|| KnownPeer {
+            addresses: Vec::new(),
+            hostname: String::new(),
+            os: seen.os,
+            display_name: String::new(),
+            last_seen: UNIX_EPOCH,
+        });
+        if let Some(address) = address {
+            peer.addresses.retain(|known| known != address);
+            peer.addresses.insert(0, address.clone());
+            peer.addresses.truncate(MAX_ADDRESSES);
+        }
+        peer.hostname = seen.hostname.clone();
+        peer.os = seen.os;
+        peer.display_name = seen.display_name.clone();
+        peer.last_seen = SystemTime::now();
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut entries: Vec<Entry> = self
+            .peers
+            .iter()
+            .map(|(peer_id, peer)| Entry {
+                peer_id: peer_id.to_base58(),
+                addresses: peer.addresses.iter().map(|a| a.to_string()).collect(),
+                hostname: peer.hostname.clone(),
+                os: peer.os,
+                display_name: peer.display_name.clone(),
+                last_seen: peer
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0),
+            })
+            .collect();
+        // Stable order, so that the file doesn't change needlessly
+        entries.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+
+        let toml = toml::to_string(&KnownPeersFile { peers: entries })
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        fs::write(path, toml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{Multiaddr, PeerId};
+    use tempfile::tempdir;
+
+    use crate::p2p::{OperatingSystem, Peer, Trust};
+    use crate::user_data::KnownPeers;
+
+    #[test]
+    fn test_known_peers_are_saved() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("known_peers.toml");
+        let peer_id = PeerId::random();
+        let first: Multiaddr = "/ip4/10.0.0.2/tcp/36571".parse().unwrap();
+        let second: Multiaddr = "/ip4/10.0.0.3/tcp/36571".parse().unwrap();
+
+        let mut peer = Peer {
+            name: peer_id.to_base58(),
+            address: first.clone(),
+            peer_id,
+            hostname: "laptop".to_string(),
+            os: OperatingSystem::Linux,
+            display_name: String::new(),
+            online: true,
+            capabilities: None,
+            trust: Trust::Unpaired,
+            pairing_code: String::new(),
+        };
+
+        let mut known = KnownPeers::load(path.clone()).unwrap();
+        assert_eq!(known.iter().count(), 0);
+        known.seen(&peer, Some(&first)).unwrap();
+        known.seen(&peer, Some(&second)).unwrap();
+        peer.hostname = "desktop".to_string();
+        peer.os = OperatingSystem::Windows;
+        peer.display_name = "Living room".to_string();
+        known.seen(&peer, None).unwrap();
+
+        let loaded = KnownPeers::load(path).unwrap();
+        let peer = loaded.get(&peer_id).unwrap();
+        assert_eq!(peer.addresses, vec![second, first]);
+        assert_eq!(peer.hostname, "desktop");
+        assert_eq!(peer.os, OperatingSystem::Windows);
+        assert_eq!(peer.display_name, "Living room");
+        assert!(peer.last_seen.elapsed().unwrap().as_secs() < 60);
+    }
+}
@@ -1,43 +1,189 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io::{Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use directories_next::{BaseDirs, UserDirs};
 use libp2p::identity::Keypair;
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use toml;
 
+use crate::p2p::discovery::handler::MAX_OUTBOUND_SUBSTREAMS;
+use crate::p2p::transfer::ranges::MAX_STREAMS;
+
+mod known_peers;
+
+pub use known_peers::{KnownPeer, KnownPeers};
+
 // Unassigned in IANA
 const DEFAULT_LISTEN_PORT: u16 = 36571;
 const DEFAULT_FIREWALL_CHECKED: bool = false;
+const DEFAULT_PRESERVE_MTIME: bool = true;
+const DEFAULT_PRESERVE_PERMISSIONS: bool = true;
+const DEFAULT_PARALLEL_STREAMS: usize = 1;
+const DEFAULT_TRANSFERS_PER_PEER: usize = 1;
+const DEFAULT_STALL_TIMEOUT: u64 = 60;
+const DEFAULT_ANSWER_TIMEOUT: u64 = 300;
 const IDENTITY_FILE: &str = "identity.key";
+const KNOWN_PEERS_FILE: &str = "known_peers.toml";
+const PARTIAL_SUFFIX: &str = ".dragit-part";
+
+/// What to do with a received file or directory whose name is taken
+/// in the target directory already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Keep both, adding a number to the new name, like "report (1).pdf"
+    #[default]
+    Rename,
+    Overwrite,
+    Skip,
+    /// Let the user pick one of the above in the accept dialog
+    Ask,
+}
+
+/// Attributes of the sender's files that are applied to the received ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreservedAttributes {
+    pub mtime: bool,
+    /// Unix permission bits, such as the executable one
+    pub permissions: bool,
+}
+
+impl Default for PreservedAttributes {
+    fn default() -> Self {
+        PreservedAttributes {
+            mtime: DEFAULT_PRESERVE_MTIME,
+            permissions: DEFAULT_PRESERVE_PERMISSIONS,
+        }
+    }
+}
+
+/// How long transfers wait for the other peer. None waits forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// A running transfer fails when no data moves for that long
+    pub stall: Option<Duration>,
+    /// An offer fails when the receiving user doesn't answer in time
+    pub answer: Option<Duration>,
+}
+
+impl Timeouts {
+    fn from_secs(stall: u64, answer: u64) -> Self {
+        let duration = |secs| Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+        Timeouts {
+            stall: duration(stall),
+            answer: duration(answer),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::from_secs(DEFAULT_STALL_TIMEOUT, DEFAULT_ANSWER_TIMEOUT)
+    }
+}
 
-fn generate_full_path(path: &Path, name: &str) -> Result<String, Error> {
-    // If file or dir already exists in the target directory, create a path extended with a timestamp
-    let joined = path.join(&name);
-    joined.into_os_string().into_string().or_else(|_| {
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            "Could not return target path as string",
-        ))
-    })
+/// Transfer rate limits in KiB per second. Zero means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub upload: u64,
+    #[serde(default)]
+    pub download: u64,
 }
 
-pub fn get_target_path(name: &str, target_path: Option<&String>) -> Result<String, Error> {
+/// Returns None when the payload should be skipped. `Ask` has to be answered
+/// by the user before, so here it keeps both like `Rename`.
+fn generate_full_path(
+    path: &Path,
+    name: &str,
+    policy: ConflictPolicy,
+) -> Result<Option<String>, Error> {
+    let mut joined = path.join(name);
+    if exists(&joined) {
+        match policy {
+            ConflictPolicy::Overwrite => {}
+            ConflictPolicy::Skip => return Ok(None),
+            ConflictPolicy::Rename | ConflictPolicy::Ask => {
+                let name = Path::new(name);
+                let stem = name.file_stem().unwrap_or(name.as_os_str());
+                let extension = name.extension();
+                for i in 1.. {
+                    let mut numbered = stem.to_os_string();
+                    numbered.push(format!(" ({})", i));
+                    if let Some(extension) = extension {
+                        numbered.push(".");
+                        numbered.push(extension);
+                    }
+                    joined = path.join(numbered);
+                    if !exists(&joined) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    joined
+        .into_os_string()
+        .into_string()
+        .map(Some)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Could not return target path as string",
+            )
+        })
+}
+
+pub fn get_target_path(
+    name: &str,
+    target_path: Option<&String>,
+    policy: ConflictPolicy,
+) -> Result<Option<String>, Error> {
     match target_path {
         Some(path) => {
             let path = Path::new(path);
-            generate_full_path(path, name)
+            generate_full_path(path, name, policy)
         }
         None => {
             let config = UserConfig::new()?;
             let dir = config.get_downloads_dir();
-            generate_full_path(dir.as_path(), name)
+            generate_full_path(dir.as_path(), name, policy)
         }
     }
 }
 
+/// Checks whether `name` is taken in the target directory.
+pub fn is_name_taken(name: &str, target_path: Option<&String>) -> Result<bool, Error> {
+    Ok(get_target_path(name, target_path, ConflictPolicy::Skip)?.is_none())
+}
+
+/// Removes whatever is at `path`, so that a received payload can take its place.
+pub fn remove_existing(path: &str) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn exists(path: &Path) -> bool {
+    // Broken symlinks take the name too
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Incoming files are written next to their target under a temporary name
+/// and renamed once complete. Data left there by an interrupted transfer
+/// is used to resume it.
+pub fn get_partial_path(target_path: &str) -> String {
+    format!("{}{}", target_path, PARTIAL_SUFFIX)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     downloads: String,
@@ -47,6 +193,58 @@ struct Config {
 
     #[serde(default = "default_firewall_checked")]
     firewall_checked: bool,
+
+    #[serde(default)]
+    conflict_policy: ConflictPolicy,
+
+    #[serde(default = "default_preserve_mtime")]
+    preserve_mtime: bool,
+
+    #[serde(default = "default_preserve_permissions")]
+    preserve_permissions: bool,
+
+    #[serde(default)]
+    upload_limit: u64,
+
+    #[serde(default)]
+    download_limit: u64,
+
+    /// Number of substreams large files are sent over, 1 turns it off
+    #[serde(default = "default_parallel_streams")]
+    parallel_streams: usize,
+
+    /// Outgoing transfers that run at once for every peer, the rest waits
+    #[serde(default = "default_transfers_per_peer")]
+    transfers_per_peer: usize,
+
+    /// Seconds without any data before a transfer fails, 0 waits forever
+    #[serde(default = "default_stall_timeout")]
+    stall_timeout: u64,
+
+    /// Seconds an offer waits for the receiving user, 0 waits forever
+    #[serde(default = "default_answer_timeout")]
+    answer_timeout: u64,
+
+    /// Largest payload accepted from other peers in MiB, 0 accepts any
+    #[serde(default)]
+    max_size: u64,
+
+    /// Name shown to the other devices, the hostname when empty
+    #[serde(default)]
+    display_name: String,
+
+    /// Addresses of peers added by hand, dialed on startup
+    #[serde(default)]
+    manual_peers: Vec<String>,
+
+    /// PeerIds of the devices paired with, after the user compared their codes
+    #[serde(default)]
+    trusted_peers: Vec<String>,
+
+    /// Limits for particular peers, keyed by PeerId. They apply on top
+    /// of the global ones. Tables go last in TOML.
+    #[serde(default)]
+    peer_limits: HashMap<String, RateLimit>,
 }
 
 fn default_port() -> u16 {
@@ -57,6 +255,43 @@ fn default_firewall_checked() -> bool {
     DEFAULT_FIREWALL_CHECKED
 }
 
+fn default_preserve_mtime() -> bool {
+    DEFAULT_PRESERVE_MTIME
+}
+
+fn default_preserve_permissions() -> bool {
+    DEFAULT_PRESERVE_PERMISSIONS
+}
+
+fn default_parallel_streams() -> usize {
+    DEFAULT_PARALLEL_STREAMS
+}
+
+fn default_transfers_per_peer() -> usize {
+    DEFAULT_TRANSFERS_PER_PEER
+}
+
+/// Parallel streams and transfers per peer within the limits. Running transfers
+/// have to get all of their substreams at once, a file sent in ranges takes
+/// one for every range and one for the Metadata.
+fn stream_limits(parallel_streams: usize, transfers_per_peer: usize) -> (usize, usize) {
+    let parallel_streams = parallel_streams.clamp(1, MAX_STREAMS);
+    let per_transfer = match parallel_streams {
+        1 => 1,
+        streams => streams + 1,
+    };
+    let transfers_per_peer = transfers_per_peer.clamp(1, MAX_OUTBOUND_SUBSTREAMS / per_transfer);
+    (parallel_streams, transfers_per_peer)
+}
+
+fn default_stall_timeout() -> u64 {
+    DEFAULT_STALL_TIMEOUT
+}
+
+fn default_answer_timeout() -> u64 {
+    DEFAULT_ANSWER_TIMEOUT
+}
+
 pub struct UserConfig {
     conf: Config,
     conf_path: PathBuf,
@@ -65,8 +300,7 @@ pub struct UserConfig {
 
 impl UserConfig {
     pub fn new() -> Result<UserConfig, Error> {
-        let base_dirs = BaseDirs::new()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Problem opening base dirs"))?;
+        let base_dirs = BaseDirs::new().ok_or_else(|| Error::other("Problem opening base dirs"))?;
         let base_config_path = base_dirs.config_dir();
 
         let dragit_dir = env::var("DRAGIT_DIR").unwrap_or("dragit".to_string());
@@ -84,8 +318,8 @@ impl UserConfig {
         if !joined_path.exists() {
             info!("Creating default {:?} file", joined_path);
 
-            let user_dirs = UserDirs::new()
-                .ok_or_else(|| Error::new(ErrorKind::Other, "Problem opening user dirs"))?;
+            let user_dirs =
+                UserDirs::new().ok_or_else(|| Error::other("Problem opening user dirs"))?;
             let config = Config {
                 downloads: match user_dirs.download_dir() {
                     Some(v) => v.to_string_lossy().to_string(),
@@ -93,10 +327,24 @@ impl UserConfig {
                 },
                 port: DEFAULT_LISTEN_PORT,
                 firewall_checked: DEFAULT_FIREWALL_CHECKED,
+                conflict_policy: ConflictPolicy::default(),
+                preserve_mtime: DEFAULT_PRESERVE_MTIME,
+                preserve_permissions: DEFAULT_PRESERVE_PERMISSIONS,
+                upload_limit: 0,
+                download_limit: 0,
+                parallel_streams: DEFAULT_PARALLEL_STREAMS,
+                transfers_per_peer: DEFAULT_TRANSFERS_PER_PEER,
+                stall_timeout: DEFAULT_STALL_TIMEOUT,
+                answer_timeout: DEFAULT_ANSWER_TIMEOUT,
+                max_size: 0,
+                display_name: String::new(),
+                manual_peers: Vec::new(),
+                trusted_peers: Vec::new(),
+                peer_limits: HashMap::new(),
             };
-            let toml = Self::serialize_config(config)?;
+            let toml = Self::serialize_config(&config)?;
             let mut file = fs::File::create(&joined_path)?;
-            file.write_all(&toml.as_bytes())?;
+            file.write_all(toml.as_bytes())?;
         }
 
         let mut file = fs::File::open(&joined_path)?;
@@ -107,10 +355,7 @@ impl UserConfig {
             Ok(c) => c,
             Err(e) => {
                 error!("{}", e);
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Problem with loading user config file",
-                ));
+                return Err(Error::other("Problem with loading user config file"));
             }
         };
 
@@ -144,18 +389,20 @@ impl UserConfig {
         } else {
             info!("Generating new keypair, saving to {:?}", key_path);
             let keypair = Keypair::generate_ed25519();
-            let bytes = keypair.to_protobuf_encoding().map_err(|e| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to encode keypair: {:?}", e),
-                )
-            })?;
+            let bytes = keypair
+                .to_protobuf_encoding()
+                .map_err(|e| Error::other(format!("Failed to encode keypair: {:?}", e)))?;
             let mut file = fs::File::create(&key_path)?;
             file.write_all(&bytes)?;
             Ok(keypair)
         }
     }
 
+    /// Peers seen before, stored next to the identity key.
+    pub fn get_known_peers(&self) -> Result<KnownPeers, Error> {
+        KnownPeers::load(self.config_dir.join(KNOWN_PEERS_FILE))
+    }
+
     pub fn get_downloads_dir(&self) -> PathBuf {
         Path::new(&self.conf.downloads).to_owned()
     }
@@ -168,40 +415,134 @@ impl UserConfig {
         self.conf.firewall_checked
     }
 
-    pub fn set_downloads_dir(&self, path: &Path) -> Result<(), Error> {
-        // Watch out, this ::create will truncate the file
-        let mut file = fs::File::create(&self.conf_path.as_path())?;
+    pub fn get_conflict_policy(&self) -> ConflictPolicy {
+        self.conf.conflict_policy
+    }
 
-        let config: Config = Config {
-            downloads: path.to_string_lossy().to_string(),
-            port: self.conf.port,
-            firewall_checked: self.conf.firewall_checked,
-        };
-        let toml = Self::serialize_config(config)?;
-        file.write_all(&toml.as_bytes())?;
-        Ok(())
+    pub fn get_preserved_attributes(&self) -> PreservedAttributes {
+        PreservedAttributes {
+            mtime: self.conf.preserve_mtime,
+            permissions: self.conf.preserve_permissions,
+        }
     }
 
-    pub fn set_firewall_checked(&self, value: bool) -> Result<(), Error> {
-        // Watch out, this ::create will truncate the file
-        let mut file = fs::File::create(&self.conf_path.as_path())?;
+    pub fn get_rate_limit(&self) -> RateLimit {
+        RateLimit {
+            upload: self.conf.upload_limit,
+            download: self.conf.download_limit,
+        }
+    }
 
-        let config: Config = Config {
-            downloads: self.conf.downloads.to_owned(),
-            port: self.conf.port,
-            firewall_checked: value,
-        };
-        let toml = Self::serialize_config(config)?;
-        file.write_all(&toml.as_bytes())?;
+    pub fn get_parallel_streams(&self) -> usize {
+        stream_limits(self.conf.parallel_streams, self.conf.transfers_per_peer).0
+    }
+
+    pub fn get_transfers_per_peer(&self) -> usize {
+        stream_limits(self.conf.parallel_streams, self.conf.transfers_per_peer).1
+    }
+
+    pub fn get_timeouts(&self) -> Timeouts {
+        Timeouts::from_secs(self.conf.stall_timeout, self.conf.answer_timeout)
+    }
+
+    /// In bytes, None when there's no limit
+    pub fn get_max_size(&self) -> Option<u64> {
+        Some(self.conf.max_size)
+            .filter(|max_size| *max_size > 0)
+            .map(|max_size| max_size.saturating_mul(1024 * 1024))
+    }
+
+    pub fn get_display_name(&self) -> &str {
+        &self.conf.display_name
+    }
+
+    pub fn get_manual_peers(&self) -> &[String] {
+        &self.conf.manual_peers
+    }
+
+    pub fn get_trusted_peers(&self) -> HashSet<PeerId> {
+        self.conf
+            .trusted_peers
+            .iter()
+            .filter_map(|peer| match peer.parse::<PeerId>() {
+                Ok(peer_id) => Some(peer_id),
+                Err(e) => {
+                    warn!("Ignoring invalid trusted peer {}: {}", peer, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_peer_rate_limits(&self) -> HashMap<PeerId, RateLimit> {
+        self.conf
+            .peer_limits
+            .iter()
+            .filter_map(|(peer, limit)| match peer.parse::<PeerId>() {
+                Ok(peer_id) => Some((peer_id, *limit)),
+                Err(e) => {
+                    warn!("Ignoring rate limit of invalid peer {}: {}", peer, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_downloads_dir(&mut self, path: &Path) -> Result<(), Error> {
+        self.update(|conf| conf.downloads = path.to_string_lossy().to_string())
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) -> Result<(), Error> {
+        self.update(|conf| conf.conflict_policy = policy)
+    }
+
+    pub fn set_firewall_checked(&mut self, value: bool) -> Result<(), Error> {
+        self.update(|conf| conf.firewall_checked = value)
+    }
+
+    pub fn set_rate_limit(&mut self, limit: RateLimit) -> Result<(), Error> {
+        self.update(|conf| {
+            conf.upload_limit = limit.upload;
+            conf.download_limit = limit.download;
+        })
+    }
+
+    pub fn set_display_name(&mut self, name: &str) -> Result<(), Error> {
+        self.update(|conf| conf.display_name = name.to_string())
+    }
+
+    pub fn add_manual_peer(&mut self, address: &str) -> Result<(), Error> {
+        if self.conf.manual_peers.iter().any(|peer| peer == address) {
+            return Ok(());
+        }
+        self.update(|conf| conf.manual_peers.push(address.to_string()))
+    }
+
+    pub fn trust_peer(&mut self, peer_id: &PeerId) -> Result<(), Error> {
+        let peer_id = peer_id.to_base58();
+        if self.conf.trusted_peers.contains(&peer_id) {
+            return Ok(());
+        }
+        self.update(|conf| conf.trusted_peers.push(peer_id))
+    }
+
+    /// Change the loaded config and save it, so that the changes made
+    /// one after another are all kept.
+    fn update(&mut self, f: impl FnOnce(&mut Config)) -> Result<(), Error> {
+        f(&mut self.conf);
+        let toml = Self::serialize_config(&self.conf)?;
+        // Watch out, this ::create will truncate the file
+        let mut file = fs::File::create(&self.conf_path)?;
+        file.write_all(toml.as_bytes())?;
         Ok(())
     }
 
-    fn serialize_config(config: Config) -> Result<String, Error> {
-        match toml::to_string(&config) {
+    fn serialize_config(config: &Config) -> Result<String, Error> {
+        match toml::to_string(config) {
             Ok(v) => Ok(v),
             Err(e) => {
                 error!("Problem parsing toml: {:?}", e);
-                return Err(Error::new(ErrorKind::Other, "Problem parsing toml"));
+                Err(Error::other("Problem parsing toml"))
             }
         }
     }
@@ -209,16 +550,23 @@ impl UserConfig {
 
 #[cfg(test)]
 mod tests {
-    use crate::user_data::generate_full_path;
+    use crate::user_data::{
+        generate_full_path, get_partial_path, stream_limits, ConflictPolicy, RateLimit, UserConfig,
+    };
     use std::fs::{create_dir_all, File};
     use std::path::Path;
     use tempfile::tempdir;
 
     #[test]
     fn test_generate_full_file_path() {
-        let result = generate_full_path(Path::new("/home/user/"), "a-file.txt").unwrap();
-
-        assert_eq!(result, "/home/user/a-file.txt");
+        let result = generate_full_path(
+            Path::new("/home/user/"),
+            "a-file.txt",
+            ConflictPolicy::Rename,
+        )
+        .unwrap();
+
+        assert_eq!(result.unwrap(), "/home/user/a-file.txt");
     }
 
     #[test]
@@ -227,17 +575,91 @@ mod tests {
         let path = dir.path();
         let received_file_name = "a-file.txt";
         File::create(path.join(received_file_name)).unwrap();
+        File::create(path.join("a-file (1).txt")).unwrap();
 
-        let result = generate_full_path(path, received_file_name).unwrap();
+        let result = generate_full_path(path, received_file_name, ConflictPolicy::Rename).unwrap();
 
-        assert_eq!(result, path.join("a-file.txt").to_string_lossy());
+        assert_eq!(
+            result.unwrap(),
+            path.join("a-file (2).txt").to_string_lossy()
+        );
     }
 
     #[test]
-    fn test_generate_full_dir_path() {
-        let result = generate_full_path(Path::new("/home/user/"), "some_directory").unwrap();
+    fn test_generate_full_file_path_when_file_exists_overwrite() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        File::create(path.join("a-file.txt")).unwrap();
+
+        let result = generate_full_path(path, "a-file.txt", ConflictPolicy::Overwrite).unwrap();
+
+        assert_eq!(result.unwrap(), path.join("a-file.txt").to_string_lossy());
+    }
+
+    #[test]
+    fn test_generate_full_file_path_when_file_exists_skip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        File::create(path.join("a-file.txt")).unwrap();
+
+        let result = generate_full_path(path, "a-file.txt", ConflictPolicy::Skip).unwrap();
+
+        assert_eq!(result, None);
+    }
 
-        assert_eq!(result, "/home/user/some_directory");
+    #[test]
+    fn test_consecutive_changes_are_kept() {
+        let dir = tempdir().unwrap();
+        let conf_path = dir.path().join("config.toml");
+        let mut config = UserConfig {
+            conf: toml::from_str("downloads = \"/tmp\"").unwrap(),
+            conf_path: conf_path.clone(),
+            config_dir: dir.path().to_owned(),
+        };
+
+        let limit = RateLimit {
+            upload: 100,
+            download: 200,
+        };
+        config.set_rate_limit(limit).unwrap();
+        config.set_display_name("Kitchen laptop").unwrap();
+        config.add_manual_peer("192.168.1.2:5200").unwrap();
+
+        let saved = UserConfig {
+            conf: toml::from_str(&std::fs::read_to_string(&conf_path).unwrap()).unwrap(),
+            conf_path,
+            config_dir: dir.path().to_owned(),
+        };
+        assert_eq!(saved.get_rate_limit(), limit);
+        assert_eq!(saved.get_display_name(), "Kitchen laptop");
+        assert_eq!(saved.get_manual_peers(), ["192.168.1.2:5200".to_string()]);
+    }
+
+    #[test]
+    fn test_stream_limits() {
+        assert_eq!(stream_limits(0, 0), (1, 1));
+        assert_eq!(stream_limits(1, 100), (1, 8));
+        assert_eq!(stream_limits(2, 3), (2, 2));
+        assert_eq!(stream_limits(100, 100), (4, 1));
+    }
+
+    #[test]
+    fn test_get_partial_path() {
+        let result = get_partial_path("/home/user/a-file.txt");
+
+        assert_eq!(result, "/home/user/a-file.txt.dragit-part");
+    }
+
+    #[test]
+    fn test_generate_full_dir_path() {
+        let result = generate_full_path(
+            Path::new("/home/user/"),
+            "some_directory",
+            ConflictPolicy::Rename,
+        )
+        .unwrap();
+
+        assert_eq!(result.unwrap(), "/home/user/some_directory");
     }
 
     #[test]
@@ -246,8 +668,11 @@ mod tests {
         let path = dir.path();
         let received_dir_name = "some_directory";
         create_dir_all(path.join(received_dir_name)).unwrap();
-        let result = generate_full_path(path, received_dir_name).unwrap();
+        let result = generate_full_path(path, received_dir_name, ConflictPolicy::Rename).unwrap();
 
-        assert_eq!(result, path.join("some_directory").to_string_lossy());
+        assert_eq!(
+            result.unwrap(),
+            path.join("some_directory (1)").to_string_lossy()
+        );
     }
 }
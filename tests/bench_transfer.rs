@@ -1,6 +1,6 @@
 use core::panic;
 use std::fs;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use async_channel::bounded;
 
@@ -10,11 +10,12 @@ use libp2p::{
     Multiaddr,
 };
 
-use dragit::p2p::{FileToSend, Payload, TransferCommand, TransferOut};
+use dragit::p2p::transfer::ranges::Parallelism;
+use dragit::p2p::{FileToSend, Payload, TransferPayload};
 
 mod common;
 
-use common::{build_swarm, hash_contents_sync, setup_logger};
+use common::{accept_incoming, build_swarm, setup_logger};
 
 #[test]
 fn bench_file_transfer() {
@@ -25,13 +26,11 @@ fn bench_file_transfer() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let (tx, rx) = bounded::<Multiaddr>(10);
-        let (peer1, sender, _, mut swarm1, _tempdir1) = build_swarm();
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
         let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
 
-        let file = fs::File::open(&file_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        let file_hash = hash_contents_sync(file).unwrap();
-        sender.try_send(TransferCommand::Accept(file_hash)).unwrap();
+        let file_size = fs::metadata(&file_path).unwrap().len();
+        accept_incoming(events, sender);
 
         let addr = "/ip4/127.0.0.1/tcp/3010".parse().unwrap();
         swarm1.listen_on(addr).unwrap();
@@ -39,7 +38,7 @@ fn bench_file_transfer() {
         let start = Instant::now();
 
         let sw1 = async move {
-            while let Some(_) = swarm1.next().now_or_never() {}
+            while swarm1.next().now_or_never().is_some() {}
 
             for addr in swarm1.listeners() {
                 tx.send(addr.clone()).await.unwrap();
@@ -65,23 +64,18 @@ fn bench_file_transfer() {
             loop {
                 if let Some(event) = swarm2.next().await {
                     match event {
-                        SwarmEvent::ConnectionEstablished { .. } => {
-                            if !pushed {
-                                let behaviour = swarm2.behaviour_mut();
-                                let payload = Payload::File(file_path.clone());
-                                let file = FileToSend::new(&peer1, payload).unwrap();
-                                let transfer = TransferOut {
-                                    file,
-                                    sender_queue: behaviour.sender.clone(),
-                                };
-                                let event = ToSwarm::NotifyHandler {
-                                    handler: NotifyHandler::Any,
-                                    peer_id: peer1.to_owned(),
-                                    event: transfer,
-                                };
-                                behaviour.events.push(event);
-                                pushed = true;
-                            }
+                        SwarmEvent::ConnectionEstablished { .. } if !pushed => {
+                            let behaviour = swarm2.behaviour_mut();
+                            let payload = Payload::File(file_path.clone());
+                            let file = FileToSend::new(&peer1, payload).unwrap();
+                            let transfer = behaviour.transfer_out(file);
+                            let event = ToSwarm::NotifyHandler {
+                                handler: NotifyHandler::Any,
+                                peer_id: peer1.to_owned(),
+                                event: transfer,
+                            };
+                            behaviour.events.push_back(event);
+                            pushed = true;
                         }
                         SwarmEvent::ConnectionClosed { cause, .. } => {
                             panic!("Conn2 closed {:?}", cause);
@@ -117,10 +111,138 @@ fn bench_file_transfer() {
             }
             Payload::Dir(_) => panic!("Got directory instead of file!"),
             Payload::Text(_) => panic!("Got text instead of file!"),
+            Payload::Batch(_) => panic!("Got batch instead of file!"),
         };
     });
 }
 
+/// Sends the file at `file_path` to a listener on `port`, splitting it
+/// as `parallelism` says. Returns what arrived and how long it took.
+async fn transfer_file(
+    file_path: &str,
+    port: u16,
+    parallelism: Parallelism,
+) -> (TransferPayload, Duration) {
+    let (tx, rx) = bounded::<Multiaddr>(10);
+    let (peer1, sender, events, mut swarm1, tempdir1) = build_swarm();
+    let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
+    swarm2.behaviour_mut().parallelism = parallelism;
+    accept_incoming(events, sender);
+
+    let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+    swarm1.listen_on(addr).unwrap();
+
+    let start = Instant::now();
+
+    let sw1 = async move {
+        while swarm1.next().now_or_never().is_some() {}
+
+        for addr in swarm1.listeners() {
+            tx.send(addr.clone()).await.unwrap();
+        }
+
+        loop {
+            match swarm1.next().await.unwrap() {
+                SwarmEvent::ConnectionClosed { cause, .. } => {
+                    panic!("Conn1 closed! {:?}", cause);
+                }
+                SwarmEvent::Behaviour(event) => {
+                    return event;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let mut pushed = false;
+    let sw2 = async move {
+        let addr = rx.recv().await.unwrap();
+        swarm2.dial(addr).unwrap();
+        loop {
+            match swarm2.next().await.unwrap() {
+                SwarmEvent::ConnectionEstablished { .. } if !pushed => {
+                    let behaviour = swarm2.behaviour_mut();
+                    let payload = Payload::File(file_path.to_string());
+                    let file = FileToSend::new(&peer1, payload).unwrap();
+                    let transfer = behaviour.transfer_out(file);
+                    let event = ToSwarm::NotifyHandler {
+                        handler: NotifyHandler::Any,
+                        peer_id: peer1.to_owned(),
+                        event: transfer,
+                    };
+                    behaviour.events.push_back(event);
+                    pushed = true;
+                }
+                SwarmEvent::ConnectionClosed { cause, .. } => {
+                    panic!("Conn2 closed {:?}", cause);
+                }
+                SwarmEvent::Behaviour(event) => {
+                    return event;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let result = future::select(Box::pin(sw1), Box::pin(sw2)).await;
+    let (received, _) = result.factor_first();
+    let elapsed = start.elapsed();
+
+    // The received file goes away with the directory of the receiver
+    let path = match &received.payload {
+        Payload::File(path) => path.to_string(),
+        _ => panic!("Got {} instead of file!", received.payload),
+    };
+    let sent = fs::read(file_path).unwrap();
+    assert!(fs::read(&path).unwrap() == sent, "Received file differs");
+    drop(tempdir1);
+    (received, elapsed)
+}
+
+#[test]
+fn bench_parallel_file_transfer() {
+    setup_logger();
+
+    // Data that doesn't compress, like most large files
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("bench_32mb.bin");
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let data: Vec<u8> = (0..32 * 1024 * 1024 / 8)
+        .flat_map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state.to_le_bytes()
+        })
+        .collect();
+    fs::write(&file_path, &data).unwrap();
+    let file_path = file_path.to_string_lossy().to_string();
+    let file_size = data.len() as f64 / 1024.0 / 1024.0;
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let single = Parallelism::default();
+        let (received, single_time) = transfer_file(&file_path, 3012, single).await;
+        assert_eq!(received.name, "bench_32mb.bin");
+
+        let parallel = Parallelism {
+            streams: 4,
+            min_size: 1024 * 1024,
+        };
+        let (received, parallel_time) = transfer_file(&file_path, 3013, parallel).await;
+        assert_eq!(received.name, "bench_32mb.bin");
+
+        println!(
+            "\n[bench_parallel_file_transfer] size: {:.0} MB | single stream: {} ms, {:.2} MB/s | 4 streams: {} ms, {:.2} MB/s",
+            file_size,
+            single_time.as_millis(),
+            file_size / single_time.as_secs_f64(),
+            parallel_time.as_millis(),
+            file_size / parallel_time.as_secs_f64(),
+        );
+    });
+}
+
 #[test]
 fn bench_directory_transfer() {
     setup_logger();
@@ -130,12 +252,10 @@ fn bench_directory_transfer() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let (tx, rx) = bounded::<Multiaddr>(10);
-        let (peer1, sender, _, mut swarm1, _tempdir1) = build_swarm();
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
         let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
 
-        sender
-            .try_send(TransferCommand::Accept("directory".to_string()))
-            .unwrap();
+        accept_incoming(events, sender);
 
         // Ensure the empty dir exists (git does not track empty dirs)
         fs::create_dir_all("tests/data/test_dir/empty_dir").unwrap();
@@ -162,7 +282,7 @@ fn bench_directory_transfer() {
         let start = Instant::now();
 
         let sw1 = async move {
-            while let Some(_) = swarm1.next().now_or_never() {}
+            while swarm1.next().now_or_never().is_some() {}
 
             for addr in swarm1.listeners() {
                 tx.send(addr.clone()).await.unwrap();
@@ -190,23 +310,18 @@ fn bench_directory_transfer() {
             loop {
                 if let Some(event) = swarm2.next().await {
                     match event {
-                        SwarmEvent::ConnectionEstablished { .. } => {
-                            if !pushed {
-                                let behaviour = swarm2.behaviour_mut();
-                                let payload = Payload::Dir(dir_path.clone());
-                                let file = FileToSend::new(&peer1, payload).unwrap();
-                                let transfer = TransferOut {
-                                    file,
-                                    sender_queue: behaviour.sender.clone(),
-                                };
-                                let event = ToSwarm::NotifyHandler {
-                                    handler: NotifyHandler::Any,
-                                    peer_id: peer1.to_owned(),
-                                    event: transfer,
-                                };
-                                behaviour.events.push(event);
-                                pushed = true;
-                            }
+                        SwarmEvent::ConnectionEstablished { .. } if !pushed => {
+                            let behaviour = swarm2.behaviour_mut();
+                            let payload = Payload::Dir(dir_path.clone());
+                            let file = FileToSend::new(&peer1, payload).unwrap();
+                            let transfer = behaviour.transfer_out(file);
+                            let event = ToSwarm::NotifyHandler {
+                                handler: NotifyHandler::Any,
+                                peer_id: peer1.to_owned(),
+                                event: transfer,
+                            };
+                            behaviour.events.push_back(event);
+                            pushed = true;
                         }
                         SwarmEvent::ConnectionClosed { cause, .. } => {
                             panic!("Conn2 closed {:?}", cause);
@@ -241,6 +356,7 @@ fn bench_directory_transfer() {
             }
             Payload::File(_) => panic!("Got file instead of directory!"),
             Payload::Text(_) => panic!("Got text instead of directory!"),
+            Payload::Batch(_) => panic!("Got batch instead of directory!"),
         };
     });
 }
@@ -3,19 +3,38 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use async_channel::{bounded, Receiver, Sender};
-use hex;
-use md5::{Digest, Md5};
+use futures::future;
 use tempfile::{tempdir, TempDir};
 use tokio::sync::Mutex;
+use tokio_util::compat::TokioAsyncReadCompatExt;
 
+use libp2p::core::{InboundUpgrade, OutboundUpgrade};
 use libp2p::{identity, noise, tcp, yamux, PeerId, Swarm, SwarmBuilder};
 
+use dragit::p2p::transfer::hash::{HashAlgorithm, Hasher};
 use dragit::p2p::transfer::metadata::HASH_BUFFER_SIZE;
-use dragit::p2p::{FileToSend, PeerEvent, TransferBehaviour, TransferCommand};
+use dragit::p2p::transfer::protocol::ProtocolEvent;
+use dragit::p2p::transfer::{ProtocolVersion, TransferPayload};
+use dragit::p2p::{FileToSend, Payload, PeerEvent, TransferBehaviour, TransferCommand};
 
+/// Accepts every incoming transfer, like a user clicking "Yes" in the dialog.
 #[allow(dead_code)]
-pub fn hash_contents_sync(mut file: impl Read) -> Result<String, Error> {
-    let mut state = Md5::default();
+pub fn accept_incoming(events: Receiver<PeerEvent>, commands: Sender<TransferCommand>) {
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            if let PeerEvent::FileIncoming(incoming) = event {
+                commands
+                    .send(TransferCommand::Accept(incoming.id))
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+}
+
+#[allow(dead_code)]
+pub fn hash_contents_sync(mut file: impl Read, algorithm: HashAlgorithm) -> Result<String, Error> {
+    let mut state = Hasher::new(algorithm);
     let mut buffer = [0u8; HASH_BUFFER_SIZE];
 
     loop {
@@ -30,7 +49,7 @@ pub fn hash_contents_sync(mut file: impl Read) -> Result<String, Error> {
             Err(e) => return Err(e),
         };
     }
-    Ok(hex::encode::<Vec<u8>>(state.finalize().to_vec()))
+    Ok(state.finish())
 }
 
 pub fn build_swarm() -> (
@@ -75,10 +94,50 @@ pub fn build_swarm() -> (
         .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
 
-    let peer_id = local_peer_id.clone();
+    let peer_id = local_peer_id;
     (peer_id, command_sender, peer_receiver, swarm, dir)
 }
 
+/// Runs both ends of a transfer of `payload` over an in-memory stream, as if
+/// the peers had agreed on `version`. The receiving end can be adjusted with
+/// `configure`. Returns what the receiver got, the events of the sender
+/// and the receiver's directory.
+#[allow(dead_code)]
+pub fn transfer_over(
+    version: ProtocolVersion,
+    payload: Payload,
+    configure: impl FnOnce(&mut TransferPayload),
+) -> (
+    Option<(Payload, ProtocolVersion)>,
+    Receiver<PeerEvent>,
+    TempDir,
+) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, commands1, events1, swarm1, tempdir1) = build_swarm();
+        let (peer2, _, events2, swarm2, _tempdir2) = build_swarm();
+        accept_incoming(events1, commands1);
+
+        let (socket1, socket2) = tokio::io::duplex(64 * 1024);
+        let mut incoming = swarm1.behaviour().transfer_in(peer2);
+        configure(&mut incoming);
+        let file = FileToSend::new(&peer1, payload).unwrap();
+        let outgoing = swarm2.behaviour().transfer_out(file);
+
+        let (received, sent) = future::join(
+            incoming.upgrade_inbound(socket1.compat(), version),
+            outgoing.upgrade_outbound(socket2.compat(), version),
+        )
+        .await;
+        assert!(matches!(sent.unwrap(), ProtocolEvent::Sent(sent) if sent == version));
+
+        let received = received
+            .ok()
+            .map(|transfer| (transfer.payload, transfer.version));
+        (received, events2, tempdir1)
+    })
+}
+
 pub fn setup_logger() {
     let env = env_logger::Env::default().filter_or("LOG_LEVEL", "info");
     env_logger::Builder::from_env(env)
@@ -0,0 +1,78 @@
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
+
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::Payload;
+use dragit::user_data::PreservedAttributes;
+
+mod common;
+
+use common::{setup_logger, transfer_over};
+
+/// Makes a script modified long ago, executable by anyone and setuid on Unix.
+fn old_script(dir: &tempfile::TempDir) -> (String, SystemTime) {
+    let path = dir.path().join("script.sh");
+    fs::write(&path, "#!/bin/sh\necho 'I'll fly to device!'\n").unwrap();
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4777)).unwrap();
+    }
+    (path.to_string_lossy().to_string(), mtime)
+}
+
+fn received_path(received: Option<(Payload, ProtocolVersion)>) -> String {
+    match received {
+        Some((Payload::File(path), _)) => path,
+        other => panic!("Expected a file, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_attributes_are_preserved() {
+    setup_logger();
+
+    let source = tempfile::tempdir().unwrap();
+    let (path, mtime) = old_script(&source);
+    let (received, _, _tempdir) =
+        transfer_over(ProtocolVersion::LATEST, Payload::File(path), |_| {});
+
+    let meta = fs::metadata(received_path(received)).unwrap();
+    assert_eq!(meta.modified().unwrap(), mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Neither setuid nor write access of others is taken over
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+    }
+}
+
+#[test]
+fn test_attributes_can_be_ignored() {
+    setup_logger();
+
+    let source = tempfile::tempdir().unwrap();
+    let (path, mtime) = old_script(&source);
+    let (received, _, _tempdir) =
+        transfer_over(ProtocolVersion::LATEST, Payload::File(path), |incoming| {
+            incoming.preserved = PreservedAttributes {
+                mtime: false,
+                permissions: false,
+            };
+        });
+
+    let meta = fs::metadata(received_path(received)).unwrap();
+    assert!(meta.modified().unwrap() > mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(meta.permissions().mode() & 0o111, 0);
+    }
+}
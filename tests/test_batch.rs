@@ -0,0 +1,138 @@
+use core::panic;
+use std::fs;
+use std::path::Path;
+
+use async_channel::bounded;
+
+use futures::{future, prelude::*};
+use libp2p::{
+    swarm::{NotifyHandler, SwarmEvent, ToSwarm},
+    Multiaddr,
+};
+
+use dragit::p2p::{FileToSend, Payload};
+
+mod common;
+
+use common::{accept_incoming, build_swarm, setup_logger};
+
+#[test]
+fn test_batch_transfer() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
+        let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
+
+        accept_incoming(events, sender);
+
+        let addr = "/ip4/127.0.0.1/tcp/3004".parse().unwrap();
+
+        swarm1.listen_on(addr).unwrap();
+        let sw1 = async move {
+            while swarm1.next().now_or_never().is_some() {
+                println!("aaaa");
+            }
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                println!("Pool1");
+                match swarm1.next().await.unwrap() {
+                    SwarmEvent::ConnectionClosed { cause, .. } => {
+                        panic!("Conn1 closed! {:?}", cause);
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        println!("Event1: {:?}", event);
+                        return event;
+                    }
+                    event => {
+                        println!("Other1: {:?}", event);
+                    }
+                }
+            }
+        };
+        let mut pushed = false;
+        let sw2 = async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            loop {
+                println!("Pool2");
+                if let Some(event) = swarm2.next().await {
+                    match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            println!("Established!: {:?}", peer_id);
+                            if !pushed {
+                                println!("Pushing batch");
+                                let behaviour = swarm2.behaviour_mut();
+                                // Put a file after the directory to verify that the
+                                // archive doesn't swallow the items that follow it.
+                                let payload = Payload::Batch(vec![
+                                    Payload::File("tests/data/file.txt".to_string()),
+                                    Payload::Dir("tests/data/test_dir".to_string()),
+                                    Payload::File("tests/data/bench_1mb.bin".to_string()),
+                                ]);
+                                let file = FileToSend::new(&peer1, payload).unwrap();
+                                let transfer = behaviour.transfer_out(file);
+                                let event = ToSwarm::NotifyHandler {
+                                    handler: NotifyHandler::Any,
+                                    peer_id: peer1.to_owned(),
+                                    event: transfer,
+                                };
+                                behaviour.events.push_back(event);
+                                pushed = true;
+                            }
+                        }
+                        SwarmEvent::ConnectionClosed { cause, .. } => {
+                            panic!("Conn2 closed {:?}", cause);
+                        }
+                        SwarmEvent::Behaviour(event) => {
+                            println!("Event2: {:?}", event);
+                            return event;
+                        }
+                        other => {
+                            println!("Other2: {:?}", other);
+                        }
+                    }
+                }
+            }
+        };
+
+        let result = future::select(Box::pin(sw1), Box::pin(sw2)).await;
+        let (p1, _) = result.factor_first();
+
+        print!("P1: {:?}", p1);
+
+        assert_eq!(p1.name, "3 items".to_string());
+
+        match p1.payload {
+            Payload::Batch(items) => {
+                assert_eq!(items.len(), 3);
+                match &items[..] {
+                    [Payload::File(text), Payload::Dir(dir), Payload::File(bench)] => {
+                        assert_eq!(
+                            fs::read(text).unwrap(),
+                            fs::read("tests/data/file.txt").unwrap()
+                        );
+                        assert_eq!(
+                            fs::metadata(Path::new(dir).join("test.odt")).unwrap().len(),
+                            8988
+                        );
+                        assert_eq!(
+                            fs::read(bench).unwrap(),
+                            fs::read("tests/data/bench_1mb.bin").unwrap()
+                        );
+                    }
+                    other => panic!("Unexpected batch items: {:?}", other),
+                }
+            }
+            Payload::File(_) => panic!("Got file instead!"),
+            Payload::Dir(_) => panic!("Got directory instead!"),
+            Payload::Text(_) => panic!("Got text instead!"),
+        };
+    });
+}
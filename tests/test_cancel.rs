@@ -0,0 +1,146 @@
+use std::fs;
+use std::time::Duration;
+
+use async_channel::{bounded, Receiver};
+
+use futures::prelude::*;
+use libp2p::{
+    swarm::{NotifyHandler, SwarmEvent, ToSwarm},
+    Multiaddr,
+};
+
+use dragit::p2p::{FileToSend, Payload, PeerEvent, TransferCommand};
+
+mod common;
+
+use common::{accept_incoming, build_swarm, setup_logger};
+
+/// Waits for the cancellation event and returns whether it came from the other side.
+async fn wait_for_cancel(events: Receiver<PeerEvent>) -> bool {
+    let cancelled = async {
+        loop {
+            match events.recv().await.unwrap() {
+                PeerEvent::TransferCancelled { by_remote, .. } => return by_remote,
+                PeerEvent::TransferCompleted => panic!("Transfer was not cancelled"),
+                event => println!("Event: {:?}", event),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), cancelled)
+        .await
+        .expect("No cancellation event")
+}
+
+/// Sends the test file from one swarm to the other, letting `cancel_by_receiver`
+/// decide which side cancels it. Returns whether the receiver and the sender
+/// were told that the other side cancelled, and the receiver's files.
+fn run_cancelled_transfer(port: u16, cancel_by_receiver: bool) -> (bool, bool, usize) {
+    let file_path = "tests/data/bench_1mb.bin".to_string();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, commands1, events1, mut swarm1, tempdir1) = build_swarm();
+        let (_, commands2, events2, mut swarm2, _tempdir2) = build_swarm();
+
+        let (incoming_sender, incoming) = bounded::<PeerEvent>(1024);
+        if cancel_by_receiver {
+            // Cancel right after accepting, like a user who changed their mind
+            tokio::spawn(async move {
+                while let Ok(event) = events1.recv().await {
+                    if let PeerEvent::FileIncoming(incoming) = event {
+                        let id = incoming.id;
+                        commands1.send(TransferCommand::Accept(id)).await.unwrap();
+                        commands1.send(TransferCommand::Cancel(id)).await.unwrap();
+                    } else {
+                        incoming_sender.send(event).await.unwrap();
+                    }
+                }
+            });
+        } else {
+            let (accepted_sender, accepted) = bounded::<PeerEvent>(1024);
+            accept_incoming(accepted, commands1);
+            tokio::spawn(async move {
+                while let Ok(event) = events1.recv().await {
+                    accepted_sender.send(event.clone()).await.unwrap();
+                    incoming_sender.send(event).await.unwrap();
+                }
+            });
+        }
+
+        let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        swarm1.listen_on(addr).unwrap();
+        tokio::spawn(async move {
+            while swarm1.next().now_or_never().is_some() {}
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                let event = swarm1.next().await.unwrap();
+                println!("Other1: {:?}", event);
+            }
+        });
+
+        tokio::spawn(async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            let mut pushed = false;
+            loop {
+                if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
+                    swarm2.next().await.unwrap()
+                {
+                    println!("Established!: {:?}", peer_id);
+                    if !pushed {
+                        let behaviour = swarm2.behaviour_mut();
+                        let payload = Payload::File(file_path.clone());
+                        let file = FileToSend::new(&peer1, payload).unwrap();
+                        if !cancel_by_receiver {
+                            commands2
+                                .send(TransferCommand::Cancel(file.id))
+                                .await
+                                .unwrap();
+                        }
+                        let transfer = behaviour.transfer_out(file);
+                        let event = ToSwarm::NotifyHandler {
+                            handler: NotifyHandler::Any,
+                            peer_id: peer1.to_owned(),
+                            event: transfer,
+                        };
+                        behaviour.events.push_back(event);
+                        pushed = true;
+                    }
+                }
+            }
+        });
+
+        let (receiver_remote, sender_remote) =
+            future::join(wait_for_cancel(incoming), wait_for_cancel(events2)).await;
+        let received = fs::read_dir(tempdir1.path()).unwrap().count();
+        (receiver_remote, sender_remote, received)
+    })
+}
+
+#[test]
+fn test_receiver_cancels_transfer() {
+    setup_logger();
+
+    let (receiver_remote, sender_remote, received) = run_cancelled_transfer(3005, true);
+
+    assert!(!receiver_remote);
+    assert!(sender_remote);
+    // The partial file is removed
+    assert_eq!(received, 0);
+}
+
+#[test]
+fn test_sender_cancels_transfer() {
+    setup_logger();
+
+    let (receiver_remote, sender_remote, received) = run_cancelled_transfer(3006, false);
+
+    assert!(receiver_remote);
+    assert!(!sender_remote);
+    assert_eq!(received, 0);
+}
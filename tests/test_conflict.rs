@@ -0,0 +1,123 @@
+use core::panic;
+use std::fs;
+
+use async_channel::bounded;
+
+use futures::{future, prelude::*};
+use libp2p::{
+    swarm::{NotifyHandler, SwarmEvent, ToSwarm},
+    Multiaddr,
+};
+
+use dragit::p2p::{FileToSend, Payload};
+
+mod common;
+
+use common::{accept_incoming, build_swarm, setup_logger};
+
+#[test]
+fn test_file_transfer_renames_taken_name() {
+    setup_logger();
+
+    let file_path = "tests/data/file.txt".to_string();
+    let file_path_c = file_path.clone();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, sender, events, mut swarm1, tempdir1) = build_swarm();
+        let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
+
+        // The name is taken by a file the user already has
+        let existing = tempdir1.path().join("file.txt");
+        fs::write(&existing, "mine").unwrap();
+
+        accept_incoming(events, sender);
+
+        let addr = "/ip4/127.0.0.1/tcp/3007".parse().unwrap();
+
+        swarm1.listen_on(addr).unwrap();
+        let sw1 = async move {
+            while swarm1.next().now_or_never().is_some() {
+                println!("aaaa");
+            }
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                println!("Pool1");
+                match swarm1.next().await.unwrap() {
+                    SwarmEvent::ConnectionClosed { cause, .. } => {
+                        panic!("Conn1 closed! {:?}", cause);
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        println!("Event1: {:?}", event);
+                        return event;
+                    }
+                    event => {
+                        println!("Other1: {:?}", event);
+                    }
+                }
+            }
+        };
+        let mut pushed = false;
+        let sw2 = async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            loop {
+                println!("Pool2");
+                if let Some(event) = swarm2.next().await {
+                    match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            println!("Established!: {:?}", peer_id);
+                            if !pushed {
+                                println!("Pushing file");
+                                let behaviour = swarm2.behaviour_mut();
+                                let payload = Payload::File(file_path.clone());
+                                let file = FileToSend::new(&peer1, payload).unwrap();
+                                let transfer = behaviour.transfer_out(file);
+                                let event = ToSwarm::NotifyHandler {
+                                    handler: NotifyHandler::Any,
+                                    peer_id: peer1.to_owned(),
+                                    event: transfer,
+                                };
+                                behaviour.events.push_back(event);
+                                pushed = true;
+                            }
+                        }
+                        SwarmEvent::ConnectionClosed { cause, .. } => {
+                            panic!("Conn2 closed {:?}", cause);
+                        }
+                        SwarmEvent::Behaviour(event) => {
+                            println!("Event2: {:?}", event);
+                            return event;
+                        }
+                        other => {
+                            println!("Other2: {:?}", other);
+                        }
+                    }
+                }
+            }
+        };
+
+        let result = future::select(Box::pin(sw1), Box::pin(sw2)).await;
+        let (p1, _) = result.factor_first();
+
+        print!("P1: {:?}", p1);
+
+        assert_eq!(p1.name, "file.txt".to_string());
+
+        match p1.payload {
+            Payload::File(path) => {
+                assert_eq!(path, tempdir1.path().join("file (1).txt").to_string_lossy());
+                assert_eq!(fs::read(&path).unwrap(), fs::read(&file_path_c).unwrap());
+                assert_eq!(fs::read_to_string(&existing).unwrap(), "mine");
+            }
+            Payload::Dir(_) => panic!("Got directory instead!"),
+            Payload::Text(_) => panic!("Got text instead!"),
+            Payload::Batch(_) => panic!("Got batch instead!"),
+        };
+    });
+}
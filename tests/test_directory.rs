@@ -10,11 +10,12 @@ use libp2p::{
     Multiaddr,
 };
 
-use dragit::p2p::{FileToSend, Payload, TransferCommand, TransferOut};
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{FileToSend, Payload, PeerEvent};
 
 mod common;
 
-use common::{build_swarm, setup_logger};
+use common::{accept_incoming, build_swarm, setup_logger, transfer_over};
 
 #[test]
 fn test_directory_transfer() {
@@ -23,18 +24,16 @@ fn test_directory_transfer() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let (tx, rx) = bounded::<Multiaddr>(10);
-        let (peer1, sender, _, mut swarm1, _tempdir1) = build_swarm();
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
         let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
 
-        sender
-            .try_send(TransferCommand::Accept("directory".to_string()))
-            .unwrap();
+        accept_incoming(events, sender);
 
         let addr = "/ip4/127.0.0.1/tcp/3002".parse().unwrap();
 
         swarm1.listen_on(addr).unwrap();
         let sw1 = async move {
-            while let Some(_) = swarm1.next().now_or_never() {
+            while swarm1.next().now_or_never().is_some() {
                 println!("aaaa");
             }
 
@@ -80,16 +79,13 @@ fn test_directory_transfer() {
 
                                 let payload = Payload::Dir("tests/data/test_dir".to_string());
                                 let file = FileToSend::new(&peer1, payload).unwrap();
-                                let transfer = TransferOut {
-                                    file,
-                                    sender_queue: behaviour.sender.clone(),
-                                };
+                                let transfer = behaviour.transfer_out(file);
                                 let event = ToSwarm::NotifyHandler {
                                     handler: NotifyHandler::Any,
                                     peer_id: peer1.to_owned(),
                                     event: transfer,
                                 };
-                                behaviour.events.push(event);
+                                behaviour.events.push_back(event);
                                 pushed = true;
                             }
                         }
@@ -143,6 +139,32 @@ fn test_directory_transfer() {
             }
             Payload::File(_) => panic!("Got file instead!"),
             Payload::Text(_) => panic!("Got text instead!"),
+            Payload::Batch(_) => panic!("Got batch instead!"),
         };
     });
 }
+
+#[test]
+fn test_directory_progress() {
+    setup_logger();
+
+    let payload = Payload::Dir("tests/data/test_dir".to_string());
+    let (received, events, _tempdir) = transfer_over(ProtocolVersion::LATEST, payload, |_| {});
+    assert!(received.is_some());
+
+    let reports: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+        .filter_map(|event| match event {
+            PeerEvent::DirectoryProgress(progress) => Some(progress),
+            _ => None,
+        })
+        .collect();
+
+    let first = reports.first().expect("No directory progress");
+    assert_eq!(first.files_done, 0);
+    assert!(first.path.starts_with("test_dir/"));
+
+    let last = reports.last().unwrap();
+    assert!(last.files_total > 0);
+    assert_eq!(last.files_done, last.files_total);
+    assert_eq!(last.bytes, last.total_bytes);
+}
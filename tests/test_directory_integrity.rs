@@ -0,0 +1,104 @@
+use std::fs;
+
+use futures::{future, AsyncReadExt};
+use libp2p::core::InboundUpgrade;
+use libp2p::PeerId;
+use tempfile::tempdir;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use dragit::p2p::peer::Direction;
+use dragit::p2p::transfer::chunks::CancelSignal;
+use dragit::p2p::transfer::compression::copy_compressed;
+use dragit::p2p::transfer::directory::{EntryProgress, TarStream};
+use dragit::p2p::transfer::hash::Hasher;
+use dragit::p2p::transfer::metadata::{Answer, Metadata, Trailer};
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{FileToSend, Payload, PeerEvent, TransferCommand};
+
+mod common;
+
+use common::{build_swarm, setup_logger};
+
+const DAMAGED_CONTENTS: &[u8] = b"Contents that get damaged on the way";
+
+/// The sender computes the digests of the directory honestly,
+/// but one of the files is damaged in the archive it sends.
+#[test]
+fn test_incorrect_file_in_directory() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let source = tempdir().unwrap();
+        let dir = source.path().join("integrity_dir");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("intact.txt"), b"Contents that arrive as they are").unwrap();
+        fs::write(dir.join("nested/damaged.txt"), DAMAGED_CONTENTS).unwrap();
+        let path = dir.to_string_lossy().to_string();
+
+        let (peer1, commands, events, swarm, target) = build_swarm();
+        let incorrect = tokio::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                match event {
+                    PeerEvent::FileIncoming(incoming) => commands
+                        .send(TransferCommand::Accept(incoming.id))
+                        .await
+                        .unwrap(),
+                    PeerEvent::FileIncorrect(files) => return files,
+                    _ => {}
+                }
+            }
+            panic!("No FileIncorrect event");
+        });
+
+        let version = ProtocolVersion::LATEST;
+        let (socket1, socket2) = tokio::io::duplex(64 * 1024);
+        let incoming = swarm.behaviour().transfer_in(PeerId::random());
+
+        let faulty_sender = async move {
+            let mut socket = socket2.compat();
+            let file = FileToSend::new(&peer1, Payload::Dir(path.to_string())).unwrap();
+            Metadata::write(&file, &mut socket, version).await.unwrap();
+            let (answer, _) = Answer::read(&mut socket, version).await.unwrap();
+            assert!(answer.accepted);
+
+            let (progress_queue, _progress) = async_channel::unbounded();
+            let progress = EntryProgress::new(0, progress_queue, Direction::Outgoing);
+            let mut stream = TarStream::new(path, None, answer.hash_algorithm, progress);
+            let handle = stream.take_handle().unwrap();
+            let mut archive = vec![];
+            stream.read_to_end(&mut archive).await.unwrap();
+            let files = handle.await.unwrap().unwrap();
+
+            let mut hasher = Hasher::new(answer.hash_algorithm);
+            hasher.update(&archive);
+            let mut trailer = Trailer::new(hasher.finish(), answer.hash_algorithm);
+            trailer.files = files;
+
+            let start = archive
+                .windows(DAMAGED_CONTENTS.len())
+                .position(|window| window == DAMAGED_CONTENTS)
+                .unwrap();
+            archive[start] ^= 0xff;
+
+            let mut cancel: CancelSignal = Box::pin(future::pending());
+            copy_compressed(&archive[..], &mut socket, answer.compression, &mut cancel)
+                .await
+                .unwrap();
+            trailer.write(&mut socket, version).await.unwrap();
+        };
+
+        let (received, _) = future::join(
+            incoming.upgrade_inbound(socket1.compat(), version),
+            faulty_sender,
+        )
+        .await;
+        assert!(received.is_err());
+
+        let files = incorrect.await.unwrap();
+        assert_eq!(files, vec!["integrity_dir/nested/damaged.txt".to_string()]);
+
+        // Neither the directory nor its partial copy is left behind
+        assert_eq!(fs::read_dir(target.path()).unwrap().count(), 0);
+    });
+}
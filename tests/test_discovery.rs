@@ -0,0 +1,428 @@
+use std::time::Duration;
+
+use async_channel::{bounded, Receiver};
+use futures::prelude::*;
+use libp2p::{
+    identity, noise, swarm::SwarmEvent, tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder,
+};
+
+use dragit::p2p::{
+    manual_address, Capabilities, DiscoveryBehaviour, OperatingSystem, Peer, PeerEvent, Trust,
+};
+use dragit::user_data::KnownPeers;
+
+mod common;
+
+use common::setup_logger;
+
+fn build_discovery_swarm() -> (PeerId, Receiver<PeerEvent>, Swarm<DiscoveryBehaviour>) {
+    let (peer_sender, peer_receiver) = bounded::<PeerEvent>(1024);
+    let local_keys = identity::Keypair::generate_ed25519();
+    let local_peer_id = PeerId::from(local_keys.public());
+
+    let swarm = SwarmBuilder::with_existing_identity(local_keys)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default().nodelay(true),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .unwrap()
+        .with_behaviour(move |key| {
+            DiscoveryBehaviour::new(peer_sender.clone(), key.public().to_peer_id())
+        })
+        .unwrap()
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    (local_peer_id, peer_receiver, swarm)
+}
+
+#[test]
+fn test_manually_added_peer_is_discovered() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, mut swarm1) = build_discovery_swarm();
+        let (_, events2, mut swarm2) = build_discovery_swarm();
+
+        swarm1
+            .listen_on("/ip4/127.0.0.1/tcp/3014".parse().unwrap())
+            .unwrap();
+        let sw1 = async move {
+            loop {
+                let event = swarm1.select_next_some().await;
+                if let SwarmEvent::Behaviour(event) = event {
+                    swarm1.behaviour_mut().update_peer(event);
+                }
+            }
+        };
+
+        let addr = manual_address("127.0.0.1:3014").unwrap();
+        swarm2.behaviour_mut().dial_address(addr);
+        let sw2 = async move {
+            loop {
+                match swarm2.select_next_some().await {
+                    SwarmEvent::Behaviour(event) => {
+                        assert_eq!(event.peer, peer1);
+                        swarm2.behaviour_mut().update_peer(event);
+                        return;
+                    }
+                    event => println!("Other2: {:?}", event),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = sw1 => unreachable!(),
+            _ = sw2 => {},
+        };
+
+        let peers = match events2.recv().await.unwrap() {
+            PeerEvent::PeersUpdated(peers) => peers,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        let peer = peers.iter().find(|peer| peer.peer_id == peer1).unwrap();
+        assert_eq!(peer.address.to_string(), "/ip4/127.0.0.1/tcp/3014");
+        assert_ne!(peer.hostname, "Not known yet");
+    });
+}
+
+#[test]
+fn test_unreachable_manual_peer_is_reported() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (_, events, mut swarm) = build_discovery_swarm();
+
+        // Nothing listens there
+        let addr = manual_address("127.0.0.1:3015").unwrap();
+        swarm.behaviour_mut().dial_address(addr);
+
+        let poll = async move {
+            loop {
+                swarm.select_next_some().await;
+            }
+        };
+        let event = tokio::select! {
+            _ = poll => unreachable!(),
+            event = events.recv() => event.unwrap(),
+        };
+        match event {
+            PeerEvent::Error(message) => assert!(message.contains("127.0.0.1"), "{}", message),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    });
+}
+
+#[test]
+fn test_known_peer_is_redialed_after_disconnect() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, mut swarm1) = build_discovery_swarm();
+        let (_, events2, mut swarm2) = build_discovery_swarm();
+
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/3016".parse().unwrap();
+        swarm1.listen_on(addr.clone()).unwrap();
+        let sw1 = async move {
+            loop {
+                swarm1.select_next_some().await;
+            }
+        };
+
+        // Seen before the restart, so it's dialed without mDNS
+        let peer = Peer {
+            name: peer1.to_base58(),
+            address: addr.clone(),
+            peer_id: peer1,
+            hostname: "laptop".to_string(),
+            os: OperatingSystem::Linux,
+            display_name: String::new(),
+            online: true,
+            capabilities: None,
+            trust: Trust::Unpaired,
+            pairing_code: String::new(),
+        };
+        let mut known = KnownPeers::default();
+        known.seen(&peer, Some(&addr)).unwrap();
+        swarm2.behaviour_mut().restore_known_peers(known);
+        let peers = match events2.recv().await.unwrap() {
+            PeerEvent::PeersUpdated(peers) => peers,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        assert!(!peers[0].online);
+
+        let sw2 = async move {
+            let mut discovered = 0;
+            loop {
+                match swarm2.select_next_some().await {
+                    SwarmEvent::Behaviour(event) => {
+                        swarm2.behaviour_mut().update_peer(event);
+                        discovered += 1;
+                        if discovered == 2 {
+                            return;
+                        }
+                        // Reconnects on its own after the backoff
+                        swarm2.disconnect_peer_id(peer1).unwrap();
+                    }
+                    event => println!("Other2: {:?}", event),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = sw1 => unreachable!(),
+            _ = sw2 => {},
+        };
+
+        let mut online = Vec::new();
+        while let Ok(PeerEvent::PeersUpdated(peers)) = events2.try_recv() {
+            online.push(peers[0].online);
+        }
+        // Online, offline after the disconnect, then online again
+        assert_eq!(online, vec![true, false, true]);
+    });
+}
+
+#[test]
+fn test_display_name_is_advertised() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, mut swarm1) = build_discovery_swarm();
+        let (_, _, mut swarm2) = build_discovery_swarm();
+        swarm1
+            .behaviour_mut()
+            .set_display_name("Kitchen laptop".to_string());
+
+        swarm1
+            .listen_on("/ip4/127.0.0.1/tcp/3017".parse().unwrap())
+            .unwrap();
+        let sw1 = async move {
+            let mut names = Vec::new();
+            loop {
+                if let SwarmEvent::Behaviour(event) = swarm1.select_next_some().await {
+                    names.push(event.display_name);
+                    if names.len() == 2 {
+                        return names;
+                    }
+                }
+            }
+        };
+
+        let addr = manual_address("127.0.0.1:3017").unwrap();
+        swarm2.behaviour_mut().dial_address(addr);
+        let sw2 = async move {
+            let mut renamed = false;
+            loop {
+                if let SwarmEvent::Behaviour(event) = swarm2.select_next_some().await {
+                    assert_eq!(event.peer, peer1);
+                    assert_eq!(event.display_name, "Kitchen laptop");
+                    if !renamed {
+                        // The connected peer learns the new name right away
+                        swarm2
+                            .behaviour_mut()
+                            .set_display_name("Office".to_string());
+                        renamed = true;
+                    }
+                }
+            }
+        };
+
+        let names = tokio::select! {
+            names = sw1 => names,
+            _ = sw2 => unreachable!(),
+        };
+        assert_eq!(names, vec!["".to_string(), "Office".to_string()]);
+    });
+}
+
+#[test]
+fn test_capabilities_are_advertised() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, mut swarm1) = build_discovery_swarm();
+        let (_, events2, mut swarm2) = build_discovery_swarm();
+        let capabilities = Capabilities::local(Some(1024 * 1024));
+        swarm1.behaviour_mut().capabilities = capabilities.clone();
+
+        swarm1
+            .listen_on("/ip4/127.0.0.1/tcp/3019".parse().unwrap())
+            .unwrap();
+        let sw1 = async move {
+            loop {
+                swarm1.select_next_some().await;
+            }
+        };
+
+        let addr = manual_address("127.0.0.1:3019").unwrap();
+        swarm2.behaviour_mut().dial_address(addr);
+        let sw2 = async move {
+            loop {
+                if let SwarmEvent::Behaviour(event) = swarm2.select_next_some().await {
+                    swarm2.behaviour_mut().update_peer(event);
+                    return;
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = sw1 => unreachable!(),
+            _ = sw2 => {},
+        };
+
+        let peers = match events2.recv().await.unwrap() {
+            PeerEvent::PeersUpdated(peers) => peers,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        let peer = peers.iter().find(|peer| peer.peer_id == peer1).unwrap();
+        assert_eq!(peer.capabilities, Some(capabilities));
+        assert!(!peer.is_outdated());
+    });
+}
+
+#[test]
+fn test_paired_name_with_another_key_is_reported() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, mut swarm1) = build_discovery_swarm();
+        let (_, events2, mut swarm2) = build_discovery_swarm();
+        swarm1
+            .behaviour_mut()
+            .set_display_name("Kitchen laptop".to_string());
+
+        // Paired before under the same name, with another key
+        let paired = PeerId::random();
+        let peer = Peer {
+            name: paired.to_base58(),
+            address: Multiaddr::empty(),
+            peer_id: paired,
+            hostname: "laptop".to_string(),
+            os: OperatingSystem::Linux,
+            display_name: "Kitchen laptop".to_string(),
+            online: true,
+            capabilities: None,
+            trust: Trust::Unpaired,
+            pairing_code: String::new(),
+        };
+        let mut known = KnownPeers::default();
+        known.seen(&peer, None).unwrap();
+        swarm2.behaviour_mut().restore_known_peers(known);
+        swarm2
+            .behaviour_mut()
+            .set_trusted([paired].into_iter().collect());
+        events2.recv().await.unwrap();
+
+        swarm1
+            .listen_on("/ip4/127.0.0.1/tcp/3020".parse().unwrap())
+            .unwrap();
+        let sw1 = async move {
+            loop {
+                swarm1.select_next_some().await;
+            }
+        };
+
+        let addr = manual_address("127.0.0.1:3020").unwrap();
+        swarm2.behaviour_mut().dial_address(addr);
+        let sw2 = async move {
+            loop {
+                if let SwarmEvent::Behaviour(event) = swarm2.select_next_some().await {
+                    swarm2.behaviour_mut().update_peer(event);
+                    return swarm2;
+                }
+            }
+        };
+
+        let mut swarm2 = tokio::select! {
+            _ = sw1 => unreachable!(),
+            swarm2 = sw2 => swarm2,
+        };
+
+        match events2.recv().await.unwrap() {
+            PeerEvent::Error(message) => assert!(message.contains("Kitchen laptop"), "{}", message),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        let trust = |event| match event {
+            PeerEvent::PeersUpdated(peers) => {
+                let peer = peers
+                    .into_iter()
+                    .find(|peer| peer.peer_id == peer1)
+                    .unwrap();
+                assert_eq!(peer.pairing_code.len(), 7);
+                peer.trust
+            }
+            event => panic!("Unexpected event {:?}", event),
+        };
+        assert_eq!(trust(events2.recv().await.unwrap()), Trust::KeyChanged);
+
+        // The user compared the codes after all
+        swarm2.behaviour_mut().trust_peer(peer1);
+        assert_eq!(trust(events2.recv().await.unwrap()), Trust::Paired);
+    });
+}
+
+#[test]
+fn test_both_devices_show_the_same_pairing_code() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, events1, mut swarm1) = build_discovery_swarm();
+        let (peer2, events2, mut swarm2) = build_discovery_swarm();
+
+        swarm1
+            .listen_on("/ip4/127.0.0.1/tcp/3023".parse().unwrap())
+            .unwrap();
+        let addr = manual_address("127.0.0.1:3023").unwrap();
+        swarm2.behaviour_mut().dial_address(addr);
+
+        // The second device is renamed once discovered, which runs discovery again
+        let (mut discovered1, mut discovered2) = (0, 0);
+        while discovered1 < 2 || discovered2 < 1 {
+            tokio::select! {
+                event = swarm1.select_next_some() => if let SwarmEvent::Behaviour(event) = event {
+                    swarm1.behaviour_mut().update_peer(event);
+                    discovered1 += 1;
+                },
+                event = swarm2.select_next_some() => if let SwarmEvent::Behaviour(event) = event {
+                    swarm2.behaviour_mut().update_peer(event);
+                    discovered2 += 1;
+                    if discovered2 == 1 {
+                        swarm2
+                            .behaviour_mut()
+                            .set_display_name("Office".to_string());
+                    }
+                },
+            }
+        }
+
+        let codes = |events: Receiver<PeerEvent>, peer_id| {
+            let mut codes = vec![];
+            while let Ok(event) = events.try_recv() {
+                if let PeerEvent::PeersUpdated(peers) = event {
+                    codes.extend(
+                        peers
+                            .into_iter()
+                            .filter(|peer| peer.peer_id == peer_id)
+                            .map(|peer| peer.pairing_code),
+                    );
+                }
+            }
+            codes
+        };
+        let codes1 = codes(events1, peer2);
+        let code2 = codes(events2, peer1).pop().unwrap();
+        assert_eq!(code2.len(), 7);
+        // The code doesn't change while the users compare it
+        assert!(codes1.len() > 1);
+        assert!(codes1.iter().all(|code| *code == code2));
+    });
+}
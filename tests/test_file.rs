@@ -1,5 +1,6 @@
 use core::panic;
 use std::fs;
+use std::sync::{Arc, Mutex};
 
 use async_channel::bounded;
 
@@ -9,11 +10,12 @@ use libp2p::{
     Multiaddr,
 };
 
-use dragit::p2p::{FileToSend, Payload, TransferCommand, TransferOut};
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{FileToSend, Payload, PeerEvent, PeerTrust, Trust};
 
 mod common;
 
-use common::{build_swarm, hash_contents_sync, setup_logger};
+use common::{accept_incoming, build_swarm, setup_logger, transfer_over};
 
 #[test]
 fn test_file_transfer() {
@@ -24,19 +26,16 @@ fn test_file_transfer() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let (tx, rx) = bounded::<Multiaddr>(10);
-        let (peer1, sender, _, mut swarm1, _tempdir1) = build_swarm();
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
         let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
 
-        // File hash should be accepted from the beginning
-        let file = fs::File::open(&file_path).unwrap();
-        let file_hash = hash_contents_sync(file).unwrap();
-        sender.try_send(TransferCommand::Accept(file_hash)).unwrap();
+        accept_incoming(events, sender);
 
         let addr = "/ip4/127.0.0.1/tcp/3000".parse().unwrap();
 
         swarm1.listen_on(addr).unwrap();
         let sw1 = async move {
-            while let Some(_) = swarm1.next().now_or_never() {
+            while swarm1.next().now_or_never().is_some() {
                 println!("aaaa");
             }
 
@@ -75,16 +74,13 @@ fn test_file_transfer() {
                                 let behaviour = swarm2.behaviour_mut();
                                 let payload = Payload::File(file_path.clone());
                                 let file = FileToSend::new(&peer1, payload).unwrap();
-                                let transfer = TransferOut {
-                                    file,
-                                    sender_queue: behaviour.sender.clone(),
-                                };
+                                let transfer = behaviour.transfer_out(file);
                                 let event = ToSwarm::NotifyHandler {
                                     handler: NotifyHandler::Any,
                                     peer_id: peer1.to_owned(),
                                     event: transfer,
                                 };
-                                behaviour.events.push(event);
+                                behaviour.events.push_back(event);
                                 pushed = true;
                             }
                         }
@@ -109,6 +105,7 @@ fn test_file_transfer() {
         print!("P1: {:?}", p1);
 
         assert_eq!(p1.name, "file.txt".to_string());
+        assert_eq!(p1.version, ProtocolVersion::LATEST);
 
         match p1.payload {
             Payload::File(path) => {
@@ -117,6 +114,37 @@ fn test_file_transfer() {
             }
             Payload::Dir(_) => panic!("Got directory instead!"),
             Payload::Text(_) => panic!("Got text instead!"),
+            Payload::Batch(_) => panic!("Got batch instead!"),
         };
     });
 }
+
+#[test]
+fn test_incoming_file_tells_the_trust_of_its_sender() {
+    setup_logger();
+
+    let offered = Arc::new(Mutex::new(None));
+    let offered_clone = offered.clone();
+    let payload = Payload::File("tests/data/bench_1mb.bin".to_string());
+    let (received, _, _tempdir) = transfer_over(ProtocolVersion::LATEST, payload, |incoming| {
+        let trust = PeerTrust::default();
+        trust.set([(incoming.peer, Trust::Paired)].into_iter().collect());
+        incoming.peer_trust = trust;
+
+        // Note the offer on its way to the user
+        let (sender, events) = bounded(1024);
+        let user = std::mem::replace(&mut incoming.sender_queue, sender);
+        tokio::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                if let PeerEvent::FileIncoming(file) = &event {
+                    *offered_clone.lock().unwrap() = Some(file.clone());
+                }
+                user.send(event).await.unwrap();
+            }
+        });
+    });
+
+    assert!(received.is_some());
+    let offered = offered.lock().unwrap().take().unwrap();
+    assert_eq!(offered.trust, Trust::Paired);
+}
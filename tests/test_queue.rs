@@ -0,0 +1,138 @@
+use std::fs;
+
+use async_channel::bounded;
+use futures::prelude::*;
+use libp2p::{swarm::SwarmEvent, Multiaddr, PeerId};
+
+use dragit::p2p::{FileToSend, Payload, PeerEvent};
+
+mod common;
+
+use common::{accept_incoming, build_swarm, setup_logger};
+
+#[test]
+fn test_files_to_a_peer_arrive_in_order() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
+        let (_, _, _, mut swarm2, tempdir2) = build_swarm();
+
+        accept_incoming(events, sender);
+
+        let names = ["first.txt", "second.txt", "third.txt"];
+        let paths: Vec<String> = names
+            .iter()
+            .map(|name| {
+                let path = tempdir2.path().join(name);
+                fs::write(&path, name.repeat(1000)).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let addr = "/ip4/127.0.0.1/tcp/3024".parse().unwrap();
+
+        swarm1.listen_on(addr).unwrap();
+        let sw1 = async move {
+            while swarm1.next().now_or_never().is_some() {}
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            let mut received = vec![];
+            while received.len() < names.len() {
+                match swarm1.next().await.unwrap() {
+                    SwarmEvent::ConnectionClosed { cause, .. } => {
+                        panic!("Conn1 closed! {:?}", cause);
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        println!("Event1: {:?}", event);
+                        received.push(event.name);
+                    }
+                    event => {
+                        println!("Other1: {:?}", event);
+                    }
+                }
+            }
+            received
+        };
+        let sw2 = async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            let mut pushed = false;
+            loop {
+                match swarm2.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { connection_id, .. } if !pushed => {
+                        // The files are queued at once and sent one after another
+                        let behaviour = swarm2.behaviour_mut();
+                        behaviour.transfers_per_peer = 1;
+                        behaviour.connection_discovered(peer1, connection_id);
+                        for path in &paths {
+                            let payload = Payload::File(path.clone());
+                            let file = FileToSend::new(&peer1, payload).unwrap();
+                            behaviour.push_file(file);
+                        }
+                        pushed = true;
+                    }
+                    SwarmEvent::ConnectionClosed { cause, .. } => {
+                        panic!("Conn2 closed {:?}", cause);
+                    }
+                    event => {
+                        println!("Other2: {:?}", event);
+                    }
+                }
+            }
+        };
+
+        let received = tokio::select! {
+            received = sw1 => received,
+            _ = sw2 => unreachable!(),
+        };
+        assert_eq!(received, names);
+    });
+}
+
+#[test]
+fn test_files_for_disconnected_peer_fail() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (_, _, events, mut swarm, _tempdir) = build_swarm();
+
+        // Never connected, the second file waits for the first one
+        let peer = PeerId::random();
+        for _ in 0..2 {
+            let payload = Payload::File("tests/data/bench_1mb.bin".to_string());
+            let file = FileToSend::new(&peer, payload).unwrap();
+            swarm.behaviour_mut().push_file(file);
+        }
+
+        let poll = async move {
+            loop {
+                swarm.select_next_some().await;
+            }
+        };
+        let failed = async move {
+            let mut failed = vec![];
+            while failed.len() < 2 {
+                match events.recv().await.unwrap() {
+                    PeerEvent::TransferFailed { id, reason, .. } => {
+                        assert!(reason.contains("not connected"), "{}", reason);
+                        failed.push(id);
+                    }
+                    event => println!("Event: {:?}", event),
+                }
+            }
+            failed
+        };
+        let failed = tokio::select! {
+            _ = poll => unreachable!(),
+            failed = failed => failed,
+        };
+        assert_ne!(failed[0], failed[1]);
+    });
+}
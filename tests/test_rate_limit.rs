@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::Instant;
+
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{Bandwidth, Payload};
+use dragit::user_data::RateLimit;
+
+mod common;
+
+use common::{setup_logger, transfer_over};
+
+#[test]
+fn test_download_limit() {
+    setup_logger();
+
+    // Half of the file fits in the burst, the other half takes half a second
+    let limit = RateLimit {
+        upload: 0,
+        download: 1024,
+    };
+    let payload = Payload::File("tests/data/bench_1mb.bin".to_string());
+    let start = Instant::now();
+    let (received, _, _tempdir) = transfer_over(ProtocolVersion::LATEST, payload, |incoming| {
+        incoming.bandwidth = Bandwidth::new(limit, HashMap::new());
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+
+    match received {
+        Some((Payload::File(path), _)) => assert_eq!(
+            fs::read(path).unwrap(),
+            fs::read("tests/data/bench_1mb.bin").unwrap()
+        ),
+        other => panic!("Expected a file, got {:?}", other),
+    }
+    assert!(elapsed > 0.4, "took {}s", elapsed);
+}
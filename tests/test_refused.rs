@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use async_channel::{bounded, Receiver};
+
+use futures::prelude::*;
+use libp2p::{
+    swarm::{NotifyHandler, SwarmEvent, ToSwarm},
+    Multiaddr,
+};
+
+use dragit::p2p::{FileToSend, Payload, PeerEvent};
+
+mod common;
+
+use common::{build_swarm, setup_logger};
+
+/// Waits for the first event that `pick` recognizes.
+async fn wait_for<T>(events: Receiver<PeerEvent>, pick: impl Fn(PeerEvent) -> Option<T>) -> T {
+    let picked = async {
+        loop {
+            let event = events.recv().await.unwrap();
+            println!("Event: {:?}", event);
+            if let PeerEvent::FileIncoming(..) = event {
+                panic!("Refused payload reached the user");
+            }
+            if let Some(picked) = pick(event) {
+                return picked;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), picked)
+        .await
+        .expect("Transfer was not refused")
+}
+
+#[test]
+fn test_unsafe_name_is_refused() {
+    setup_logger();
+
+    let file_path = "tests/data/file.txt".to_string();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, _, events1, mut swarm1, tempdir1) = build_swarm();
+        let (_, _, events2, mut swarm2, _tempdir2) = build_swarm();
+
+        let target = tempdir1.path().join("nested");
+        std::fs::create_dir(&target).unwrap();
+        swarm1.behaviour_mut().target_path = Some(target.to_string_lossy().to_string());
+
+        let addr = "/ip4/127.0.0.1/tcp/3008".parse().unwrap();
+        swarm1.listen_on(addr).unwrap();
+        tokio::spawn(async move {
+            while swarm1.next().now_or_never().is_some() {}
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                let event = swarm1.next().await.unwrap();
+                println!("Other1: {:?}", event);
+            }
+        });
+
+        tokio::spawn(async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            let mut pushed = false;
+            loop {
+                if let SwarmEvent::ConnectionEstablished { .. } = swarm2.next().await.unwrap() {
+                    if !pushed {
+                        let behaviour = swarm2.behaviour_mut();
+                        let payload = Payload::File(file_path.clone());
+                        let mut file = FileToSend::new(&peer1, payload).unwrap();
+                        // A sender trying to write next to the target directory
+                        file.name = "../escaped.txt".to_string();
+                        let transfer = behaviour.transfer_out(file);
+                        let event = ToSwarm::NotifyHandler {
+                            handler: NotifyHandler::Any,
+                            peer_id: peer1.to_owned(),
+                            event: transfer,
+                        };
+                        behaviour.events.push_back(event);
+                        pushed = true;
+                    }
+                }
+            }
+        });
+
+        let refused = wait_for(events1, |event| match event {
+            PeerEvent::PayloadRefused { reason, .. } => Some(reason),
+            _ => None,
+        });
+        let failed = wait_for(events2, |event| match event {
+            PeerEvent::TransferFailed { reason, .. } => Some(reason),
+            _ => None,
+        });
+        let (refused, failed) = future::join(refused, failed).await;
+
+        assert!(refused.contains("unsafe name"));
+        assert!(failed.contains("refused"));
+        assert!(!tempdir1.path().join("escaped.txt").exists());
+    });
+}
+
+#[test]
+fn test_oversized_payload_is_refused() {
+    setup_logger();
+
+    let file_path = "tests/data/file.txt".to_string();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, _, events1, mut swarm1, _tempdir1) = build_swarm();
+        let (_, _, events2, mut swarm2, _tempdir2) = build_swarm();
+        swarm1.behaviour_mut().max_size = Some(3);
+
+        let addr = "/ip4/127.0.0.1/tcp/3018".parse().unwrap();
+        swarm1.listen_on(addr).unwrap();
+        tokio::spawn(async move {
+            while swarm1.next().now_or_never().is_some() {}
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                let event = swarm1.next().await.unwrap();
+                println!("Other1: {:?}", event);
+            }
+        });
+
+        tokio::spawn(async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            let mut pushed = false;
+            loop {
+                if let SwarmEvent::ConnectionEstablished { .. } = swarm2.next().await.unwrap() {
+                    if !pushed {
+                        // The sender doesn't know the limit, so the receiver has to refuse
+                        let behaviour = swarm2.behaviour_mut();
+                        let payload = Payload::File(file_path.clone());
+                        let file = FileToSend::new(&peer1, payload).unwrap();
+                        let transfer = behaviour.transfer_out(file);
+                        let event = ToSwarm::NotifyHandler {
+                            handler: NotifyHandler::Any,
+                            peer_id: peer1.to_owned(),
+                            event: transfer,
+                        };
+                        behaviour.events.push_back(event);
+                        pushed = true;
+                    }
+                }
+            }
+        });
+
+        let refused = wait_for(events1, |event| match event {
+            PeerEvent::PayloadRefused { reason, .. } => Some(reason),
+            _ => None,
+        });
+        let failed = wait_for(events2, |event| match event {
+            PeerEvent::TransferFailed { reason, .. } => Some(reason),
+            _ => None,
+        });
+        let (refused, failed) = future::join(refused, failed).await;
+
+        assert!(refused.contains("over the limit"), "{}", refused);
+        assert!(failed.contains("refused"));
+    });
+}
@@ -0,0 +1,174 @@
+use core::panic;
+use std::fs;
+use std::time::UNIX_EPOCH;
+
+use async_channel::bounded;
+
+use futures::{future, prelude::*};
+use libp2p::{
+    swarm::{NotifyHandler, SwarmEvent, ToSwarm},
+    Multiaddr,
+};
+
+use dragit::p2p::transfer::partial::get_source_path;
+use dragit::p2p::{FileToSend, Payload, PeerEvent};
+use dragit::user_data::get_partial_path;
+
+mod common;
+
+use common::{accept_incoming, build_swarm, setup_logger};
+
+const FILE_PATH: &str = "tests/data/bench_1mb.bin";
+const RECEIVED: usize = 300_000;
+
+/// Sends the file to a peer that has the first bytes of it already,
+/// in a partial file noted to come from `source`, if any.
+/// Returns the progress the sender reported.
+fn send_after_interruption(
+    port: u16,
+    source: impl FnOnce(&str, usize) -> Option<String>,
+) -> Vec<usize> {
+    setup_logger();
+
+    let file_path = FILE_PATH.to_string();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (tx, rx) = bounded::<Multiaddr>(10);
+        let (peer1, sender, events, mut swarm1, tempdir1) = build_swarm();
+        let (peer2, _, events2, mut swarm2, _tempdir2) = build_swarm();
+
+        // Leave a partial file behind, as if the previous transfer was interrupted.
+        let contents = fs::read(&file_path).unwrap();
+        let target = tempdir1
+            .path()
+            .join("bench_1mb.bin")
+            .to_string_lossy()
+            .to_string();
+        let partial = get_partial_path(&target);
+        fs::write(&partial, &contents[..RECEIVED]).unwrap();
+        if let Some(source) = source(&peer2.to_string(), contents.len()) {
+            fs::write(get_source_path(&target), source).unwrap();
+        }
+
+        accept_incoming(events, sender);
+
+        let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+
+        swarm1.listen_on(addr).unwrap();
+        let sw1 = async move {
+            while swarm1.next().now_or_never().is_some() {}
+
+            for addr in swarm1.listeners() {
+                tx.send(addr.clone()).await.unwrap();
+            }
+
+            loop {
+                match swarm1.next().await.unwrap() {
+                    SwarmEvent::ConnectionClosed { cause, .. } => {
+                        panic!("Conn1 closed! {:?}", cause);
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        println!("Event1: {:?}", event);
+                        return event;
+                    }
+                    event => {
+                        println!("Other1: {:?}", event);
+                    }
+                }
+            }
+        };
+        let mut pushed = false;
+        let sw2 = async move {
+            let addr = rx.recv().await.unwrap();
+            swarm2.dial(addr).unwrap();
+            loop {
+                if let Some(event) = swarm2.next().await {
+                    match event {
+                        SwarmEvent::ConnectionEstablished { .. } => {
+                            if !pushed {
+                                let behaviour = swarm2.behaviour_mut();
+                                let payload = Payload::File(file_path.clone());
+                                let file = FileToSend::new(&peer1, payload).unwrap();
+                                let transfer = behaviour.transfer_out(file);
+                                let event = ToSwarm::NotifyHandler {
+                                    handler: NotifyHandler::Any,
+                                    peer_id: peer1.to_owned(),
+                                    event: transfer,
+                                };
+                                behaviour.events.push_back(event);
+                                pushed = true;
+                            }
+                        }
+                        SwarmEvent::ConnectionClosed { cause, .. } => {
+                            panic!("Conn2 closed {:?}", cause);
+                        }
+                        SwarmEvent::Behaviour(event) => {
+                            println!("Event2: {:?}", event);
+                            return event;
+                        }
+                        other => {
+                            println!("Other2: {:?}", other);
+                        }
+                    }
+                }
+            }
+        };
+
+        let result = future::select(Box::pin(sw1), Box::pin(sw2)).await;
+        let (p1, _) = result.factor_first();
+
+        assert_eq!(p1.name, "bench_1mb.bin".to_string());
+        assert_eq!(p1.size_bytes, contents.len());
+
+        match p1.payload {
+            Payload::File(path) => {
+                assert_eq!(fs::read(path).unwrap(), contents);
+                assert!(fs::metadata(&partial).is_err());
+                assert!(fs::metadata(get_source_path(&target)).is_err());
+            }
+            Payload::Dir(_) => panic!("Got directory instead!"),
+            Payload::Text(_) => panic!("Got text instead!"),
+            Payload::Batch(_) => panic!("Got batch instead!"),
+        };
+
+        let mut progress = vec![];
+        while let Ok(event) = events2.try_recv() {
+            if let PeerEvent::TransferProgress((_, counter, _, _, _)) = event {
+                progress.push(counter);
+            }
+        }
+        progress
+    })
+}
+
+fn source_of_file(peer: &str, size: usize) -> Option<String> {
+    let mtime = fs::metadata(FILE_PATH).unwrap().modified().unwrap();
+    let since = mtime.duration_since(UNIX_EPOCH).unwrap();
+    Some(format!("{} {} {}", peer, size, since.as_secs()))
+}
+
+#[test]
+fn test_resume_file_transfer() {
+    let progress = send_after_interruption(3003, source_of_file);
+
+    // The sender starts where the previous transfer stopped
+    assert_eq!(progress.first(), Some(&RECEIVED));
+    assert!(progress.iter().all(|counter| *counter >= RECEIVED));
+}
+
+#[test]
+fn test_partial_file_of_unknown_source_is_not_resumed() {
+    let progress = send_after_interruption(3021, |_, _| None);
+
+    assert_eq!(progress.first(), Some(&0));
+}
+
+#[test]
+fn test_partial_file_of_another_file_is_not_resumed() {
+    let progress = send_after_interruption(3022, |peer, size| {
+        source_of_file(peer, size).map(|source| format!("{}0", source))
+    });
+
+    assert_eq!(progress.first(), Some(&0));
+}
@@ -8,11 +8,11 @@ use libp2p::{
     Multiaddr,
 };
 
-use dragit::p2p::{FileToSend, Payload, TransferCommand, TransferOut};
+use dragit::p2p::{FileToSend, Payload};
 
 mod common;
 
-use common::{build_swarm, setup_logger};
+use common::{accept_incoming, build_swarm, setup_logger};
 
 #[test]
 fn test_text_transfer() {
@@ -21,21 +21,16 @@ fn test_text_transfer() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let (tx, rx) = bounded::<Multiaddr>(10);
-        let (peer1, sender, _, mut swarm1, _tempdir1) = build_swarm();
+        let (peer1, sender, events, mut swarm1, _tempdir1) = build_swarm();
         let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
 
-        // Text hash should be accepted from the beginning
-        sender
-            .try_send(TransferCommand::Accept(
-                "e8ea7a8d1e93e8764a84a0f3df4644de".to_string(),
-            ))
-            .unwrap();
+        accept_incoming(events, sender);
 
         let addr = "/ip4/127.0.0.1/tcp/3001".parse().unwrap();
 
         swarm1.listen_on(addr).unwrap();
         let sw1 = async move {
-            while let Some(_) = swarm1.next().now_or_never() {
+            while swarm1.next().now_or_never().is_some() {
                 println!("aaaa");
             }
 
@@ -76,16 +71,13 @@ fn test_text_transfer() {
                                 let behaviour = swarm2.behaviour_mut();
                                 let payload = Payload::Text("Hello there".to_string());
                                 let file = FileToSend::new(&peer1, payload).unwrap();
-                                let transfer = TransferOut {
-                                    file,
-                                    sender_queue: behaviour.sender.clone(),
-                                };
+                                let transfer = behaviour.transfer_out(file);
                                 let event = ToSwarm::NotifyHandler {
                                     handler: NotifyHandler::Any,
                                     peer_id: peer1.to_owned(),
                                     event: transfer,
                                 };
-                                behaviour.events.push(event);
+                                behaviour.events.push_back(event);
                                 pushed = true;
                             }
                         }
@@ -114,6 +106,7 @@ fn test_text_transfer() {
         match p1.payload {
             Payload::File(_) => panic!("Got file instead!"),
             Payload::Dir(_) => panic!("Got directory instead!"),
+            Payload::Batch(_) => panic!("Got batch instead!"),
             Payload::Text(text) => {
                 assert_eq!(text, "Hello there".to_string());
             }
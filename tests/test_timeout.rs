@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use futures::future;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use libp2p::core::OutboundUpgrade;
+
+use dragit::p2p::transfer::metadata::{Answer, Metadata};
+use dragit::p2p::transfer::stall::Stalled;
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{FileToSend, Payload};
+
+mod common;
+
+use common::{build_swarm, setup_logger};
+
+#[test]
+fn test_unanswered_offer_times_out() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, _, _, _tempdir1) = build_swarm();
+        let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
+        swarm2.behaviour_mut().timeouts.answer = Some(Duration::from_secs(1));
+
+        let (socket1, socket2) = tokio::io::duplex(64 * 1024);
+        let payload = Payload::File("tests/data/file.txt".to_string());
+        let file = FileToSend::new(&peer1, payload).unwrap();
+        let outgoing = swarm2.behaviour().transfer_out(file);
+
+        // The receiving user never answers
+        let version = ProtocolVersion::LATEST;
+        let receiver = async {
+            let (_, socket) = Metadata::read(socket1.compat(), version).await.unwrap();
+            future::pending::<()>().await;
+            drop(socket);
+        };
+        let sent = tokio::select! {
+            sent = outgoing.upgrade_outbound(socket2.compat(), version) => sent,
+            _ = receiver => unreachable!(),
+        };
+
+        // The behaviour reports the error to the user
+        let error = sent.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(error.to_string().contains("didn't answer"), "{}", error);
+    });
+}
+
+#[test]
+fn test_receiver_that_stops_reading_stalls_the_transfer() {
+    setup_logger();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (peer1, _, _, _, _tempdir1) = build_swarm();
+        let (_, _, _, mut swarm2, _tempdir2) = build_swarm();
+        swarm2.behaviour_mut().timeouts.stall = Some(Duration::from_secs(1));
+
+        let (socket1, socket2) = tokio::io::duplex(64 * 1024);
+        let payload = Payload::File("tests/data/bench_1mb.bin".to_string());
+        let file = FileToSend::new(&peer1, payload).unwrap();
+        let outgoing = swarm2.behaviour().transfer_out(file);
+
+        // The receiver accepts, but never reads the data
+        let version = ProtocolVersion::LATEST;
+        let receiver = async {
+            let (_, socket) = Metadata::read(socket1.compat(), version).await.unwrap();
+            let answer = Answer {
+                accepted: true,
+                ..Answer::denied()
+            };
+            let (_, socket) = answer.write(socket, version).await.unwrap();
+            future::pending::<()>().await;
+            drop(socket);
+        };
+        let sent = tokio::select! {
+            sent = outgoing.upgrade_outbound(socket2.compat(), version) => sent,
+            _ = receiver => unreachable!(),
+        };
+
+        let error = sent.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(
+            Stalled::find(&error).unwrap().timeout,
+            Duration::from_secs(1)
+        );
+    });
+}
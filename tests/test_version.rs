@@ -0,0 +1,62 @@
+use std::fs;
+
+use dragit::p2p::transfer::ProtocolVersion;
+use dragit::p2p::{Payload, PeerEvent};
+
+mod common;
+
+use common::{setup_logger, transfer_over};
+
+#[test]
+fn test_legacy_file_transfer() {
+    setup_logger();
+
+    let payload = Payload::File("tests/data/file.txt".to_string());
+    let (received, _, _tempdir) = transfer_over(ProtocolVersion::V1_2, payload, |_| {});
+
+    match received {
+        Some((Payload::File(path), version)) => {
+            assert_eq!(version, ProtocolVersion::V1_2);
+            assert_eq!(
+                fs::read(path).unwrap(),
+                fs::read("tests/data/file.txt").unwrap()
+            );
+        }
+        other => panic!("Expected a file, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_legacy_directory_transfer() {
+    setup_logger();
+
+    let payload = Payload::Dir("tests/data/test_dir".to_string());
+    let (received, _, _tempdir) = transfer_over(ProtocolVersion::V1_2, payload, |_| {});
+
+    match received {
+        Some((Payload::Dir(path), version)) => {
+            assert_eq!(version, ProtocolVersion::V1_2);
+            let epub = fs::read(format!("{}/Der_Zauberberg.epub", path)).unwrap();
+            assert_eq!(
+                epub,
+                fs::read("tests/data/test_dir/Der_Zauberberg.epub").unwrap()
+            );
+        }
+        other => panic!("Expected a directory, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_legacy_peer_gets_no_batches() {
+    setup_logger();
+
+    let payload = Payload::Batch(vec![
+        Payload::File("tests/data/file.txt".to_string()),
+        Payload::File("tests/data/bench_1mb.bin".to_string()),
+    ]);
+    let (_, events, _) = transfer_over(ProtocolVersion::V1_2, payload, |_| {});
+
+    let failed = std::iter::from_fn(|| events.try_recv().ok())
+        .any(|event| matches!(event, PeerEvent::TransferFailed { .. }));
+    assert!(failed);
+}